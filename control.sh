@@ -28,17 +28,38 @@ print_help() {
     echo "Commands:"
     echo "g <idx>                  - Get health status"
     echo "s <idx>                  - Get node status"
+    echo "nb <idx>                 - Get next, nnext and prev"
+    echo "cm <idx>                 - Get the SWIM member list"
+    echo "rep <idx>                - Get running and recent topology repairs"
     echo "j <from_idx> <to_idx>    - Join nodes"
     echo "l <idx>                  - Node leaves"
-    echo "k <idx>                  - Kill node"
+    echo "k <idx> [wipe]           - Kill node (wipe drops its volatile state)"
     echo "r <idx>                  - Revive node"
     echo "acq <idx> <resource>     - Acquire resource"
     echo "rel <idx> <resource>     - Release resource"
-    echo "det <idx>                - Start detection"
+    echo "det <idx> [and|or]       - Start detection (AND or OR model)"
+    echo "res <idx>                - Get last detection result"
+    echo "auto <idx> <on|off> [ms] - Toggle automatic detection (passive threshold)"
+    echo "wfg <idx> [dot]          - Get global wait-for graph"
+    echo "bc <idx> <text>          - Causal broadcast to the ring"
+    echo "feed <idx>               - Get delivered broadcasts"
+    echo "tob <idx> <key> [value]  - Total order update (no value deletes)"
+    echo "tobs <idx>               - Get replicated total order state"
+    echo "snap <idx>               - Take a global snapshot"
+    echo "term <idx>               - Start termination detection"
+    echo "terms <idx>              - Get termination result"
+    echo "bar <idx> <name> [count] - Wait at a barrier (all ring members by default)"
+    echo "kvget <idx> <key>        - Read a key from the replicated store"
+    echo "kvput <idx> <key> <val>  - Write a key"
+    echo "kvdel <idx> <key>        - Delete a key"
+    echo "place <idx> <static|hashed> - Set resource placement mode on the ring"
     echo "wait <idx> <target_idx>  - Wait for message"
     echo "active <idx>             - Set active"
     echo "passive <idx>            - Set passive"
     echo "delay <idx> <ms>         - Set delay"
+    echo "succ <idx> <k>           - Set successor list length"
+    echo "part <idx> <i,j,...>     - Cut node off from the listed nodes"
+    echo "heal <idx>               - Reconnect node to everyone"
     echo "h                        - Help"
     echo "q                        - Quit"
 }
@@ -54,7 +75,7 @@ print_servers() {
 while true; do
     echo -e "\nEnter command (h for help):"
     print_servers
-    read -r cmd arg1 arg2
+    read -r cmd arg1 arg2 arg3
 
     case $cmd in
         h)
@@ -79,6 +100,30 @@ while true; do
             url="http://$(increment_port "${servers[$arg1]}")/status"
             send_get_request "$url"
             ;;
+        nb)
+            if [[ -z "${servers[$arg1]}" ]]; then
+                echo "Invalid node index"
+                continue
+            fi
+            url="http://$(increment_port "${servers[$arg1]}")/neighbors"
+            send_get_request "$url"
+            ;;
+        cm)
+            if [[ -z "${servers[$arg1]}" ]]; then
+                echo "Invalid node index"
+                continue
+            fi
+            url="http://$(increment_port "${servers[$arg1]}")/cluster/members"
+            send_get_request "$url"
+            ;;
+        rep)
+            if [[ -z "${servers[$arg1]}" ]]; then
+                echo "Invalid node index"
+                continue
+            fi
+            url="http://$(increment_port "${servers[$arg1]}")/repairs"
+            send_get_request "$url"
+            ;;
         j)
             if [[ -z "${servers[$arg1]}" || -z "${servers[$arg2]}" ]]; then
                 echo "Invalid node indices"
@@ -102,6 +147,9 @@ while true; do
                 continue
             fi
             url="http://$(increment_port "${servers[$arg1]}")/kill"
+            if [[ "$arg2" == "wipe" ]]; then
+                url="$url?wipe=true"
+            fi
             send_post_request "$url"
             ;;
         r)
@@ -135,9 +183,143 @@ while true; do
                 echo "Invalid node index"
                 continue
             fi
-            url="http://$(increment_port "${servers[$arg1]}")/detection/start"
+            url="http://$(increment_port "${servers[$arg1]}")/detection/start?mode=${arg2:-and}"
             send_post_request "$url"
             ;;
+        res)
+            if [[ -z "${servers[$arg1]}" ]]; then
+                echo "Invalid node index"
+                continue
+            fi
+            url="http://$(increment_port "${servers[$arg1]}")/detection/result"
+            send_get_request "$url"
+            ;;
+        auto)
+            if [[ -z "${servers[$arg1]}" || -z "$arg2" ]]; then
+                echo "Usage: auto <node_idx> <on|off> [threshold_ms]"
+                continue
+            fi
+            enabled=false
+            [[ "$arg2" == "on" ]] && enabled=true
+            url="http://$(increment_port "${servers[$arg1]}")/detection/auto"
+            if [[ -n "$arg3" ]]; then
+                json="{\"enabled\": $enabled, \"passive_threshold_ms\": $arg3}"
+            else
+                json="{\"enabled\": $enabled}"
+            fi
+            send_post_request "$url" "$json"
+            ;;
+        wfg)
+            if [[ -z "${servers[$arg1]}" ]]; then
+                echo "Invalid node index"
+                continue
+            fi
+            url="http://$(increment_port "${servers[$arg1]}")/cluster/waitfor"
+            [[ "$arg2" == "dot" ]] && url="$url?format=dot"
+            send_get_request "$url"
+            ;;
+        bc)
+            if [[ -z "${servers[$arg1]}" || -z "$arg2" ]]; then
+                echo "Usage: bc <node_idx> <text>"
+                continue
+            fi
+            url="http://$(increment_port "${servers[$arg1]}")/broadcast"
+            json="{\"payload\": \"$arg2\"}"
+            send_post_request "$url" "$json"
+            ;;
+        feed)
+            if [[ -z "${servers[$arg1]}" ]]; then
+                echo "Invalid node index"
+                continue
+            fi
+            url="http://$(increment_port "${servers[$arg1]}")/broadcast/delivered"
+            send_get_request "$url"
+            ;;
+        tob)
+            if [[ -z "${servers[$arg1]}" || -z "$arg2" ]]; then
+                echo "Usage: tob <node_idx> <key> [value]"
+                continue
+            fi
+            url="http://$(increment_port "${servers[$arg1]}")/tob"
+            if [[ -n "$arg3" ]]; then
+                json="{\"key\": \"$arg2\", \"value\": \"$arg3\"}"
+            else
+                json="{\"key\": \"$arg2\", \"value\": null}"
+            fi
+            send_post_request "$url" "$json"
+            ;;
+        tobs)
+            if [[ -z "${servers[$arg1]}" ]]; then
+                echo "Invalid node index"
+                continue
+            fi
+            url="http://$(increment_port "${servers[$arg1]}")/tob/state"
+            send_get_request "$url"
+            ;;
+        snap)
+            if [[ -z "${servers[$arg1]}" ]]; then
+                echo "Invalid node index"
+                continue
+            fi
+            url="http://$(increment_port "${servers[$arg1]}")/snapshot"
+            send_post_request "$url"
+            ;;
+        term)
+            if [[ -z "${servers[$arg1]}" ]]; then
+                echo "Invalid node index"
+                continue
+            fi
+            url="http://$(increment_port "${servers[$arg1]}")/termination/start"
+            send_post_request "$url"
+            ;;
+        terms)
+            if [[ -z "${servers[$arg1]}" ]]; then
+                echo "Invalid node index"
+                continue
+            fi
+            url="http://$(increment_port "${servers[$arg1]}")/termination"
+            send_get_request "$url"
+            ;;
+        bar)
+            if [[ -z "${servers[$arg1]}" || -z "$arg2" ]]; then
+                echo "Usage: bar <node_idx> <name> [participants]"
+                continue
+            fi
+            url="http://$(increment_port "${servers[$arg1]}")/barrier/$arg2"
+            [[ -n "$arg3" ]] && url="$url?participants=$arg3"
+            send_post_request "$url"
+            ;;
+        kvget)
+            if [[ -z "${servers[$arg1]}" || -z "$arg2" ]]; then
+                echo "Usage: kvget <node_idx> <key>"
+                continue
+            fi
+            send_get_request "http://$(increment_port "${servers[$arg1]}")/kv/$arg2"
+            ;;
+        kvput)
+            if [[ -z "${servers[$arg1]}" || -z "$arg2" || -z "$arg3" ]]; then
+                echo "Usage: kvput <node_idx> <key> <value>"
+                continue
+            fi
+            curl -X PUT -H "Content-Type: application/json" -d "{\"value\": \"$arg3\"}" "http://$(increment_port "${servers[$arg1]}")/kv/$arg2"
+            echo
+            ;;
+        kvdel)
+            if [[ -z "${servers[$arg1]}" || -z "$arg2" ]]; then
+                echo "Usage: kvdel <node_idx> <key>"
+                continue
+            fi
+            curl -X DELETE "http://$(increment_port "${servers[$arg1]}")/kv/$arg2"
+            echo
+            ;;
+        place)
+            if [[ -z "${servers[$arg1]}" || -z "$arg2" ]]; then
+                echo "Usage: place <node_idx> <static|hashed>"
+                continue
+            fi
+            url="http://$(increment_port "${servers[$arg1]}")/placement"
+            send_post_request "$url" "{\"mode\": \"$arg2\"}"
+            ;;
         wait)
             if [[ -z "${servers[$arg1]}" || -z "${servers[$arg2]}" ]]; then
                 echo "Invalid node indices"
@@ -172,6 +354,36 @@ while true; do
             json="{\"delay_ms\": $arg2}"
             send_post_request "$url" "$json"
             ;;
+        succ)
+            if [[ -z "${servers[$arg1]}" || -z "$arg2" ]]; then
+                echo "Usage: succ <node_idx> <length>"
+                continue
+            fi
+            url="http://$(increment_port "${servers[$arg1]}")/successors"
+            json="{\"length\": $arg2}"
+            send_post_request "$url" "$json"
+            ;;
+        part)
+            if [[ -z "${servers[$arg1]}" || -z "$arg2" ]]; then
+                echo "Usage: part <node_idx> <node_idx,node_idx,...>"
+                continue
+            fi
+            peers=""
+            for i in ${arg2//,/ }; do
+                peers+="${peers:+,}\"${servers[$i]}\""
+            done
+            url="http://$(increment_port "${servers[$arg1]}")/partition"
+            json="{\"peers\": [$peers]}"
+            send_post_request "$url" "$json"
+            ;;
+        heal)
+            if [[ -z "${servers[$arg1]}" ]]; then
+                echo "Invalid node index"
+                continue
+            fi
+            curl -X DELETE "http://$(increment_port "${servers[$arg1]}")/partition"
+            echo
+            ;;
         *)
             echo "Invalid command. Use 'h' for help."
             ;;
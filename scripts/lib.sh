@@ -0,0 +1,81 @@
+#!/bin/bash
+
+# Helpers shared by the test scripts. A script sources this file, sets
+# `ports` and `resources` and runs its scenario; the nodes it started are
+# stopped when it exits. The binary is taken from the script's first
+# argument. A script with its own background jobs defines `cleanup`, which
+# runs before the nodes are stopped.
+
+BIN=${1:-./target/release/tarpc-distributed-system}
+IP=127.0.0.1
+failures=0
+pids=()
+
+send_post_request() {
+    local url=$1
+    local json=$2
+    curl -s -X POST -H "Content-Type: application/json" ${json:+-d "$json"} "$url" > /dev/null
+}
+
+rest() {
+    echo "http://$IP:$(($1 + 1))"
+}
+
+# Value of a string field in a flat JSON object
+field() {
+    sed -n "s/.*\"$2\":\"\([^\"]*\)\".*/\1/p" <<< "$1"
+}
+
+# Log of a node without color codes
+plain_log() {
+    sed 's/\x1b\[[0-9;]*m//g' "/tmp/node_$1.log"
+}
+
+check() {
+    local scenario=$1
+    local errors=$2
+    if [[ -z "$errors" ]]; then
+        echo "ok   $scenario"
+    else
+        echo "FAIL $scenario"
+        echo "$errors" | sed 's/^/     /'
+        failures=$((failures + 1))
+    fi
+}
+
+# One node per port, each logging to /tmp/node_<port>.log
+start_nodes() {
+    for i in "${!ports[@]}"; do
+        "$BIN" "$IP" "${ports[$i]}" "${resources[$i]}" > "/tmp/node_${ports[$i]}.log" 2>&1 &
+        pids+=($!)
+    done
+    sleep 1
+}
+
+# Every node joins the one started before it
+join_all() {
+    for i in $(seq 1 $((${#ports[@]} - 1))); do
+        send_post_request "$(rest "${ports[$i]}")/joinother" "{\"address\": \"$IP:${ports[$((i - 1))]}\"}"
+        sleep 0.3
+    done
+}
+
+stop_nodes() {
+    if declare -F cleanup > /dev/null; then
+        cleanup
+    fi
+    kill "${pids[@]}" 2> /dev/null
+    wait "${pids[@]}" 2> /dev/null
+    pids=()
+}
+
+# Reports the result of all checks as the exit code
+finish() {
+    if [[ $failures -gt 0 ]]; then
+        echo "$failures check(s) failed"
+        exit 1
+    fi
+    echo "All checks passed"
+}
+
+trap stop_nodes EXIT
@@ -0,0 +1,89 @@
+#!/bin/bash
+
+# Kills several neighbouring nodes at once and checks that the survivors
+# repair the ring by skipping over them with their successor lists.
+#
+# Usage: ./scripts/test_adjacent_failures.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050 2060 2070 2080)
+declare -a resources=(A B C D E F G H)
+
+set_successors() {
+    for port in "${ports[@]}"; do
+        send_post_request "$(rest "$port")/successors" "{\"length\": $1}"
+    done
+    sleep 3
+}
+
+# Kills the nodes with the given indexes, the ports still alive are left in survivors
+kill_nodes() {
+    survivors=()
+    for i in "${!ports[@]}"; do
+        if [[ " $* " == *" $i "* ]]; then
+            kill -9 "${pids[$i]}"
+            wait "${pids[$i]}" 2> /dev/null
+        else
+            survivors+=("${ports[$i]}")
+        fi
+    done
+}
+
+# Walks next from the first member, prints nothing if the ring is consistent
+ring_errors() {
+    local members=("$@")
+    local start="$IP:${members[0]}"
+    local current=$start
+    local visited=()
+    for _ in $(seq 0 ${#members[@]}); do
+        local info next_info next
+        info=$(curl -s "$(rest "${current##*:}")/neighbors")
+        next=$(field "$info" next)
+        next_info=$(curl -s "$(rest "${next##*:}")/neighbors")
+        if [[ "$(field "$next_info" prev)" != "$current" ]]; then
+            echo "prev of $next is $(field "$next_info" prev), expected $current"
+        fi
+        if [[ "$(field "$info" nnext)" != "$(field "$next_info" next)" ]]; then
+            echo "nnext of $current is $(field "$info" nnext), expected $(field "$next_info" next)"
+        fi
+        visited+=("$current")
+        current=$next
+        [[ "$current" == "$start" ]] && break
+    done
+    if [[ "$current" != "$start" || ${#visited[@]} -ne ${#members[@]} ]]; then
+        echo "walk from $start visited ${visited[*]}"
+    fi
+}
+
+expect_ring() {
+    local scenario=$1
+    shift
+    local errors
+    for _ in $(seq 1 15); do
+        errors=$(ring_errors "$@")
+        [[ -z "$errors" ]] && break
+        sleep 1
+    done
+    check "$scenario" "$errors"
+}
+
+
+# Default successor list of 3 survives 2 dead neighbours
+start_nodes
+join_all
+sleep 3
+kill_nodes 3 4
+expect_ring "two adjacent nodes killed" "${survivors[@]}"
+stop_nodes
+
+# A longer list survives more
+start_nodes
+join_all
+sleep 3
+set_successors 4
+kill_nodes 2 3 4
+expect_ring "three adjacent nodes killed with 4 successors" "${survivors[@]}"
+stop_nodes
+
+finish
@@ -0,0 +1,125 @@
+#!/bin/bash
+
+# Checks barriers on a 5 node ring:
+#  - a barrier of all members holds everyone until the last node arrives,
+#    then releases all of them with the same coordinator and participants
+#  - a second local caller of a barrier gets 409
+#  - a counted barrier needs one arrival less once a participant that had
+#    arrived died, and does not count that arrival
+#  - a barrier of all members stops waiting for a member that died
+#
+# Needs jq. Usage: ./scripts/test_barrier.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050)
+declare -a resources=(A B C D E)
+
+# Background curl of every node at every barrier, by "name port"
+declare -A waiting
+
+outcome_file() {
+    echo "/tmp/barrier_$1_$2.json"
+}
+
+# Enters a barrier in the background, the answer ends up in outcome_file
+enter() {
+    local name=$1
+    local port=$2
+    local query=$3
+    local file
+    file=$(outcome_file "$name" "$port")
+    rm -f "$file"
+    curl -s -o "$file" -X POST "$(rest "$port")/barrier/$name$query" &
+    waiting["$name $port"]=$!
+}
+
+# Prints the given nodes that already left the barrier
+released() {
+    local name=$1
+    shift
+    for port in "$@"; do
+        if ! kill -0 "${waiting["$name $port"]}" 2> /dev/null; then
+            echo "$port left barrier $name early: $(cat "$(outcome_file "$name" "$port")")"
+        fi
+    done
+}
+
+# Waits for the given nodes to leave the barrier and checks they all report
+# the same participants, which must be exactly the given nodes
+expect_released() {
+    local scenario=$1
+    local name=$2
+    shift 2
+    local errors=""
+    local expected
+    expected=$(printf "\"$IP:%s\"\n" "$@" | jq -sc 'sort')
+    for port in "$@"; do
+        local pid=${waiting["$name $port"]}
+        for _ in $(seq 1 30); do
+            kill -0 "$pid" 2> /dev/null || break
+            sleep 1
+        done
+        if kill -0 "$pid" 2> /dev/null; then
+            errors+="$port is still waiting at barrier $name"$'\n'
+            continue
+        fi
+        wait "$pid"
+        local participants
+        participants=$(jq -c '.participants | sort' "$(outcome_file "$name" "$port")" 2> /dev/null)
+        if [[ "$participants" != "$expected" ]]; then
+            errors+="$port left with participants ${participants:-none}, expected $expected"$'\n'
+        fi
+    done
+    check "$scenario" "${errors%$'\n'}"
+}
+
+cleanup() {
+    kill "${waiting[@]}" 2> /dev/null
+}
+
+start_nodes
+join_all
+sleep 4
+
+# Everyone but 2050, then 2050
+for port in 2010 2020 2030 2040; do
+    enter all "$port"
+done
+sleep 2
+check "barrier of all members holds the first four" "$(released all 2010 2020 2030 2040)"
+enter all 2050
+expect_released "last arrival releases all members" all "${ports[@]}"
+coordinators=$(jq -r '.coordinator' /tmp/barrier_all_*.json | sort -u)
+check "all members report the lowest node as coordinator" \
+    "$([[ $coordinators == "$IP:2010" ]] || echo "coordinators: $(echo $coordinators)")"
+
+# A second caller at the same node
+enter twice 2020 "?participants=2"
+sleep 1
+code=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$(rest 2020)/barrier/twice?participants=2")
+check "second local caller gets 409" "$([[ $code == 409 ]] || echo "got $code")"
+enter twice 2030 "?participants=2"
+expect_released "counted barrier releases the first caller and another node" twice 2020 2030
+
+# 2050 arrives at a barrier of 3 and dies: the barrier waits for 2 live nodes
+enter counted 2020 "?participants=3"
+enter counted 2050 "?participants=3"
+sleep 1
+kill -9 "${pids[4]}"
+wait "${pids[4]}" 2> /dev/null
+sleep 12
+check "arrival of the dead participant does not count" "$(released counted 2020)"
+enter counted 2030 "?participants=3"
+expect_released "counted barrier releases the two live nodes" counted 2020 2030
+
+# 2040 dies before arriving at a barrier of all members
+for port in 2010 2020 2030; do
+    enter members "$port"
+done
+sleep 1
+kill -9 "${pids[3]}"
+wait "${pids[3]}" 2> /dev/null
+expect_released "barrier of all members stops waiting for a dead member" members 2010 2020 2030
+
+finish
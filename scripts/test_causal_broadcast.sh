@@ -0,0 +1,96 @@
+#!/bin/bash
+
+# Every node of a ring with different per-node delays broadcasts at the same
+# time, while one node answers each broadcast of another as soon as it is
+# delivered, and checks the delivery feed of every node: all broadcasts are
+# delivered, each exactly once, and a broadcast only after everything it
+# depends on (its own earlier ones and what its sender had delivered).
+#
+# Needs jq. Usage: ./scripts/test_causal_broadcast.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050)
+declare -a resources=(A B C D E)
+declare -a delays=(0 40 5 25 10)
+ROUNDS=15
+
+# Prints a line for every broadcast delivered before one it depends on
+causal_errors() {
+    jq -r '
+        . as $feed
+        | range(length) as $i
+        | $feed[$i] as $m
+        | $m.deps | to_entries[]
+        | .key as $origin
+        | (.value - (if $origin == $m.origin then 1 else 0 end)) as $needed
+        | ([$feed[:$i][] | select(.origin == $origin)] | length) as $before
+        | select($before < $needed)
+        | "\($m.origin) #\($m.seq) delivered after \($before) of \($needed) broadcasts of \($origin) it depends on"
+    '
+}
+
+delivered() {
+    curl -s "$(rest "$1")/broadcast/delivered"
+}
+
+start_nodes
+join_all
+sleep 3
+for i in "${!ports[@]}"; do
+    send_post_request "$(rest "${ports[$i]}")/delay" "{\"delay_ms\": ${delays[$i]}}"
+done
+
+# Concurrent broadcasts from everyone
+requests=()
+for port in "${ports[@]}"; do
+    (
+        for n in $(seq 1 $ROUNDS); do
+            send_post_request "$(rest "$port")/broadcast" "{\"payload\": \"$port-$n\"}"
+            sleep "0.0$((RANDOM % 10))"
+        done
+    ) &
+    requests+=($!)
+done
+
+# 2040 answers every question of 2020 once it delivered it, so the answer
+# depends on the question on every node
+answered=0
+for n in $(seq 1 5); do
+    send_post_request "$(rest 2020)/broadcast" "{\"payload\": \"question-$n\"}"
+    for _ in $(seq 1 50); do
+        if delivered 2040 | jq -e --arg p "question-$n" 'any(.[]; .payload == $p)' > /dev/null; then
+            send_post_request "$(rest 2040)/broadcast" "{\"payload\": \"answer-$n\"}"
+            answered=$((answered + 1))
+            break
+        fi
+        sleep 0.1
+    done
+done
+wait "${requests[@]}"
+check "2040 delivered every question" "$([[ $answered -eq 5 ]] || echo "only $answered of 5 questions arrived")"
+
+expected=$((${#ports[@]} * ROUNDS + 10))
+for port in "${ports[@]}"; do
+    errors="never delivered all $expected broadcasts"
+    for _ in $(seq 1 30); do
+        feed=$(delivered "$port")
+        if [[ $(echo "$feed" | jq 'length') -ge $expected ]]; then
+            errors=$(echo "$feed" | causal_errors)
+            duplicates=$(echo "$feed" | jq -r 'group_by([.origin, .seq])[] | select(length > 1) | "\(.[0].origin) #\(.[0].seq) delivered \(length) times"')
+            errors+=${duplicates:+$'\n'$duplicates}
+            break
+        fi
+        sleep 1
+    done
+    check "$port delivered $expected broadcasts in causal order" "$errors"
+    if [[ $port != 2040 ]]; then
+        order=$(echo "$feed" | jq -r '[.[] | .payload | select(startswith("question-") or startswith("answer-"))] | map(split("-")) | . as $p
+            | range(1; 6) | tostring as $n
+            | select(([$p | to_entries[] | select(.value == ["question", $n]) | .key][0] // 1e9) > ([$p | to_entries[] | select(.value == ["answer", $n]) | .key][0] // -1))
+            | "answer-\($n) delivered before question-\($n)"')
+        check "$port delivered every question before its answer" "$order"
+    fi
+done
+
+finish
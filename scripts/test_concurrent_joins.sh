@@ -0,0 +1,102 @@
+#!/bin/bash
+
+# Hammers the ring with concurrent membership changes and checks that
+# stabilization converges to a single consistent ring: following next from
+# any node visits every member once, next.prev points back and nnext is
+# next's next.
+#
+# Usage: ./scripts/test_concurrent_joins.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050 2060 2070 2080)
+declare -a resources=(A B C D E F G H)
+
+# Builds a ring of the first ring_size nodes one join at a time
+join_sequentially() {
+    for i in $(seq 1 $((ring_size - 1))); do
+        send_post_request "$(rest "${ports[$i]}")/joinother" "{\"address\": \"$IP:${ports[$((i - 1))]}\"}"
+        sleep 0.3
+    done
+}
+
+# Sends the joins of all remaining nodes at once, joiner index i joins the
+# ring member given by the function
+join_concurrently() {
+    local target_of=$1
+    join_sequentially
+    local requests=()
+    for i in $(seq "$ring_size" $((${#ports[@]} - 1))); do
+        send_post_request "$(rest "${ports[$i]}")/joinother" "{\"address\": \"$IP:${ports[$($target_of "$i")]}\"}" &
+        requests+=($!)
+    done
+    wait "${requests[@]}"
+}
+
+# Walks next from the first member, prints nothing if the ring is consistent
+ring_errors() {
+    local members=("$@")
+    local start="$IP:${members[0]}"
+    local current=$start
+    local visited=()
+    for _ in $(seq 0 ${#members[@]}); do
+        local info next_info next
+        info=$(curl -s "$(rest "${current##*:}")/neighbors")
+        next=$(field "$info" next)
+        next_info=$(curl -s "$(rest "${next##*:}")/neighbors")
+        if [[ "$(field "$next_info" prev)" != "$current" ]]; then
+            echo "prev of $next is $(field "$next_info" prev), expected $current"
+        fi
+        if [[ "$(field "$info" nnext)" != "$(field "$next_info" next)" ]]; then
+            echo "nnext of $current is $(field "$info" nnext), expected $(field "$next_info" next)"
+        fi
+        visited+=("$current")
+        current=$next
+        [[ "$current" == "$start" ]] && break
+    done
+    if [[ "$current" != "$start" || ${#visited[@]} -ne ${#members[@]} ]]; then
+        echo "walk from $start visited ${visited[*]}"
+    fi
+}
+
+expect_ring() {
+    local scenario=$1
+    shift
+    local errors
+    for _ in $(seq 1 15); do
+        errors=$(ring_errors "$@")
+        [[ -z "$errors" ]] && break
+        sleep 1
+    done
+    check "$scenario" "$errors"
+}
+
+all_to_first() { echo 0; }
+round_robin() { echo $(($1 % ring_size)); }
+to_random() { echo $((RANDOM % ring_size)); }
+
+ring_size=3
+
+for scenario in all_to_first round_robin to_random; do
+    start_nodes
+    join_concurrently $scenario
+    expect_ring "$scenario: ${#ports[@]} nodes joining a ring of $ring_size at once" "${ports[@]}"
+    stop_nodes
+done
+
+# Two nodes join on both sides of a node while it leaves
+ring_size=6
+start_nodes
+join_sequentially
+requests=()
+send_post_request "$(rest "${ports[2]}")/leave" &
+requests+=($!)
+send_post_request "$(rest "${ports[6]}")/joinother" "{\"address\": \"$IP:${ports[3]}\"}" &
+requests+=($!)
+send_post_request "$(rest "${ports[7]}")/joinother" "{\"address\": \"$IP:${ports[1]}\"}" &
+requests+=($!)
+wait "${requests[@]}"
+expect_ring "joins racing a leave" "${ports[0]}" "${ports[1]}" "${ports[3]}" "${ports[4]}" "${ports[5]}" "${ports[6]}" "${ports[7]}"
+stop_nodes
+
+finish
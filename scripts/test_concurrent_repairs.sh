@@ -0,0 +1,89 @@
+#!/bin/bash
+
+# Kills nodes while every survivor keeps broadcasting, so that message
+# delivery and stabilization try to repair the same gap at the same time.
+# Checks that the ring heals and, through /repairs, that every repair ended:
+# nothing still running, timed out or failed, and each killed node was
+# repaired around exactly once per node that started a repair for it.
+#
+# Usage: ./scripts/test_concurrent_repairs.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050 2060 2070 2080)
+declare -a resources=(A B C D E F G H)
+
+# Walks next from the first member, prints nothing if the ring is consistent
+ring_errors() {
+    local members=("$@")
+    local start="$IP:${members[0]}"
+    local current=$start
+    local visited=()
+    for _ in $(seq 0 ${#members[@]}); do
+        local info next_info next
+        info=$(curl -s "$(rest "${current##*:}")/neighbors")
+        next=$(field "$info" next)
+        next_info=$(curl -s "$(rest "${next##*:}")/neighbors")
+        if [[ "$(field "$next_info" prev)" != "$current" ]]; then
+            echo "prev of $next is $(field "$next_info" prev), expected $current"
+        fi
+        visited+=("$current")
+        current=$next
+        [[ "$current" == "$start" ]] && break
+    done
+    if [[ "$current" != "$start" || ${#visited[@]} -ne ${#members[@]} ]]; then
+        echo "walk from $start visited ${visited[*]}"
+    fi
+}
+
+# Repairs started by each survivor: none unfinished, at most one done per killed node
+repair_errors() {
+    local killed=("${!1}")
+    shift
+    for port in "$@"; do
+        local repairs
+        repairs=$(curl -s "$(rest "$port")/repairs" | sed 's/},{"id"/}\n{"id"/g')
+        if grep -qE '"status":"(running|timed_out|failed)"' <<< "$repairs"; then
+            echo "$port has unfinished repairs: $(grep -E '"status":"(running|timed_out|failed)"' <<< "$repairs" | tr '\n' ' ')"
+        fi
+        for dead in "${killed[@]}"; do
+            local done_count
+            done_count=$(grep "\"missing\":\"$IP:$dead\",\"origin\":\"$IP:$port\"" <<< "$repairs" | grep -c '"status":"done"')
+            if [[ $done_count -gt 1 ]]; then
+                echo "$port repaired around $dead $done_count times"
+            fi
+        done
+    done
+}
+
+start_nodes
+join_all
+sleep 3
+
+# 2030 and 2060 die together, with 2040 and 2050 in between
+killed=(2030 2060)
+survivors=(2010 2020 2040 2050 2070 2080)
+kill -9 "${pids[2]}" "${pids[5]}"
+wait "${pids[2]}" "${pids[5]}" 2> /dev/null
+requests=()
+for round in 1 2 3; do
+    for port in "${survivors[@]}"; do
+        send_post_request "$(rest "$port")/broadcast" "{\"payload\": \"round $round from $port\"}" &
+        requests+=($!)
+    done
+    sleep 0.2
+done
+wait "${requests[@]}"
+
+errors=""
+for _ in $(seq 1 20); do
+    errors=$(ring_errors "${survivors[@]}")
+    [[ -z "$errors" ]] && break
+    sleep 1
+done
+check "ring healed around two dead nodes" "$errors"
+# Let every repair come back around or time out
+sleep 12
+check "every repair finished once" "$(repair_errors killed[@] "${survivors[@]}")"
+
+finish
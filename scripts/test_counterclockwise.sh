@@ -0,0 +1,99 @@
+#!/bin/bash
+
+# Checks counter-clockwise routing on an 8 node ring:
+#  - the owner of a resource answers the node right behind it over prev in
+#    one hop instead of going around the ring
+#  - an owner whose next and first finger died grants a queued request by a
+#    detour over prev, before the ring is repaired
+#
+# Needs jq. Usage: ./scripts/test_counterclockwise.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050 2060 2070 2080)
+declare -a resources=(A B C D E F G H)
+
+# Ports in ring order, following next from the given node
+ring_order() {
+    local port=$1
+    local order=()
+    for _ in "${ports[@]}"; do
+        order+=("$port")
+        port=$(curl -s "$(rest "$port")/neighbors" | jq -r '.next | split(":")[1]')
+        [[ $port == "${order[0]}" ]] && break
+    done
+    echo "${order[@]}"
+}
+
+# Number of times the nodes sent a message matching the pattern
+sends() {
+    local pattern=$1
+    for port in "${ports[@]}"; do
+        plain_log "$port" | grep -c "send to .*$pattern"
+    done | awk '{ total += $1 } END { print total }'
+}
+
+acquired() {
+    plain_log "$1" | grep -c "event .*acquired resource $2 from"
+}
+
+resource_of() {
+    echo "${resources[$((($1 - 2010) / 10))]}"
+}
+
+start_nodes
+join_all
+sleep 8
+
+read -ra order <<< "$(ring_order 2010)"
+check "ring has all ${#ports[@]} nodes" \
+    "$([[ ${#order[@]} -eq ${#ports[@]} ]] || echo "ring order: ${order[*]}")"
+
+# The requester sits right behind the owner, 7 hops away clockwise
+requester=${order[3]}
+owner=${order[4]}
+resource=$(resource_of "$owner")
+grant_pattern="Reply([0-9]*, $IP:$requester, Granted(\"$resource\""
+send_post_request "$(rest "$requester")/acquire" "{\"resource\": \"$resource\"}"
+sleep 1
+errors=""
+[[ $(acquired "$requester" "$resource") -eq 1 ]] || errors+="$requester never acquired $resource"$'\n'
+hops=$(sends "$grant_pattern")
+[[ $hops -eq 1 ]] || errors+="grant took $hops hops"$'\n'
+plain_log "$owner" | grep -q "send to $IP:$requester: Counterclockwise($IP:$requester, Resource($grant_pattern" \
+    || errors+="$owner did not send the grant to its prev $requester"$'\n'
+check "grant to the node behind the owner goes over prev in one hop" "${errors%$'\n'}"
+send_post_request "$(rest "$requester")/release" "{\"resource\": \"$resource\"}"
+
+# The owner's next and first finger die while a request is queued for it;
+# the grant goes the other way around
+owner=${order[0]}
+requester=${order[3]}
+holder=${order[5]}
+resource=$(resource_of "$owner")
+send_post_request "$(rest "$holder")/acquire" "{\"resource\": \"$resource\"}"
+sleep 0.5
+send_post_request "$(rest "$requester")/acquire" "{\"resource\": \"$resource\"}"
+sleep 0.5
+for dead in "${order[1]}" "${order[2]}"; do
+    kill -9 "${pids[$(((dead - 2010) / 10))]}"
+    wait "${pids[$(((dead - 2010) / 10))]}" 2> /dev/null
+done
+send_post_request "$(rest "$holder")/release" "{\"resource\": \"$resource\"}"
+errors="$requester never acquired $resource"
+for _ in $(seq 1 10); do
+    [[ $(acquired "$requester" "$resource") -eq 1 ]] && errors="" && break
+    sleep 1
+done
+plain_log "$owner" | grep -q "detouring message for $IP:$requester over prev" \
+    || errors+=$'\n'"$owner did not detour the grant"
+plain_log "$requester" | grep -q "receive counter-clockwise: Resource(Granted(\"$resource\"" \
+    || errors+=$'\n'"$requester did not get the grant counter-clockwise"
+check "grant detours over prev while next is dead" "${errors#$'\n'}"
+
+sleep 10
+read -ra order <<< "$(ring_order "$owner")"
+check "ring repaired without the dead nodes" \
+    "$([[ ${#order[@]} -eq $((${#ports[@]} - 2)) ]] || echo "ring order: ${order[*]}")"
+
+finish
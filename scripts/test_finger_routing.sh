@@ -0,0 +1,125 @@
+#!/bin/bash
+
+# Checks finger tables and finger routing on an 8 node ring:
+#  - every node's fingers are the nodes 1, 2 and 4 hops ahead
+#  - an acquire for a resource 5 hops ahead takes 2 hops, one per set bit,
+#    and the grant 3 hops ahead comes back in 2
+#  - with the first finger on that route crashed, release and acquire still
+#    reach the owner over next, the dead finger is dropped, and the fingers
+#    are rebuilt over the repaired ring
+#
+# Needs jq. Usage: ./scripts/test_finger_routing.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050 2060 2070 2080)
+declare -a resources=(A B C D E F G H)
+
+# Ports in ring order, following next from the given node
+ring_order() {
+    local port=$1
+    local order=()
+    for _ in "${ports[@]}"; do
+        order+=("$port")
+        port=$(curl -s "$(rest "$port")/neighbors" | jq -r '.next | split(":")[1]')
+        [[ $port == "${order[0]}" ]] && break
+    done
+    echo "${order[@]}"
+}
+
+# Prints every node whose fingers are not the nodes 1, 2, 4, ... hops ahead
+finger_errors() {
+    local order=("$@")
+    local size=${#order[@]}
+    for i in "${!order[@]}"; do
+        local expected=()
+        for ((hops = 1; hops < size; hops *= 2)); do
+            expected+=("\"$IP:${order[$(((i + hops) % size))]}\"")
+        done
+        local fingers
+        fingers=$(curl -s "$(rest "${order[$i]}")/fingers" | jq -c '.')
+        if [[ $fingers != "[$(IFS=,; echo "${expected[*]}")]" ]]; then
+            echo "${order[$i]} has fingers $fingers"
+        fi
+    done
+}
+
+expect_fingers() {
+    local scenario=$1
+    shift
+    local errors
+    for _ in $(seq 1 20); do
+        errors=$(finger_errors "$@")
+        [[ -z "$errors" ]] && break
+        sleep 1
+    done
+    check "$scenario" "$errors"
+}
+
+# Number of times the nodes sent a message matching the pattern
+sends() {
+    local pattern=$1
+    for port in "${ports[@]}"; do
+        plain_log "$port" | grep -c "send to .*$pattern"
+    done | awk '{ total += $1 } END { print total }'
+}
+
+expect_hops() {
+    local scenario=$1
+    local before=$2
+    local pattern=$3
+    local expected=$4
+    local hops=$(($(sends "$pattern") - before))
+    check "$scenario" "$([[ $hops -eq $expected ]] || echo "took $hops hops, expected $expected")"
+}
+
+acquired() {
+    plain_log "$1" | grep -c "event .*acquired resource $2 from"
+}
+
+start_nodes
+join_all
+sleep 3
+
+read -ra order <<< "$(ring_order 2010)"
+check "ring has all ${#ports[@]} nodes" \
+    "$([[ ${#order[@]} -eq ${#ports[@]} ]] || echo "ring order: ${order[*]}")"
+expect_fingers "fingers point 1, 2 and 4 hops ahead" "${order[@]}"
+
+# The origin learns the owner by a walk, then acquires it over fingers
+origin=${order[0]}
+owner=${order[5]}
+resource=${resources[$(((owner - 2010) / 10))]}
+send_post_request "$(rest "$origin")/acquire" "{\"resource\": \"$resource\"}"
+send_post_request "$(rest "$origin")/release" "{\"resource\": \"$resource\"}"
+acquire_pattern="Acquire(\"$resource\")"
+grant_pattern="Reply([0-9]*, $IP:$origin, Granted(\"$resource\""
+acquire_sends=$(sends "$acquire_pattern")
+grant_sends=$(sends "$grant_pattern")
+send_post_request "$(rest "$origin")/acquire" "{\"resource\": \"$resource\"}"
+sleep 1
+check "$origin acquired $resource from $owner" "$([[ $(acquired "$origin" "$resource") -eq 2 ]] || echo "never acquired")"
+expect_hops "acquire 5 hops ahead takes 2 hops" "$acquire_sends" "$acquire_pattern" 2
+expect_hops "grant 3 hops ahead takes 2 hops" "$grant_sends" "$grant_pattern" 2
+
+# The acquire went over the finger 4 hops ahead, which now crashes
+dead=${order[4]}
+kill -9 "${pids[$(((dead - 2010) / 10))]}"
+wait "${pids[$(((dead - 2010) / 10))]}" 2> /dev/null
+send_post_request "$(rest "$origin")/release" "{\"resource\": \"$resource\"}"
+send_post_request "$(rest "$origin")/acquire" "{\"resource\": \"$resource\"}"
+errors="never acquired $resource again"
+for _ in $(seq 1 15); do
+    [[ $(acquired "$origin" "$resource") -eq 3 ]] && errors="" && break
+    sleep 1
+done
+plain_log "$origin" | grep -q "dropping dead finger $IP:$dead" || errors+=$'\n'"$origin never dropped finger $dead"
+check "release and acquire pass the dead finger" "${errors#$'\n'}"
+
+sleep 10
+read -ra order <<< "$(ring_order 2010)"
+check "ring repaired without $dead" \
+    "$([[ ${#order[@]} -eq $((${#ports[@]} - 1)) && ! " ${order[*]} " =~ " $dead " ]] || echo "ring order: ${order[*]}")"
+expect_fingers "fingers rebuilt over the repaired ring" "${order[@]}"
+
+finish
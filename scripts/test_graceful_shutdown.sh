@@ -0,0 +1,90 @@
+#!/bin/bash
+
+# Stops nodes with SIGTERM and SIGINT and checks that they leave the ring
+# instead of crashing out of it: the process exits with 0, the rest of the
+# ring is consistent without any repair and, with hashed placement, the
+# resources of the stopped node were handed over.
+#
+# Usage: ./scripts/test_graceful_shutdown.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050)
+declare -a resources=(A B C D E)
+
+# Walks next from the first member, prints nothing if the ring is consistent
+ring_errors() {
+    local members=("$@")
+    local start="$IP:${members[0]}"
+    local current=$start
+    local visited=()
+    for _ in $(seq 0 ${#members[@]}); do
+        local info next_info next
+        info=$(curl -s "$(rest "${current##*:}")/neighbors")
+        next=$(field "$info" next)
+        next_info=$(curl -s "$(rest "${next##*:}")/neighbors")
+        if [[ "$(field "$next_info" prev)" != "$current" ]]; then
+            echo "prev of $next is $(field "$next_info" prev), expected $current"
+        fi
+        visited+=("$current")
+        current=$next
+        [[ "$current" == "$start" ]] && break
+    done
+    if [[ "$current" != "$start" || ${#visited[@]} -ne ${#members[@]} ]]; then
+        echo "walk from $start visited ${visited[*]}"
+    fi
+}
+
+# Signals the node at index, checks its exit code, the remaining ring, that
+# nobody had to repair around it and that its resource has a new owner
+stop_with() {
+    local signal=$1
+    local index=$2
+    shift 2
+    local port=${ports[$index]}
+    local survivors=("$@")
+
+    kill "-$signal" "${pids[$index]}"
+    wait "${pids[$index]}" 2> /dev/null
+    local code=$?
+    check "$signal: node $port exits with 0" "$([[ $code -eq 0 ]] || echo "exit code $code")"
+    check "$signal: ring consistent without $port" "$(ring_errors "${survivors[@]}")"
+
+    local repairs=""
+    for survivor in "${survivors[@]}"; do
+        if curl -s "$(rest "$survivor")/repairs" | grep -q "\"missing\":\"$IP:$port\""; then
+            repairs+="$survivor repaired around $port"$'\n'
+        fi
+    done
+    check "$signal: no repair needed for $port" "${repairs%$'\n'}"
+
+    # Whatever the node owned when it stopped went to the node hashing says
+    local log handed owner
+    log=$(sed 's/\x1b\[[0-9;]*m//g' "/tmp/node_$port.log" | sed -n '/shutting down/,$p')
+    handed=$(grep -o 'handing resources \[[^]]*\] to [0-9.:]*' <<< "$log")
+    local errors=""
+    if grep -q "could not hand resources" <<< "$log"; then
+        errors="$port could not hand off its resources"
+    fi
+    while read -r line; do
+        [[ -z "$line" ]] && continue
+        owner=${line##* }
+        for resource in $(grep -o '"[^"]*"' <<< "$line" | tr -d '"'); do
+            if ! sed 's/\x1b\[[0-9;]*m//g' "/tmp/node_${owner##*:}.log" | grep -q "now owns resource $resource"; then
+                errors+="$owner did not take over $resource"$'\n'
+            fi
+        done
+    done <<< "$handed"
+    check "$signal: resources of $port handed over (${handed:-none owned})" "${errors%$'\n'}"
+}
+
+start_nodes
+join_all
+# Hashed placement gives the resources of a leaving node to a survivor
+send_post_request "$(rest 2010)/placement" '{"mode": "hashed"}'
+sleep 3
+
+stop_with TERM 2 2010 2020 2040 2050
+stop_with INT 4 2010 2020 2040
+
+finish
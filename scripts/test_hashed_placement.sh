@@ -0,0 +1,118 @@
+#!/bin/bash
+
+# Checks hashed resource placement on a 5 node ring:
+#  - after switching to hashed placement every resource is owned by exactly
+#    the node its name hashes to on the ring, as the nodes compute it
+#  - a node that joins takes over the resources that now hash to it, and its
+#    own resource moves to its hashed owner
+#  - a node that leaves hands its resources to their owners among the rest
+#  - a node that does not own a resource is granted it by the hashed owner
+#
+# Needs jq. Usage: ./scripts/test_hashed_placement.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050)
+declare -a resources=(A B C D E)
+
+# Position on the hash ring like the nodes compute it, with the top bit
+# flipped so that signed comparison orders positions as unsigned numbers
+ring_position() {
+    local name=$1
+    local hash=$((0xcbf29ce484222325))
+    local i byte
+    for ((i = 0; i < ${#name}; i++)); do
+        printf -v byte '%d' "'${name:i:1}"
+        hash=$(((hash ^ byte) * 0x100000001b3))
+    done
+    hash=$(((hash ^ ((hash >> 33) & 0x7fffffff)) * 0xff51afd7ed558ccd))
+    hash=$(((hash ^ ((hash >> 33) & 0x7fffffff)) * 0xc4ceb9fe1a85ec53))
+    echo $(((hash ^ ((hash >> 33) & 0x7fffffff)) ^ (1 << 63)))
+}
+
+# First node at or after the resource on the hash ring, wrapping around
+hashed_owner() {
+    local key
+    key=$(ring_position "$1")
+    shift
+    local owner="" owner_position lowest="" lowest_position
+    for port in "$@"; do
+        local position
+        position=$(ring_position "$IP:$port")
+        if [[ $position -ge $key ]] && [[ -z $owner || $position -lt $owner_position ]]; then
+            owner=$port
+            owner_position=$position
+        fi
+        if [[ -z $lowest || $position -lt $lowest_position ]]; then
+            lowest=$port
+            lowest_position=$position
+        fi
+    done
+    echo "${owner:-$lowest}"
+}
+
+# resource:port of every owned resource in a snapshot taken at the given node
+owners() {
+    curl -s -X POST "$(rest "$1")/snapshot" \
+        | jq -r '.nodes[] | (.addr | split(":")[1]) as $port | .owned_resources | keys[] | "\(.):\($port)"' \
+        | sort
+}
+
+# Every resource must be owned by its hashed owner among the given nodes, and
+# by nobody else
+expect_placement() {
+    local scenario=$1
+    shift
+    local expected actual
+    expected=$(for resource in "${all_resources[@]}"; do
+        echo "$resource:$(hashed_owner "$resource" "$@")"
+    done | sort)
+    for _ in $(seq 1 10); do
+        actual=$(owners "$1")
+        [[ "$actual" == "$expected" ]] && break
+        sleep 1
+    done
+    check "$scenario" "$([[ "$actual" != "$expected" ]] \
+        && echo "owners: $(echo $actual)" && echo "expected: $(echo $expected)")"
+}
+
+start_nodes
+join_all
+sleep 3
+
+all_resources=("${resources[@]}")
+expected=$(for i in "${!ports[@]}"; do echo "${resources[$i]}:${ports[$i]}"; done | sort)
+check "static placement keeps resources at their start nodes" \
+    "$([[ "$(owners 2010)" == "$expected" ]] || echo "owners: $(echo $(owners 2010))")"
+
+send_post_request "$(rest 2030)/placement" '{"mode": "hashed"}'
+expect_placement "every resource moved to its hashed owner" "${ports[@]}"
+
+"$BIN" "$IP" 2060 F > /tmp/node_2060.log 2>&1 &
+pids+=($!)
+sleep 1
+send_post_request "$(rest 2060)/joinother" "{\"address\": \"$IP:2020\"}"
+all_resources+=(F)
+expect_placement "a joining node takes over what hashes to it" 2010 2020 2030 2040 2050 2060
+
+send_post_request "$(rest 2030)/leave"
+expect_placement "a leaving node hands its resources over" 2010 2020 2040 2050 2060
+
+# Every resource from a node that does not own it, released right away
+errors=""
+members=(2010 2020 2040 2050 2060)
+for resource in "${all_resources[@]}"; do
+    owner=$(hashed_owner "$resource" "${members[@]}")
+    user=${members[0]}
+    [[ $user == "$owner" ]] && user=${members[1]}
+    send_post_request "$(rest "$user")/acquire" "{\"resource\": \"$resource\"}"
+    sleep 0.5
+    used=$(curl -s -X POST "$(rest 2010)/snapshot" \
+        | jq -r --arg addr "$IP:$user" --arg r "$resource" '.nodes[] | select(.addr == $addr) | .used_resources[$r] // "nobody"')
+    [[ $used != "$IP:$owner" ]] && errors+="$user got $resource from $used, hashed owner is $owner"$'\n'
+    send_post_request "$(rest "$user")/release" "{\"resource\": \"$resource\"}"
+    sleep 0.3
+done
+check "resources are granted by their hashed owners" "${errors%$'\n'}"
+
+finish
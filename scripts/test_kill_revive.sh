@@ -0,0 +1,100 @@
+#!/bin/bash
+
+# Kills a node through REST and checks that it behaves like a crashed
+# process: REST answers 503, its log stops growing and the others repair
+# around it and declare it dead. After revive it is back on the ring and
+# alive again and its total order state synced, both with its old state and
+# after a kill that wiped it.
+#
+# Usage: ./scripts/test_kill_revive.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050)
+declare -a resources=(A B C D E)
+
+http_code() {
+    curl -s -o /dev/null -w "%{http_code}" "$(rest "$1")$2"
+}
+
+# Walks next from the first member, prints nothing if the ring is consistent
+ring_errors() {
+    local members=("$@")
+    local start="$IP:${members[0]}"
+    local current=$start
+    local visited=()
+    for _ in $(seq 0 ${#members[@]}); do
+        local info next_info next
+        info=$(curl -s "$(rest "${current##*:}")/neighbors")
+        next=$(field "$info" next)
+        next_info=$(curl -s "$(rest "${next##*:}")/neighbors")
+        if [[ "$(field "$next_info" prev)" != "$current" ]]; then
+            echo "prev of $next is $(field "$next_info" prev), expected $current"
+        fi
+        visited+=("$current")
+        current=$next
+        [[ "$current" == "$start" ]] && break
+    done
+    if [[ "$current" != "$start" || ${#visited[@]} -ne ${#members[@]} ]]; then
+        echo "walk from $start visited ${visited[*]}"
+    fi
+}
+
+# Prints a line for every observer that does not see member with status
+member_errors() {
+    local member=$1
+    local expected=$2
+    shift 2
+    for observer in "$@"; do
+        local seen
+        seen=$(curl -s "$(rest "$observer")/cluster/members" | grep -o "\"addr\":\"$IP:$member\",\"status\":\"[a-z]*\"" | sed 's/.*"status":"\([a-z]*\)"/\1/')
+        if [[ "$seen" != "$expected" ]]; then
+            echo "$observer sees $member as ${seen:-unknown}, expected $expected"
+        fi
+    done
+}
+
+# The revived node's total order sync request has to get through the ring
+tob_errors() {
+    curl -s "$(rest "$1")/tob/state" | grep -q '"synced":true' || echo "$1 never synced its total order state"
+}
+
+# Retries the check function with its arguments until it prints nothing
+expect() {
+    local scenario=$1
+    shift
+    local errors
+    for _ in $(seq 1 20); do
+        errors=$("$@")
+        [[ -z "$errors" ]] && break
+        sleep 1
+    done
+    check "$scenario" "$errors"
+}
+
+start_nodes
+join_all
+sleep 3
+
+others=(2010 2020 2040 2050)
+for wipe in false true; do
+    send_post_request "$(rest 2030)/kill?wipe=$wipe"
+    code=$(http_code 2030 /health)
+    check "killed node answers REST with 503 (wipe=$wipe)" "$([[ "$code" == 503 ]] || echo "GET /health returned $code")"
+
+    sleep 2
+    lines=$(wc -l < /tmp/node_2030.log)
+    sleep 5
+    grown=$(($(wc -l < /tmp/node_2030.log) - lines))
+    check "killed node stays quiet (wipe=$wipe)" "$([[ $grown -eq 0 ]] || echo "log grew by $grown lines while down")"
+
+    expect "ring repaired around the killed node (wipe=$wipe)" ring_errors "${others[@]}"
+    expect "killed node declared dead (wipe=$wipe)" member_errors 2030 dead "${others[@]}"
+
+    send_post_request "$(rest 2030)/revive"
+    expect "revived node back on the ring (wipe=$wipe)" ring_errors "${ports[@]}"
+    expect "revived node alive again (wipe=$wipe)" member_errors 2030 alive "${others[@]}"
+    expect "revived node synced total order (wipe=$wipe)" tob_errors 2030
+done
+
+finish
@@ -0,0 +1,103 @@
+#!/bin/bash
+
+# Checks the replicated key-value store on a 5 node ring:
+#  - writes sent to any node are read back from every node with the latest
+#    value and version, deleted keys are gone everywhere
+#  - right after a node crashes every key is still readable from the replicas
+#  - once the ring is repaired writes work again, and a node that joins
+#    afterwards reads every key and no deleted one
+#
+# Needs jq. Usage: ./scripts/test_kv_store.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050)
+declare -a resources=(A B C D E)
+KEYS=20
+DELETED=5
+
+kv_put() {
+    curl -s -o /dev/null -w "%{http_code}" -X PUT -H "Content-Type: application/json" \
+        -d "{\"value\": \"$3\"}" "$(rest "$1")/kv/$2"
+}
+
+# Writes tag-n to the given keys through changing nodes, prints failed writes
+put_keys() {
+    local tag=$1
+    local first=$2
+    shift 2
+    local nodes=("$@")
+    for n in $(seq "$first" $KEYS); do
+        local port=${nodes[$((n % ${#nodes[@]}))]}
+        local code
+        code=$(kv_put "$port" "key-$n" "$tag-$n")
+        [[ $code != 200 ]] && echo "PUT key-$n to $port returned $code"
+    done
+}
+
+# Prints every key a node does not read as tag-n with the given version,
+# deleted keys must be missing
+read_errors() {
+    local port=$1
+    local tag=$2
+    local version=$3
+    for n in $(seq 1 $KEYS); do
+        local body code
+        body=$(curl -s -w "\n%{http_code}" "$(rest "$port")/kv/key-$n")
+        code=${body##*$'\n'}
+        body=${body%$'\n'*}
+        if [[ $n -le $DELETED ]]; then
+            [[ $code != 404 ]] && echo "$port reads deleted key-$n: $code $body"
+        elif [[ $code != 200 ]]; then
+            echo "$port GET key-$n returned $code"
+        elif [[ $(echo "$body" | jq -r '.value') != "$tag-$n" ]]; then
+            echo "$port reads key-$n as $body, expected $tag-$n"
+        elif [[ -n "$version" && $(echo "$body" | jq '.version') != "$version" ]]; then
+            echo "$port reads key-$n at version $(echo "$body" | jq '.version'), expected $version"
+        fi
+    done
+}
+
+expect_reads() {
+    local scenario=$1
+    local tag=$2
+    local version=$3
+    shift 3
+    local errors=""
+    for port in "$@"; do
+        errors+=$(read_errors "$port" "$tag" "$version")$'\n'
+    done
+    check "$scenario" "$(echo "$errors" | sed '/^$/d')"
+}
+
+start_nodes
+join_all
+sleep 4
+
+errors=$(put_keys first 1 "${ports[@]}")$'\n'
+errors+=$(put_keys second 1 2050 2040 2030 2020 2010)$'\n'
+for n in $(seq 1 $DELETED); do
+    code=$(curl -s -o /dev/null -w "%{http_code}" -X DELETE "$(rest "${ports[$((n % 5))]}")/kv/key-$n")
+    [[ $code != 200 ]] && errors+="DELETE key-$n returned $code"$'\n'
+done
+check "writes and deletes through every node" "$(echo "$errors" | sed '/^$/d')"
+expect_reads "every node reads the second write of every key" second 2 "${ports[@]}"
+
+# 2030 crashes, its keys are read from the replicas before any repair
+kill -9 "${pids[2]}"
+wait "${pids[2]}" 2> /dev/null
+survivors=(2010 2020 2040 2050)
+expect_reads "every key readable right after a crash" second 2 "${survivors[@]}"
+
+sleep 15
+check "writes after the repair" "$(put_keys third $((DELETED + 1)) "${survivors[@]}")"
+expect_reads "survivors read the writes after the repair" third "" "${survivors[@]}"
+
+"$BIN" "$IP" 2060 F > /tmp/node_2060.log 2>&1 &
+pids+=($!)
+sleep 1
+send_post_request "$(rest 2060)/joinother" "{\"address\": \"$IP:2050\"}"
+sleep 5
+expect_reads "a node that joined later reads every key" third "" 2060 2010
+
+finish
@@ -0,0 +1,180 @@
+#!/bin/bash
+
+# Splits a ring in two by cutting every node off from the other half, checks
+# that each half repairs into its own ring, then heals the partition and
+# checks that the rings merge into one. Nodes A and D are both started with
+# resource A, after the merge exactly one node may own it. Each half grants A
+# to one of its nodes while split; after the merge one holder must have lost
+# it and get it once the other releases. Both halves keep
+# sequencing total order updates while split; after the merge every node must
+# deliver the same order again.
+#
+# Needs jq.
+# Usage: ./scripts/test_partition_merge.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050 2060)
+declare -a resources=(A B C A E F)
+declare -a left=(2010 2020 2030)
+declare -a right=(2040 2050 2060)
+
+peer_list() {
+    local peers=""
+    for port in "$@"; do
+        peers+="${peers:+,}\"$IP:$port\""
+    done
+    echo "{\"peers\": [$peers]}"
+}
+
+# Walks next from the first member, prints nothing if the ring is consistent
+ring_errors() {
+    local members=("$@")
+    local start="$IP:${members[0]}"
+    local current=$start
+    local visited=()
+    for _ in $(seq 0 ${#members[@]}); do
+        local info next_info next
+        info=$(curl -s "$(rest "${current##*:}")/neighbors")
+        next=$(field "$info" next)
+        next_info=$(curl -s "$(rest "${next##*:}")/neighbors")
+        if [[ "$(field "$next_info" prev)" != "$current" ]]; then
+            echo "prev of $next is $(field "$next_info" prev), expected $current"
+        fi
+        if [[ "$(field "$info" nnext)" != "$(field "$next_info" next)" ]]; then
+            echo "nnext of $current is $(field "$info" nnext), expected $(field "$next_info" next)"
+        fi
+        visited+=("$current")
+        current=$next
+        [[ "$current" == "$start" ]] && break
+    done
+    if [[ "$current" != "$start" || ${#visited[@]} -ne ${#members[@]} ]]; then
+        echo "walk from $start visited ${visited[*]}"
+    fi
+}
+
+expect_ring() {
+    local scenario=$1
+    shift
+    local errors
+    for _ in $(seq 1 15); do
+        errors=$(ring_errors "$@")
+        [[ -z "$errors" ]] && break
+        sleep 1
+    done
+    check "$scenario" "$errors"
+}
+
+# Merging must have moved resource A away from one of its two owners
+expect_single_owner() {
+    local owners=0
+    for port in "${ports[@]}"; do
+        if sed 's/\x1b\[[0-9;]*m//g' "/tmp/node_$port.log" | grep -q "now owns resource A\|giving up resources \[\"A\"\]"; then
+            owners=$((owners + 1))
+        fi
+    done
+    if [[ $owners -eq 2 ]]; then
+        echo "ok   resource A handed from one owner to the other"
+    else
+        echo "FAIL resource A: expected one hand over between two nodes, saw $owners nodes involved"
+        failures=$((failures + 1))
+    fi
+}
+
+tob() {
+    send_post_request "$(rest "$1")/tob" "{\"key\": \"$2\", \"value\": \"$3\"}"
+}
+
+# Prints nothing once every node is synced and has the same total order state
+# containing the given key
+tob_errors() {
+    local key=$1
+    local first
+    first=$(curl -s "$(rest "${ports[0]}")/tob/state" | jq -c '.state')
+    for port in "${ports[@]}"; do
+        local state
+        state=$(curl -s "$(rest "$port")/tob/state")
+        if [[ $(echo "$state" | jq '.synced') != true ]]; then
+            echo "$port is not synced"
+        elif [[ $(echo "$state" | jq -c '.state') != "$first" ]]; then
+            echo "$port has $(echo "$state" | jq -c '.state'), ${ports[0]} has $first"
+        elif [[ $(echo "$state" | jq --arg key "$key" '.state | has($key)') != true ]]; then
+            echo "$port has not delivered $key"
+        fi
+    done
+}
+
+expect_tob() {
+    local scenario=$1
+    local key=$2
+    local errors
+    for _ in $(seq 1 15); do
+        errors=$(tob_errors "$key")
+        [[ -z "$errors" ]] && break
+        sleep 1
+    done
+    check "$scenario" "$errors"
+}
+
+# The node holding A in a snapshot, one per line
+holders_of_a() {
+    curl -s -X POST "$(rest "${ports[0]}")/snapshot" \
+        | jq -r '.nodes[] | select(.used_resources.A != null) | .addr | split(":")[1]'
+}
+
+
+start_nodes
+join_all
+sleep 3
+for port in "${left[@]}"; do
+    send_post_request "$(rest "$port")/partition" "$(peer_list "${right[@]}")"
+done
+for port in "${right[@]}"; do
+    send_post_request "$(rest "$port")/partition" "$(peer_list "${left[@]}")"
+done
+expect_ring "left half repaired" "${left[@]}"
+expect_ring "right half repaired" "${right[@]}"
+tob 2010 side left
+tob 2020 left only
+tob 2050 side right
+send_post_request "$(rest 2020)/acquire" '{"resource": "A"}'
+send_post_request "$(rest 2050)/acquire" '{"resource": "A"}'
+sleep 2
+
+
+for port in "${ports[@]}"; do
+    curl -s -X DELETE "$(rest "$port")/partition"
+done
+expect_ring "halves merged" "${ports[@]}"
+expect_single_owner
+sleep 2
+holders=$(holders_of_a)
+if [[ $(echo "$holders" | grep -c .) -eq 1 ]]; then
+    echo "ok   one holder of A after the merge"
+    holder=$holders
+    other=$([[ $holder == 2020 ]] && echo 2050 || echo 2020)
+    acquired=$(plain_log "$other" | grep -c "acquired resource A")
+    send_post_request "$(rest "$holder")/release" '{"resource": "A"}'
+    errors="$other never got A back"
+    for _ in $(seq 1 10); do
+        if [[ $(plain_log "$other" | grep -c "acquired resource A") -gt $acquired ]]; then
+            errors=""
+            break
+        fi
+        sleep 1
+    done
+    if [[ -z "$errors" ]]; then
+        echo "ok   displaced holder gets A after the release"
+    else
+        echo "FAIL $errors"
+        failures=$((failures + 1))
+    fi
+else
+    echo "FAIL expected one holder of A after the merge, got: $(echo $holders)"
+    failures=$((failures + 1))
+fi
+tob 2060 merged yes
+expect_tob "one total order after the merge" merged
+stop_nodes
+
+finish
@@ -0,0 +1,68 @@
+#!/bin/bash
+
+# Revives nodes whose resource state no longer matches the ring and checks
+# that the rejoin reconciliation sorts it out:
+#  - a holder killed with its state wiped forgot resource A, so its owner
+#    passes A on to the node queued behind it
+#  - the owner of A killed with its state wiped learns from the ring who
+#    holds A and who waits for it, so a release still reaches the next user
+#
+# Usage: ./scripts/test_rejoin_reconcile.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040)
+declare -a resources=(A B C D)
+
+# Number of times the node logged that it acquired the resource
+acquired_count() {
+    sed 's/\x1b\[[0-9;]*m//g' "/tmp/node_$1.log" | grep -c "acquired resource $2"
+}
+
+# Waits for the node to log one more acquisition of the resource than before
+expect_acquired() {
+    local scenario=$1
+    local port=$2
+    local resource=$3
+    local before=$4
+    for _ in $(seq 1 20); do
+        if [[ $(acquired_count "$port" "$resource") -gt $before ]]; then
+            echo "ok   $scenario"
+            return
+        fi
+        sleep 1
+    done
+    echo "FAIL $scenario"
+    echo "     $port never acquired $resource"
+    failures=$((failures + 1))
+}
+
+kill_and_revive() {
+    local port=$1
+    send_post_request "$(rest "$port")/kill?wipe=true"
+    sleep 12
+    send_post_request "$(rest "$port")/revive"
+}
+
+start_nodes
+join_all
+sleep 3
+
+# 2020 holds A, 2030 waits for it
+send_post_request "$(rest 2020)/acquire" '{"resource": "A"}'
+before=$(acquired_count 2030 A)
+send_post_request "$(rest 2030)/acquire" '{"resource": "A"}' &
+sleep 1
+kill_and_revive 2020
+expect_acquired "owner passes A on after its wiped holder rejoined" 2030 A "$before"
+
+# 2030 holds A now and 2040 waits for it, then the owner loses its state
+send_post_request "$(rest 2040)/acquire" '{"resource": "A"}' &
+sleep 1
+kill_and_revive 2010
+sleep 2
+before=$(acquired_count 2040 A)
+send_post_request "$(rest 2030)/release" '{"resource": "A"}'
+expect_acquired "wiped owner rebuilt holder and queue of A" 2040 A "$before"
+
+finish
@@ -0,0 +1,93 @@
+#!/bin/bash
+
+# Takes snapshots while nodes keep acquiring and releasing a resource, with
+# grants and replies going over fingers and counter-clockwise, and checks
+# every cut for consistency: whoever holds the resource, is granted it in
+# flight or is releasing it in flight must be its user at the owner, and the
+# user at the owner must be one of those. An inconsistent cut shows a holder
+# the owner has already moved past or one it has not granted yet, and a cut
+# missing channel messages loses the resource. Also checks that every member
+# is in the cut.
+#
+# Needs jq. Usage: ./scripts/test_snapshot_consistency.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050 2060)
+declare -a resources=(A B C D E F)
+declare -a users=(2030 2040 2050 2060)
+SNAPSHOTS=10
+RUNNING=/tmp/snapshot_test_running
+
+# Prints a line for every holder, grant or release in the cut that does not
+# match the owner's current user, and for a user that has none of them
+cut_errors() {
+    jq -r '
+        . as $cut
+        | [.in_flight[].message] as $messages
+        | .nodes[] | .owned_resources | to_entries[]
+        | .key as $resource | .value.current_user as $user
+        | ([$cut.nodes[] | select(.used_resources[$resource] != null) | {addr, how: "holds it"}]
+           + [$messages[] | .. | objects | select(has("Granted")) | .Granted
+              | select(.[0] == $resource) | {addr: .[1], how: "is granted it in flight"}]
+           + [$messages[] | .. | objects | select(has("Resource")) | .Resource
+              | select((.[0] | type) == "object" and .[0].Release == $resource) | {addr: .[1], how: "is releasing it in flight"}]) as $claims
+        | [$claims[] | select(.addr != $user) | "\($resource): owner has \($user // "no user"), but \(.addr) \(.how)"]
+          + if $user != null and (any($claims[]; .addr == $user) | not)
+            then ["\($resource): owner has \($user) as user, but it neither holds it nor has it on the way"]
+            else [] end
+        | .[]
+    ' "$1"
+}
+
+# Acquires and releases A until the flag file is gone
+cycle() {
+    local port=$1
+    while [[ -f $RUNNING ]]; do
+        send_post_request "$(rest "$port")/acquire" '{"resource": "A"}'
+        sleep "0.$((RANDOM % 3 + 1))"
+        send_post_request "$(rest "$port")/release" '{"resource": "A"}'
+        sleep "0.$((RANDOM % 2 + 1))"
+    done
+}
+
+cleanup() {
+    rm -f "$RUNNING"
+    [[ ${#workers[@]} -gt 0 ]] && wait "${workers[@]}" 2> /dev/null
+}
+
+workers=()
+start_nodes
+join_all
+# Member lists and fingers settle, a delay keeps messages in flight longer
+sleep 5
+for port in "${ports[@]}"; do
+    send_post_request "$(rest "$port")/delay" '{"delay_ms": 30}'
+done
+
+touch "$RUNNING"
+for port in "${users[@]}"; do
+    cycle "$port" &
+    workers+=($!)
+done
+sleep 2
+
+in_flight=0
+for n in $(seq 1 $SNAPSHOTS); do
+    initiator=${ports[$((n % ${#ports[@]}))]}
+    file=/tmp/snapshot_$n.json
+    code=$(curl -s -o "$file" -w "%{http_code}" -X POST "$(rest "$initiator")/snapshot")
+    if [[ "$code" != 200 ]]; then
+        check "snapshot $n from $initiator" "POST /snapshot returned $code"
+        continue
+    fi
+    members=$(jq '.nodes | length' "$file")
+    errors=$(cut_errors "$file")
+    [[ $members -ne ${#ports[@]} ]] && errors+=$'\n'"cut has $members of ${#ports[@]} nodes"
+    check "snapshot $n from $initiator is consistent" "${errors#$'\n'}"
+    in_flight=$((in_flight + $(jq '.in_flight | length' "$file")))
+    sleep 0.5
+done
+echo "     $in_flight messages were in flight across all cuts"
+
+finish
@@ -0,0 +1,68 @@
+#!/bin/bash
+
+# Checks the SWIM member lists served at /cluster/members: every node learns
+# every member, a member only one node cannot reach stays alive thanks to
+# indirect pings, a crashed member is declared dead and a member that leaves
+# is marked as left, everywhere.
+#
+# Usage: ./scripts/test_swim_membership.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050)
+declare -a resources=(A B C D E)
+
+# Status of member port as seen by node port
+status_at() {
+    curl -s "$(rest "$1")/cluster/members" | grep -o "\"addr\":\"$IP:$2\",\"status\":\"[a-z]*\"" | sed 's/.*"status":"\([a-z]*\)"/\1/'
+}
+
+# Prints a line for every observer that does not see member with status
+view_errors() {
+    local member=$1
+    local expected=$2
+    shift 2
+    for observer in "$@"; do
+        local seen
+        seen=$(status_at "$observer" "$member")
+        if [[ "$seen" != "$expected" ]]; then
+            echo "$observer sees $member as ${seen:-unknown}, expected $expected"
+        fi
+    done
+}
+
+expect_status() {
+    local scenario=$1
+    local member=$2
+    local expected=$3
+    shift 3
+    local errors
+    for _ in $(seq 1 20); do
+        errors=$(view_errors "$member" "$expected" "$@")
+        [[ -z "$errors" ]] && break
+        sleep 1
+    done
+    check "$scenario" "$errors"
+}
+
+start_nodes
+join_all
+
+for member in "${ports[@]}"; do
+    expect_status "every node knows $member" "$member" alive "${ports[@]}"
+done
+
+# 2010 cannot reach 2030 directly, the others ping it for 2010
+send_post_request "$(rest 2010)/partition" "{\"peers\": [\"$IP:2030\"]}"
+sleep 10
+expect_status "2030 stays alive at 2010 through indirect pings" 2030 alive 2010
+curl -s -X DELETE "$(rest 2010)/partition" > /dev/null
+
+kill -9 "${pids[3]}"
+wait "${pids[3]}" 2> /dev/null
+expect_status "crashed 2040 declared dead" 2040 dead 2010 2020 2030 2050
+
+send_post_request "$(rest 2020)/leave"
+expect_status "2020 marked as left" 2020 left 2010 2030 2050
+
+finish
@@ -0,0 +1,78 @@
+#!/bin/bash
+
+# Runs Safra's termination detection on a ring of nodes switched between
+# active and passive:
+#  - no termination is reported while one node stays active, and it is
+#    reported, and announced to every node, once that node turns passive
+#  - a permission still in transit while every node is passive keeps the
+#    computation running: the node it wakes up is active until it turns
+#    passive itself
+#
+# Needs jq. Usage: ./scripts/test_termination.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050)
+declare -a resources=(A B C D E)
+
+# Initiator of the termination a node knows about, null if none
+detected_by() {
+    curl -s "$(rest "$1")/termination" | jq -r '.initiator // "null"'
+}
+
+expect_no_termination() {
+    local scenario=$1
+    local initiator=$2
+    sleep 3
+    local detected
+    detected=$(detected_by "$initiator")
+    check "$scenario" "$([[ $detected != null ]] && echo "termination reported by $detected")"
+}
+
+# Every node must learn that the initiator detected termination
+expect_termination() {
+    local scenario=$1
+    local initiator=$2
+    local errors
+    for _ in $(seq 1 15); do
+        errors=""
+        for port in "${ports[@]}"; do
+            local detected
+            detected=$(detected_by "$port")
+            [[ $detected != "$IP:$initiator" ]] && errors+="$port knows of termination by $detected"$'\n'
+        done
+        [[ -z "$errors" ]] && break
+        sleep 1
+    done
+    check "$scenario" "${errors%$'\n'}"
+}
+
+start_nodes
+join_all
+sleep 3
+for port in "${ports[@]}"; do
+    send_post_request "$(rest "$port")/detection/auto" '{"enabled": false}'
+done
+
+# 2030 stays active
+for port in 2010 2020 2040 2050; do
+    send_post_request "$(rest "$port")/setPassive"
+done
+send_post_request "$(rest 2010)/termination/start"
+expect_no_termination "no termination while 2030 is active" 2010
+send_post_request "$(rest 2030)/setPassive"
+expect_termination "termination once 2030 is passive" 2010
+
+# 2020 waits for 2030. 2030 wakes it up and turns passive right away, its
+# slow outbox keeps the permission in transit while detection starts.
+send_post_request "$(rest 2020)/waitForMessage" "{\"address\": \"$IP:2030\"}"
+sleep 1
+send_post_request "$(rest 2030)/delay" '{"delay_ms": 2000}'
+send_post_request "$(rest 2030)/setActive"
+send_post_request "$(rest 2030)/setPassive"
+send_post_request "$(rest 2040)/termination/start"
+expect_no_termination "no termination with a permission in transit and then 2020 active" 2040
+send_post_request "$(rest 2020)/setPassive"
+expect_termination "termination once 2020 is passive again" 2040
+
+finish
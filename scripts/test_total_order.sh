@@ -0,0 +1,92 @@
+#!/bin/bash
+
+# Every node of a ring with different per-node delays sends updates to the
+# same keys at the same time, and checks that all nodes delivered the same
+# updates in the same order and hold the same state. Then crashes a node
+# while updates keep coming, so the sequencer token may be lost with it, and
+# checks the same on the survivors.
+#
+# Needs jq. Usage: ./scripts/test_total_order.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+declare -a ports=(2010 2020 2030 2040 2050)
+declare -a resources=(A B C D E)
+declare -a delays=(0 30 5 20 10)
+UPDATES=20
+
+# Updates to the keys x and y from every given node at once
+send_updates() {
+    local tag=$1
+    shift
+    local requests=()
+    for port in "$@"; do
+        (
+            for n in $(seq 1 $UPDATES); do
+                local key=$([[ $((n % 2)) -eq 0 ]] && echo x || echo y)
+                send_post_request "$(rest "$port")/tob" "{\"key\": \"$key\", \"value\": \"$tag-$port-$n\"}"
+                sleep "0.0$((RANDOM % 5))"
+            done
+        ) &
+        requests+=($!)
+    done
+    wait "${requests[@]}"
+}
+
+# Prints nothing once every given node delivered at least the given number
+# of updates, the same ones in the same order, and holds the same state
+order_errors() {
+    local expected=$1
+    shift
+    local first=$1
+    local first_order first_state
+    first_order=$(curl -s "$(rest "$first")/tob/delivered" | jq -c 'map([.seq, .origin, .command])')
+    first_state=$(curl -s "$(rest "$first")/tob/state" | jq -c '.state')
+    for port in "$@"; do
+        local state order
+        state=$(curl -s "$(rest "$port")/tob/state")
+        order=$(curl -s "$(rest "$port")/tob/delivered" | jq -c 'map([.seq, .origin, .command])')
+        if [[ $(echo "$state" | jq '.delivered_upto') -lt $expected ]]; then
+            echo "$port delivered up to $(echo "$state" | jq '.delivered_upto') of $expected"
+        elif [[ "$order" != "$first_order" ]]; then
+            echo "$port delivered another order than $first"
+        elif [[ $(echo "$state" | jq -c '.state') != "$first_state" ]]; then
+            echo "$port has state $(echo "$state" | jq -c '.state'), $first has $first_state"
+        fi
+    done
+}
+
+expect_order() {
+    local scenario=$1
+    shift
+    local errors
+    for _ in $(seq 1 20); do
+        errors=$(order_errors "$@")
+        [[ -z "$errors" ]] && break
+        sleep 1
+    done
+    check "$scenario" "$errors"
+}
+
+start_nodes
+join_all
+sleep 3
+for i in "${!ports[@]}"; do
+    send_post_request "$(rest "${ports[$i]}")/delay" "{\"delay_ms\": ${delays[$i]}}"
+done
+
+send_updates first "${ports[@]}"
+expect_order "all nodes delivered the same order" $((${#ports[@]} * UPDATES)) "${ports[@]}"
+
+# 2030 crashes while the others keep sending
+survivors=(2010 2020 2040 2050)
+send_updates second "${survivors[@]}" &
+sender=$!
+sleep 0.5
+kill -9 "${pids[2]}"
+wait "${pids[2]}" 2> /dev/null
+wait "$sender"
+expect_order "survivors delivered the same order after a crash" \
+    $(((${#ports[@]} + ${#survivors[@]}) * UPDATES)) "${survivors[@]}"
+
+finish
@@ -0,0 +1,70 @@
+#!/bin/bash
+
+# Restarts node processes that have a data directory and checks that the lock
+# state survives:
+#  - the owner of A stopped with SIGTERM comes back still knowing who holds A
+#    and who is queued for it
+#  - the holder of A killed with SIGKILL comes back still holding A, so its
+#    release reaches the owner and A goes to the queued node
+#
+# Usage: ./scripts/test_wal_restart.sh [path_to_binary]
+
+source "$(dirname "$0")/lib.sh"
+
+DATA=/tmp/wal_test
+declare -a ports=(2010 2020 2030 2040)
+declare -a resources=(A B C D)
+
+start_node() {
+    local index=$1
+    local port=${ports[$index]}
+    "$BIN" "$IP" "$port" "${resources[$index]}" "$DATA/$port" >> "/tmp/node_$port.log" 2>&1 &
+    pids[$index]=$!
+}
+
+restart_node() {
+    local signal=$1
+    local index=$2
+    kill "-$signal" "${pids[$index]}"
+    wait "${pids[$index]}" 2> /dev/null
+    start_node "$index"
+    sleep 4
+}
+
+rm -rf "$DATA"
+for i in "${!ports[@]}"; do
+    : > "/tmp/node_${ports[$i]}.log"
+    start_node "$i"
+done
+sleep 1
+join_all
+sleep 3
+
+# 2020 holds A, 2030 is queued for it
+send_post_request "$(rest 2020)/acquire" '{"resource": "A"}'
+send_post_request "$(rest 2030)/acquire" '{"resource": "A"}' &
+sleep 1
+
+restart_node TERM 0
+check "owner restored A with its holder and queue" \
+    "$(plain_log 2010 | grep -q "restored 1 owned" || echo "2010 did not restore A")"
+
+restart_node KILL 1
+check "crashed holder restored A" \
+    "$(plain_log 2020 | grep -q "restored 1 owned, 1 used" || echo "2020 did not restore its hold on A")"
+
+acquired=$(plain_log 2030 | grep -c "acquired resource A")
+send_post_request "$(rest 2020)/release" '{"resource": "A"}'
+errors="2030 never acquired A"
+for _ in $(seq 1 20); do
+    if [[ $(plain_log 2030 | grep -c "acquired resource A") -gt $acquired ]]; then
+        errors=""
+        break
+    fi
+    sleep 1
+done
+check "release after both restarts passes A to the queued node" "$errors"
+check "restarted holder still owned A when releasing" \
+    "$(plain_log 2020 | grep "does not own resource A" | head -1)"
+
+finish
@@ -1,12 +1,26 @@
+use std::collections::HashSet;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use axum::extract::State;
-use axum::http::StatusCode;
+use axum::extract::{Path, Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
 use axum::{Json, Router};
 use axum::routing::{get, post};
 use colored::Colorize;
 use serde::{Deserialize, Serialize};
-use crate::node_base::node::Node;
+use crate::node_base::barrier::BarrierOutcome;
+use crate::node_base::broadcast::DeliveredBroadcast;
+use crate::node_base::clocks::{ClockMode, LoggedEvent, VectorClock};
+use crate::node_base::kv::{KvEntry, KvOp};
+use crate::node_base::detector::{AutoDetectionConfig, DetectionMode, DetectionReport};
+use crate::node_base::node::{NeighborInfo, Node};
+use crate::node_base::placement::PlacementMode;
+use crate::node_base::repair::RepairRecord;
+use crate::node_base::snapshot::GlobalSnapshot;
+use crate::node_base::swim::MemberView;
+use crate::node_base::termination::TerminationReport;
+use crate::node_base::total_order::{SequencedMessage, TotalOrderCommand, TotalOrderSnapshot};
 use crate::node_base::resources::ResourceMessageType::{ResourceQuery};
 
 #[derive(Debug, Serialize)]
@@ -21,11 +35,6 @@ pub struct DelayConfig {
     delay_ms: u64,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct KillReviveRequest {
-    _node_id: String,
-}
-
 // Gracefully leave the topology
 async fn leave(State(node): State<Arc<Node>>) -> StatusCode {
     tracing::info!("Node {} gracefully leaving the topology", node.id.bold().green());
@@ -36,13 +45,20 @@ async fn leave(State(node): State<Arc<Node>>) -> StatusCode {
     }
 }
 
-// Kill a node (simulate crash)
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct KillConfig {
+    wipe: bool,
+}
+
+// Kill a node (simulate crash), ?wipe=true also drops its volatile state
 async fn kill(
-    State(node): State<Arc<Node>>
+    State(node): State<Arc<Node>>,
+    Query(config): Query<KillConfig>,
 ) -> StatusCode {
     tracing::info!("Killing node {}", node.id.bold().red());
 
-    match node.kill(node.addr).await {
+    match node.kill(node.addr, config.wipe).await {
         Ok(_) => StatusCode::OK,
         Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
     }
@@ -79,6 +95,16 @@ async fn set_delay(
     StatusCode::OK
 }
 
+#[derive(Deserialize)]
+struct SuccessorConfig {
+    length: usize,
+}
+
+async fn set_successors(State(node): State<Arc<Node>>, Json(config): Json<SuccessorConfig>) -> StatusCode {
+    node.set_successor_count(config.length);
+    StatusCode::OK
+}
+
 #[derive(Deserialize)]
 struct JoinRequest {
     address: String, 
@@ -91,8 +117,10 @@ async fn join_other(
 ) -> StatusCode {
     let address: SocketAddr = payload.address.parse().expect("Invalid address format");
     tracing::debug!("Node {} will request to join {}", node.id.bold().green(), address.to_string().bold().green());
-    node.try_join_other(address).await;
-    StatusCode::OK
+    match node.try_join_other(address).await {
+        Ok(_) => StatusCode::OK,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
 }
 
 async fn status(State(node): State<Arc<Node>>) {
@@ -103,7 +131,7 @@ async fn status(State(node): State<Arc<Node>>) {
 #[axum::debug_handler]
 async fn send_message(State(node): State<Arc<Node>>) -> Result<(), StatusCode> {
     tracing::debug!("Sending message to next node from {}", node.id);
-    let ans = node.send_resource_msg(ResourceQuery("C".to_string()), node.addr).await;
+    let ans = node.request_resource_msg(ResourceQuery("C".to_string())).await;
     tracing::info!("Received answer: {:?}", ans);
     Ok(())
 }
@@ -129,16 +157,38 @@ async fn release_resource(State(node): State<Arc<Node>>, Json(payload): Json<Res
     Ok(())
 }
 
+#[derive(Deserialize)]
+struct DetectionParams {
+    #[serde(default)]
+    mode: DetectionMode,
+}
+
+// Mode is picked with ?mode=and (default) or ?mode=or
 async fn start_detection(
-    State(node): State<Arc<Node>>
+    State(node): State<Arc<Node>>,
+    Query(params): Query<DetectionParams>,
 ) -> Result<(), StatusCode> {
-    tracing::info!("Starting detection on node {}", node.id);
-    match node.start_detection().await {
+    tracing::info!("Starting {:?} detection on node {}", params.mode, node.id);
+    match node.start_detection(params.mode, false).await {
         Ok(_) => Ok(()),
         Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
     }
 }
 
+async fn detection_result(State(node): State<Arc<Node>>) -> Json<Option<DetectionReport>> {
+    tracing::debug!("Detection result requested for node {}", node.id);
+    Json(node.detection_report())
+}
+
+async fn auto_detection(
+    State(node): State<Arc<Node>>,
+    Json(config): Json<AutoDetectionConfig>,
+) -> StatusCode {
+    tracing::info!("Updating automatic detection on node {}", node.id);
+    node.set_auto_detection(config);
+    StatusCode::OK
+}
+
 async fn wait_for_message(
     State(node): State<Arc<Node>>,
     Json(payload): Json<JoinRequest>,
@@ -171,12 +221,253 @@ async fn set_passive(
     }
 }
 
-pub async fn serve(node: Arc<Node>, addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+#[derive(Deserialize)]
+struct WaitForParams {
+    format: Option<String>,
+}
+
+// Global wait-for graph, JSON by default or Graphviz with ?format=dot
+async fn cluster_wait_for(
+    State(node): State<Arc<Node>>,
+    Query(params): Query<WaitForParams>,
+) -> Response {
+    tracing::info!("Wait-for graph requested on node {}", node.id);
+    let graph = match node.collect_wait_for_graph().await {
+        Ok(graph) => graph,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    match params.format.as_deref() {
+        Some("dot") => ([(header::CONTENT_TYPE, "text/vnd.graphviz")], graph.to_dot()).into_response(),
+        _ => Json(graph).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct ClockConfig {
+    mode: ClockMode,
+}
+
+// Pick the clock used for log timestamps, "lamport" or "hybrid"
+async fn set_clock(
+    State(node): State<Arc<Node>>,
+    Json(config): Json<ClockConfig>,
+) -> StatusCode {
+    tracing::debug!("Clock mode update requested for node {}: {:?}", node.id, config.mode);
+    node.set_clock_mode(config.mode);
+    StatusCode::OK
+}
+
+async fn neighbors(State(node): State<Arc<Node>>) -> Json<NeighborInfo> {
+    Json(node.neighbor_info.read().unwrap().clone())
+}
+
+async fn fingers(State(node): State<Arc<Node>>) -> Json<Vec<SocketAddr>> {
+    Json(node.fingers.read().unwrap().clone())
+}
+
+async fn repairs(State(node): State<Arc<Node>>) -> Json<Vec<RepairRecord>> {
+    Json(node.repairs())
+}
+
+async fn cluster_members(State(node): State<Arc<Node>>) -> Json<Vec<MemberView>> {
+    Json(node.cluster_members())
+}
+
+async fn events(State(node): State<Arc<Node>>) -> Json<Vec<LoggedEvent>> {
+    tracing::debug!("Event log requested for node {}", node.id);
+    Json(node.events().into_iter().collect())
+}
+
+#[derive(Deserialize)]
+struct CompareRequest {
+    a: VectorClock,
+    b: VectorClock,
+}
+
+// Tells whether event a happened before, after or concurrently with event b
+async fn compare_events(Json(payload): Json<CompareRequest>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "relation": payload.a.relation(&payload.b) }))
+}
+
+#[derive(Deserialize)]
+struct BroadcastRequest {
+    payload: String,
+}
+
+// Causally ordered broadcast to every node on the ring, returns the message's dependencies
+async fn broadcast(
+    State(node): State<Arc<Node>>,
+    Json(request): Json<BroadcastRequest>,
+) -> Json<VectorClock> {
+    tracing::debug!("Broadcast requested on node {}", node.id);
+    Json(node.broadcast(request.payload))
+}
+
+// Broadcasts in the order this node delivered them
+async fn delivered_broadcasts(State(node): State<Arc<Node>>) -> Json<Vec<DeliveredBroadcast>> {
+    Json(node.bcast_delivered.read().unwrap().iter().cloned().collect())
+}
+
+// Replicated update delivered in the same order on every node, value null deletes the key
+async fn total_order_broadcast(
+    State(node): State<Arc<Node>>,
+    Json(command): Json<TotalOrderCommand>,
+) -> StatusCode {
+    node.total_order_broadcast(command);
+    StatusCode::ACCEPTED
+}
+
+async fn total_order_state(State(node): State<Arc<Node>>) -> Json<TotalOrderSnapshot> {
+    Json(node.total_order_snapshot())
+}
+
+async fn total_order_delivered(State(node): State<Arc<Node>>) -> Json<Vec<SequencedMessage>> {
+    Json(node.total_order_delivered())
+}
+
+// Chandy-Lamport snapshot of the whole ring, taken without stopping it
+async fn snapshot(State(node): State<Arc<Node>>) -> Result<Json<GlobalSnapshot>, StatusCode> {
+    tracing::info!("Snapshot requested on node {}", node.id);
+    match node.take_snapshot().await {
+        Ok(snapshot) => Ok(Json(snapshot)),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+// Safra's token runs until every node is passive and no permission is in transit
+async fn start_termination(State(node): State<Arc<Node>>) -> StatusCode {
+    node.start_termination_detection();
+    StatusCode::ACCEPTED
+}
+
+async fn termination_result(State(node): State<Arc<Node>>) -> Json<Option<TerminationReport>> {
+    Json(node.termination.read().unwrap().detected.clone())
+}
+
+#[derive(Deserialize)]
+struct BarrierParams {
+    participants: Option<usize>,
+}
+
+// Blocks until all participants arrived, without ?participants=N every ring member takes part
+async fn barrier(
+    State(node): State<Arc<Node>>,
+    Path(name): Path<String>,
+    Query(params): Query<BarrierParams>,
+) -> Result<Json<BarrierOutcome>, StatusCode> {
+    tracing::info!("Node {} entering barrier {}", node.id, name);
+    if node.barrier_waiters.lock().unwrap().contains_key(&name) {
+        return Err(StatusCode::CONFLICT);
+    }
+    match node.barrier_wait(name, params.participants).await {
+        Ok(outcome) => Ok(Json(outcome)),
+        Err(_) => Err(StatusCode::GATEWAY_TIMEOUT),
+    }
+}
+
+async fn kv_get(State(node): State<Arc<Node>>, Path(key): Path<String>) -> Result<Json<KvEntry>, StatusCode> {
+    match node.kv_route(KvOp::Get(key)).await {
+        Ok(Some(entry)) => Ok(Json(entry)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
+
+#[derive(Deserialize)]
+struct KvValue {
+    value: String,
+}
+
+async fn kv_put(
+    State(node): State<Arc<Node>>,
+    Path(key): Path<String>,
+    Json(payload): Json<KvValue>,
+) -> Result<Json<Option<KvEntry>>, StatusCode> {
+    match node.kv_route(KvOp::Put(key, payload.value)).await {
+        Ok(entry) => Ok(Json(entry)),
+        Err(_) => Err(StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
+
+async fn kv_delete(State(node): State<Arc<Node>>, Path(key): Path<String>) -> Result<Json<Option<KvEntry>>, StatusCode> {
+    match node.kv_route(KvOp::Delete(key)).await {
+        Ok(entry) => Ok(Json(entry)),
+        Err(_) => Err(StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
+
+#[derive(Deserialize)]
+struct PlacementConfig {
+    mode: PlacementMode,
+}
+
+// "static" keeps resources where they were started, "hashed" moves them to the node their name hashes to
+async fn set_placement(
+    State(node): State<Arc<Node>>,
+    Json(config): Json<PlacementConfig>,
+) -> StatusCode {
+    tracing::info!("Placement mode {:?} requested on node {}", config.mode, node.id);
+    match node.set_placement_everywhere(config.mode).await {
+        Ok(_) => StatusCode::OK,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[derive(Deserialize)]
+struct PartitionConfig {
+    peers: Vec<SocketAddr>,
+}
+
+// Simulates a network partition: this node stops reaching the given peers
+async fn partition(State(node): State<Arc<Node>>, Json(config): Json<PartitionConfig>) -> StatusCode {
+    node.set_partition(config.peers.into_iter().collect()).await;
+    StatusCode::OK
+}
+
+async fn heal_partition(State(node): State<Arc<Node>>) -> StatusCode {
+    node.set_partition(HashSet::new()).await;
+    StatusCode::OK
+}
+
+// A killed node answers nothing but /revive, and nothing else until it rejoined
+async fn reject_when_down(State(node): State<Arc<Node>>, request: Request, next: Next) -> Response {
+    if (node.is_down() || node.is_rejoining()) && request.uri().path() != "/revive" {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    next.run(request).await
+}
+
+// Serves until shutdown resolves, then finishes the requests already accepted
+pub async fn serve(
+    node: Arc<Node>,
+    addr: SocketAddr,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> Result<(), Box<dyn std::error::Error>> {
     let app = Router::new()
         .route("/health", get(health_check))
         .route("/delay", post(set_delay))
+        .route("/clock", post(set_clock))
         .route("/joinother", post(join_other))
         .route("/status", get(status))
+        .route("/neighbors", get(neighbors))
+        .route("/successors", post(set_successors))
+        .route("/fingers", get(fingers))
+        .route("/cluster/members", get(cluster_members))
+        .route("/repairs", get(repairs))
+        .route("/events", get(events))
+        .route("/events/compare", post(compare_events))
+        .route("/broadcast", post(broadcast))
+        .route("/broadcast/delivered", get(delivered_broadcasts))
+        .route("/tob", post(total_order_broadcast))
+        .route("/tob/state", get(total_order_state))
+        .route("/tob/delivered", get(total_order_delivered))
+        .route("/snapshot", post(snapshot))
+        .route("/termination/start", post(start_termination))
+        .route("/termination", get(termination_result))
+        .route("/barrier/{name}", post(barrier))
+        .route("/kv/{key}", get(kv_get).put(kv_put).delete(kv_delete))
+        .route("/placement", post(set_placement))
+        .route("/partition", post(partition).delete(heal_partition))
         .route("/leave", post(leave))
         .route("/kill", post(kill))
         .route("/revive", post(revive))
@@ -184,14 +475,19 @@ pub async fn serve(node: Arc<Node>, addr: SocketAddr) -> Result<(), Box<dyn std:
         .route("/acquire", post(acquire_resource))
         .route("/release", post(release_resource))
         .route("/detection/start", post(start_detection))
+        .route("/detection/result", get(detection_result))
+        .route("/detection/auto", post(auto_detection))
+        .route("/cluster/waitfor", get(cluster_wait_for))
         .route("/waitForMessage", post(wait_for_message))
         .route("/setActive", post(set_active))
         .route("/setPassive", post(set_passive))
+        .layer(middleware::from_fn_with_state(node.clone(), reject_when_down))
         .with_state(node);
 
     tracing::info!("Starting REST API server on {}", addr);
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app).with_graceful_shutdown(shutdown).await?;
+    tracing::info!("REST API server on {} closed", addr);
 
     Ok(())
 }
@@ -3,10 +3,12 @@ mod api_base;
 mod rpc_base;
 
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use tracing_subscriber::EnvFilter;
 use rpc_base::server;
 use crate::api_base::api;
-use crate::node_base::node;
+use tokio::sync::oneshot;
+use crate::node_base::{detector, fingers, messaging, node, partition, shutdown, swim, total_order, wal};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -24,9 +26,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Get command line arguments
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 4 {
+    if args.len() != 4 && args.len() != 5 {
         tracing::error!("Incorrect arguments provided");
-        tracing::info!("Usage: {} <ip> <port>", args[0]);
+        tracing::info!("Usage: {} <ip> <port> <resource> [data_dir]", args[0]);
         std::process::exit(1);
     }
 
@@ -34,25 +36,78 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let port: u16 = args[2].parse()?;
     
     let temp_resource = &args[3];
+    let data_dir = args.get(4).map(PathBuf::from);
     
     let rpc_addr: SocketAddr = format!("{}:{}", ip, port).parse()?;
     tracing::info!("Starting node with address: {}", rpc_addr);
 
     // Create node and start server
     let node = node::Node::new(format!("node_{}", port), rpc_addr);
-    node.assign_resource(temp_resource.to_string()).await.expect("TODO: panic message");
+    // State replayed from the data directory replaces the resource argument
+    let restored = match &data_dir {
+        Some(dir) => node.open_data_dir(dir)?,
+        None => false,
+    };
+    if restored {
+        tracing::info!("Restored state from {}, not assigning {}", data_dir.as_ref().unwrap().display(), temp_resource);
+    } else {
+        node.assign_resource(temp_resource.to_string()).await.expect("TODO: panic message");
+    }
     
+    messaging::start_workers(node.clone());
+
     let rpc_node = node.clone();
     tokio::spawn(async move {
         if let Err(e) = server::serve_rpc(rpc_node).await {
             tracing::error!("RPC server error: {}", e);
         }
     });
+    if restored {
+        node.rejoin_after_restart().await;
+    }
     
+    tokio::spawn(detector::run_auto_detection(node.clone()));
+    tokio::spawn(total_order::run_sequencer(node.clone()));
+    tokio::spawn(fingers::run_stabilization(node.clone()));
+    tokio::spawn(partition::run_partition_probe(node.clone()));
+    tokio::spawn(swim::run_swim(node.clone()));
+    if data_dir.is_some() {
+        tokio::spawn(wal::run_wal(node.clone()));
+    }
+
     let rest_node = node.clone();
     let rest_addr = format!("{}:{}", ip, port+1).parse()?;
     tracing::info!("Created node with id: {}", node.id);
-    api::serve(rest_node, rest_addr).await?;
+    // REST keeps serving while the node leaves, it closes once that is done
+    let (close_rest, rest_closed) = oneshot::channel::<()>();
+    let mut rest = tokio::spawn(async move {
+        api::serve(rest_node, rest_addr, async move {
+            let _ = rest_closed.await;
+        }).await.map_err(|e| e.to_string())
+    });
 
-    Ok(())
+    tokio::select! {
+        result = &mut rest => {
+            // The REST server stopped on its own, e.g. the port was taken
+            result??;
+            return Ok(());
+        }
+        signal = shutdown::wait_for_signal() => {
+            tracing::info!("Received {}, node {} shutting down", signal, node.id);
+        }
+    }
+    tokio::spawn(async {
+        shutdown::wait_for_signal().await;
+        tracing::error!("Second signal, exiting without finishing the shutdown");
+        std::process::exit(shutdown::EXIT_FORCED);
+    });
+
+    let mut clean = node.shutdown().await;
+    let _ = close_rest.send(());
+    if let Err(e) = rest.await? {
+        tracing::error!("REST API server error: {}", e);
+        clean = false;
+    }
+    tracing::info!("Node {} stopped{}", node.id, if clean { "" } else { " without a clean leave" });
+    std::process::exit(if clean { shutdown::EXIT_CLEAN } else { shutdown::EXIT_UNCLEAN });
 }
@@ -0,0 +1,265 @@
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use super::node::Node;
+
+// How often a waiting participant checks that its coordinator is still alive
+const COORDINATOR_CHECK: Duration = Duration::from_secs(2);
+// Give up waiting for the other participants after this long
+const BARRIER_TIMEOUT: Duration = Duration::from_secs(300);
+// How often an arrival may be sent on to the pinned coordinator
+const MAX_REDIRECTS: usize = 3;
+
+// Who has to arrive before a barrier opens
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BarrierGoal {
+    Count(usize),
+    // Every node that was on the ring when the first participant arrived
+    Members(BTreeSet<SocketAddr>),
+}
+
+// Barrier kept by the coordinator, the lowest address on the ring when the
+// first participant arrived
+#[derive(Debug)]
+pub struct BarrierState {
+    pub goal: BarrierGoal,
+    pub arrived: BTreeSet<SocketAddr>,
+}
+
+impl BarrierState {
+    fn is_complete(&self) -> bool {
+        match &self.goal {
+            BarrierGoal::Count(count) => self.arrived.len() >= *count,
+            BarrierGoal::Members(members) => members.is_subset(&self.arrived),
+        }
+    }
+}
+
+// Participant side of a barrier we are blocked in
+#[derive(Debug)]
+pub struct BarrierWaiter {
+    pub coordinator: SocketAddr,
+    pub done: Option<oneshot::Sender<Vec<SocketAddr>>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BarrierOutcome {
+    pub name: String,
+    pub coordinator: SocketAddr,
+    pub participants: Vec<SocketAddr>,
+    pub waited_ms: u128,
+}
+
+impl Node {
+    // Block until every participant of the named barrier has arrived.
+    // Without a count, all nodes currently on the ring take part.
+    pub async fn barrier_wait(&self, name: String, participants: Option<usize>) -> Result<BarrierOutcome, Box<dyn Error>> {
+        let started = Instant::now();
+        let (tx, mut rx) = oneshot::channel();
+        {
+            // A node arrives once, a second local caller would take over the first one's release
+            let mut waiters = self.barrier_waiters.lock().unwrap();
+            if waiters.contains_key(&name) {
+                return Err(format!("Already waiting at barrier {}", name).into());
+            }
+            waiters.insert(name.clone(), BarrierWaiter { coordinator: self.addr, done: Some(tx) });
+        }
+
+        let result = async {
+            let mut coordinator = self.barrier_arrive_at_coordinator(&name, participants).await?;
+            loop {
+                if started.elapsed() > BARRIER_TIMEOUT {
+                    return Err::<_, Box<dyn Error>>("Timed out waiting at barrier".into());
+                }
+                match tokio::time::timeout(COORDINATOR_CHECK, &mut rx).await {
+                    Ok(Ok(arrived)) => return Ok((coordinator, arrived)),
+                    Ok(Err(_)) => return Err("Barrier dropped".into()),
+                    Err(_) => {}
+                }
+
+                // Coordinator gone, arrive again at whoever coordinates now
+                let (client, ctx) = self.rpc.get_c(coordinator).await;
+                let alive = match client {
+                    Ok(client) => client.heartbeat(ctx, self.stamp_send()).await.is_ok(),
+                    Err(_) => false,
+                };
+                if !alive {
+                    tracing::info!("Node {} lost barrier {} coordinator {}, arriving again", self.id.bold().yellow(), name, coordinator);
+                    self.rpc.delete_client(coordinator).await;
+                    self.drop_barrier_pins(coordinator);
+                    coordinator = self.barrier_arrive_at_coordinator(&name, participants).await?;
+                }
+            }
+        }.await;
+        self.barrier_waiters.lock().unwrap().remove(&name);
+
+        let (coordinator, participants) = result?;
+        tracing::info!("T: {}. Node {} passed barrier {} with {:?}", self.log_time(), self.id.bold().green(), name, participants);
+        self.local_event(format!("passed barrier {}", name));
+        Ok(BarrierOutcome { name, coordinator, participants, waited_ms: started.elapsed().as_millis() })
+    }
+
+    // The barrier stays with the coordinator it was pinned to, a lower node
+    // joining meanwhile sends the arrival on to it
+    async fn barrier_arrive_at_coordinator(&self, name: &str, participants: Option<usize>) -> Result<SocketAddr, Box<dyn Error>> {
+        let members = self.ring_members().await?;
+        let pinned = self.barrier_pins.lock().unwrap().get(name).copied();
+        let mut coordinator = match pinned {
+            Some(coordinator) => coordinator,
+            None => *members.iter().min().ok_or("Empty ring")?,
+        };
+        let goal = match participants {
+            Some(count) => BarrierGoal::Count(count),
+            None => BarrierGoal::Members(members.into_iter().collect()),
+        };
+
+        for _ in 0..=MAX_REDIRECTS {
+            if let Some(waiter) = self.barrier_waiters.lock().unwrap().get_mut(name) {
+                waiter.coordinator = coordinator;
+            }
+            tracing::info!("T: {}. Node {} arriving at barrier {} (coordinator {})", self.log_time(), self.id.bold().green(), name, coordinator);
+            let (client, ctx) = self.rpc.get_c(coordinator).await;
+            let client = client.map_err(|_| format!("Cannot reach {}", coordinator))?;
+            match client.barrier_arrive(ctx, name.to_string(), self.addr, goal.clone(), self.stamp_send()).await? {
+                None => return Ok(coordinator),
+                Some(pinned) => {
+                    tracing::info!("Node {} barrier {} is pinned to {}", self.id.bold().yellow(), name, pinned);
+                    coordinator = pinned;
+                }
+            }
+        }
+        Err(format!("Could not find the coordinator of barrier {}", name).into())
+    }
+
+    // Coordinator side, registers the arrival and opens the barrier when it is
+    // complete. An arrival for a barrier pinned to another live node is
+    // answered with that node.
+    pub async fn handle_barrier_arrive(&self, name: String, participant: SocketAddr, goal: BarrierGoal) -> Option<SocketAddr> {
+        let pinned = self.barrier_pins.lock().unwrap().get(&name).copied();
+        if let Some(pinned) = pinned.filter(|pinned| *pinned != self.addr) {
+            if self.is_alive(pinned).await {
+                return Some(pinned);
+            }
+            self.drop_barrier_pins(pinned);
+        }
+
+        tracing::info!("Node {} barrier {}: {} arrived", self.id.bold().green(), name, participant);
+        let (opened, first) = {
+            let mut barriers = self.barriers.lock().unwrap();
+            let first = !barriers.contains_key(&name);
+            let barrier = barriers.entry(name.clone()).or_insert(BarrierState { goal, arrived: BTreeSet::new() });
+            barrier.arrived.insert(participant);
+            (Self::take_if_complete(&mut barriers, &name), first)
+        };
+        if let Some(arrived) = opened {
+            self.release_barrier(name, arrived).await;
+        } else if first {
+            self.barrier_pins.lock().unwrap().insert(name.clone(), self.addr);
+            self.pin_barriers_at_members(vec![name]).await;
+        }
+        None
+    }
+
+    // Tell the members, new ones included, which barriers we coordinate
+    pub async fn announce_barrier_pins(&self) {
+        let names: Vec<String> = self.barriers.lock().unwrap().keys().cloned().collect();
+        if !names.is_empty() {
+            self.pin_barriers_at_members(names).await;
+        }
+    }
+
+    async fn pin_barriers_at_members(&self, names: Vec<String>) {
+        let members = self.members.read().unwrap().clone();
+        for member in members.into_iter().filter(|member| *member != self.addr) {
+            let (client, ctx) = self.rpc.get_c(member).await;
+            let pinned = match client {
+                Ok(client) => client.barrier_pin(ctx, names.clone(), self.addr, self.stamp_send()).await.unwrap_or(false),
+                Err(_) => false,
+            };
+            if !pinned {
+                tracing::error!("Node {} could not pin barriers {:?} at {}", self.id.bold().red(), names, member);
+            }
+        }
+    }
+
+    pub fn handle_barrier_pin(&self, names: Vec<String>, coordinator: SocketAddr) {
+        let mut pins = self.barrier_pins.lock().unwrap();
+        for name in names {
+            pins.insert(name, coordinator);
+        }
+    }
+
+    fn drop_barrier_pins(&self, coordinator: SocketAddr) {
+        self.barrier_pins.lock().unwrap().retain(|_, pinned| *pinned != coordinator);
+    }
+
+    pub fn handle_barrier_release(&self, name: String, participants: Vec<SocketAddr>) -> bool {
+        let mut waiters = self.barrier_waiters.lock().unwrap();
+        match waiters.get_mut(&name).and_then(|w| w.done.take()) {
+            Some(done) => done.send(participants).is_ok(),
+            None => {
+                tracing::debug!("Node {} released from barrier {} it is not waiting at", self.id, name);
+                false
+            }
+        }
+    }
+
+    // A dead participant is no longer waited for. Its arrival is dropped, and
+    // a count only goes down if it had arrived, since a node that never took
+    // part does not stand for anyone the count was waiting for.
+    pub async fn barrier_participant_lost(&self, missing_node: SocketAddr) {
+        self.drop_barrier_pins(missing_node);
+        let opened: Vec<(String, Vec<SocketAddr>)> = {
+            let mut barriers = self.barriers.lock().unwrap();
+            let names: Vec<String> = barriers.keys().cloned().collect();
+            let mut opened = Vec::new();
+            for name in names {
+                let barrier = barriers.get_mut(&name).unwrap();
+                let had_arrived = barrier.arrived.remove(&missing_node);
+                match &mut barrier.goal {
+                    BarrierGoal::Count(count) if had_arrived => *count = count.saturating_sub(1),
+                    BarrierGoal::Count(_) => {}
+                    BarrierGoal::Members(members) => { members.remove(&missing_node); },
+                }
+                if let Some(arrived) = Self::take_if_complete(&mut barriers, &name) {
+                    opened.push((name, arrived));
+                }
+            }
+            opened
+        };
+        for (name, arrived) in opened {
+            tracing::info!("Node {} opening barrier {} after losing {}", self.id.bold().yellow(), name, missing_node);
+            self.release_barrier(name, arrived).await;
+        }
+    }
+
+    fn take_if_complete(barriers: &mut HashMap<String, BarrierState>, name: &str) -> Option<Vec<SocketAddr>> {
+        if !barriers.get(name)?.is_complete() {
+            return None;
+        }
+        barriers.remove(name).map(|b| b.arrived.into_iter().collect())
+    }
+
+    async fn release_barrier(&self, name: String, arrived: Vec<SocketAddr>) {
+        tracing::info!("T: {}. Node {} opening barrier {} for {:?}", self.log_time(), self.id.bold().green(), name, arrived);
+        self.local_event(format!("opened barrier {}", name));
+        for participant in &arrived {
+            if *participant == self.addr {
+                self.handle_barrier_release(name.clone(), arrived.clone());
+                continue;
+            }
+            let (client, ctx) = self.rpc.get_c(*participant).await;
+            let released = match client {
+                Ok(client) => client.barrier_release(ctx, name.clone(), arrived.clone(), self.stamp_send()).await.unwrap_or(false),
+                Err(_) => false,
+            };
+            if !released {
+                tracing::error!("Node {} could not release {} from barrier {}", self.id.bold().red(), participant, name);
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::net::SocketAddr;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use super::clocks::VectorClock;
+use super::messaging::RingMessage;
+use super::node::Node;
+
+// How many delivered broadcasts a node keeps for GET /broadcast/delivered
+const DELIVERED_FEED_SIZE: usize = 256;
+// How many broadcasts a node holds back at most while waiting for their dependencies
+const PENDING_LIMIT: usize = 1024;
+
+// Broadcast that goes once around the ring. `deps` counts, per origin, the
+// broadcasts the sender had delivered when sending (its own included), so
+// receivers can hold it back until everything it depends on is delivered.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CausalMessage {
+    pub origin: SocketAddr,
+    pub deps: VectorClock,
+    pub payload: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveredBroadcast {
+    pub origin: SocketAddr,
+    pub seq: u64,
+    pub deps: VectorClock,
+    pub payload: String,
+}
+
+impl Node {
+    pub fn broadcast(&self, payload: String) -> VectorClock {
+        let deps = {
+            let mut clock = self.bcast_clock.write().unwrap();
+            clock.tick(self.addr);
+            clock.clone()
+        };
+        let msg = CausalMessage { origin: self.addr, deps: deps.clone(), payload };
+        tracing::info!("T: {}. Node {} broadcasting {:?}", self.log_time(), self.id.bold().green(), msg);
+
+        // Our own message can always be delivered right away
+        self.record_delivery(&msg);
+        if self.neighbor_info.read().unwrap().next != self.addr {
+            self.post(RingMessage::Broadcast(msg));
+        }
+        deps
+    }
+
+    pub fn handle_broadcast(&self, msg: CausalMessage) {
+        if msg.origin == self.addr {
+            tracing::debug!("Node {} broadcast {:?} went around the ring", self.id, msg.deps);
+            return;
+        }
+
+        // A message we already have stops here, so it does not keep going
+        // around when its origin is gone
+        let seq = msg.deps.get(msg.origin);
+        let known = seq <= self.bcast_clock.read().unwrap().get(msg.origin)
+            || self.bcast_pending.read().unwrap().iter().any(|m| m.origin == msg.origin && m.deps.get(m.origin) == seq);
+        if known {
+            tracing::debug!("Node {} dropping duplicate broadcast {} from {}", self.id, seq, msg.origin);
+            return;
+        }
+
+        // Pass it on first, delivery here may have to wait
+        if self.neighbor_info.read().unwrap().next != msg.origin {
+            self.post(RingMessage::Broadcast(msg.clone()));
+        }
+
+        {
+            let mut pending = self.bcast_pending.write().unwrap();
+            if pending.len() >= PENDING_LIMIT {
+                let dropped = pending.remove(0);
+                tracing::error!("Node {} holds too many broadcasts, dropping {} from {}", self.id.bold().red(), dropped.deps.get(dropped.origin), dropped.origin);
+            }
+            pending.push(msg);
+        }
+        self.deliver_ready_broadcasts();
+    }
+
+    // Deliver buffered messages whose causal dependencies are satisfied
+    fn deliver_ready_broadcasts(&self) {
+        loop {
+            let ready = {
+                let clock = self.bcast_clock.read().unwrap();
+                let mut pending = self.bcast_pending.write().unwrap();
+                let position = pending.iter().position(|m| Self::deliverable(&clock, m));
+                position.map(|i| pending.remove(i))
+            };
+            let Some(msg) = ready else {
+                break;
+            };
+
+            // Duplicates (e.g. resent after a repair) are dropped
+            if msg.deps.get(msg.origin) <= self.bcast_clock.read().unwrap().get(msg.origin) {
+                continue;
+            }
+            self.bcast_clock.write().unwrap().tick(msg.origin);
+            self.record_delivery(&msg);
+        }
+
+        let waiting = self.bcast_pending.read().unwrap().len();
+        if waiting > 0 {
+            tracing::debug!("Node {} holding back {} broadcast(s)", self.id, waiting);
+        }
+    }
+
+    fn deliverable(clock: &VectorClock, msg: &CausalMessage) -> bool {
+        let next_from_origin = msg.deps.get(msg.origin) <= clock.get(msg.origin) + 1;
+        let deps_delivered = msg.deps.0.iter()
+            .filter(|(addr, _)| **addr != msg.origin)
+            .all(|(addr, count)| *count <= clock.get(*addr));
+        next_from_origin && deps_delivered
+    }
+
+    fn record_delivery(&self, msg: &CausalMessage) {
+        tracing::info!("T: {}. Node {} delivered broadcast from {}: {}", self.log_time(), self.id.bold().green(), msg.origin, msg.payload);
+        self.local_event(format!("delivered broadcast {} from {}", msg.deps.get(msg.origin), msg.origin));
+
+        let mut feed = self.bcast_delivered.write().unwrap();
+        if feed.len() == DELIVERED_FEED_SIZE {
+            feed.pop_front();
+        }
+        feed.push_back(DeliveredBroadcast {
+            origin: msg.origin,
+            seq: msg.deps.get(msg.origin),
+            deps: msg.deps.clone(),
+            payload: msg.payload.clone(),
+        });
+    }
+}
@@ -0,0 +1,250 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use super::node::Node;
+use super::swim::MemberUpdate;
+
+// How many events a node keeps for GET /events
+const EVENT_LOG_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VectorClock(pub BTreeMap<SocketAddr, u64>);
+
+impl VectorClock {
+    pub fn get(&self, addr: SocketAddr) -> u64 {
+        *self.0.get(&addr).unwrap_or(&0)
+    }
+
+    pub fn tick(&mut self, addr: SocketAddr) {
+        *self.0.entry(addr).or_insert(0) += 1;
+    }
+
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (addr, time) in &other.0 {
+            let entry = self.0.entry(*addr).or_insert(0);
+            *entry = std::cmp::max(*entry, *time);
+        }
+    }
+
+    // Less if self happened before other, None if they are concurrent
+    pub fn compare(&self, other: &VectorClock) -> Option<Ordering> {
+        let mut less = false;
+        let mut greater = false;
+        for addr in self.0.keys().chain(other.0.keys()) {
+            match self.get(*addr).cmp(&other.get(*addr)) {
+                Ordering::Less => less = true,
+                Ordering::Greater => greater = true,
+                Ordering::Equal => {}
+            }
+        }
+        match (less, greater) {
+            (false, false) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (true, true) => None,
+        }
+    }
+
+    pub fn relation(&self, other: &VectorClock) -> &'static str {
+        match self.compare(other) {
+            Some(Ordering::Less) => "happened_before",
+            Some(Ordering::Greater) => "happened_after",
+            Some(Ordering::Equal) => "equal",
+            None => "concurrent",
+        }
+    }
+}
+
+impl fmt::Display for VectorClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries: Vec<String> = self.0.iter().map(|(addr, time)| format!("{}:{}", addr.port(), time)).collect();
+        write!(f, "[{}]", entries.join(", "))
+    }
+}
+
+// Hybrid logical clock: physical milliseconds plus a logical counter for
+// events that happen within the same millisecond (or while a peer is ahead)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct HybridTimestamp {
+    pub wall_ms: u64,
+    pub logical: u32,
+}
+
+impl HybridTimestamp {
+    fn physical_now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+    }
+
+    // Local or send event
+    pub fn tick(&mut self) {
+        let now = Self::physical_now();
+        if now > self.wall_ms {
+            self.wall_ms = now;
+            self.logical = 0;
+        } else {
+            self.logical += 1;
+        }
+    }
+
+    // Receive event
+    pub fn merge(&mut self, remote: &HybridTimestamp) {
+        let now = Self::physical_now();
+        let wall_ms = now.max(self.wall_ms).max(remote.wall_ms);
+        self.logical = if wall_ms == self.wall_ms && wall_ms == remote.wall_ms {
+            self.logical.max(remote.logical) + 1
+        } else if wall_ms == self.wall_ms {
+            self.logical + 1
+        } else if wall_ms == remote.wall_ms {
+            remote.logical + 1
+        } else {
+            0
+        };
+        self.wall_ms = wall_ms;
+    }
+}
+
+impl fmt::Display for HybridTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:03}+{}", self.wall_ms / 1000, self.wall_ms % 1000, self.logical)
+    }
+}
+
+// Which clock is shown in the "T: ..." part of the logs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockMode {
+    #[default]
+    Lamport,
+    Hybrid,
+}
+
+// Clock values piggybacked on every NodeRpc call
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcHeader {
+    // Node at the sending end of the channel
+    pub sender: SocketAddr,
+    pub lamport: u64,
+    pub vclock: VectorClock,
+    pub hlc: HybridTimestamp,
+    // SWIM membership updates
+    #[serde(default)]
+    pub gossip: Vec<MemberUpdate>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LoggedEvent {
+    pub lamport: u64,
+    pub vclock: VectorClock,
+    pub hlc: HybridTimestamp,
+    pub description: String,
+}
+
+impl Node {
+    // Send event: tick all clocks and return the values to put on the message
+    pub fn stamp_send(&self) -> RpcHeader {
+        let lamport = self.increment_lamport();
+        let mut vclock = self.vector_clock.write().unwrap();
+        vclock.tick(self.addr);
+        let mut hlc = self.hlc.write().unwrap();
+        hlc.tick();
+        RpcHeader { sender: self.addr, lamport, vclock: vclock.clone(), hlc: *hlc, gossip: self.gossip_to_send() }
+    }
+
+    // Receive event: merge what the sender knew and tick our own entry
+    pub fn observe(&self, header: &RpcHeader) {
+        self.update_clock(header.lamport);
+        let mut vclock = self.vector_clock.write().unwrap();
+        vclock.merge(&header.vclock);
+        vclock.tick(self.addr);
+        self.hlc.write().unwrap().merge(&header.hlc);
+        self.apply_gossip(&header.gossip);
+    }
+
+    // Internal event worth remembering (grants, detections, ...)
+    pub fn local_event(&self, description: String) {
+        self.vector_clock.write().unwrap().tick(self.addr);
+        self.hlc.write().unwrap().tick();
+        self.log_event(description);
+    }
+
+    pub fn set_clock_mode(&self, mode: ClockMode) {
+        tracing::debug!("Node {} logging with {:?} clock", self.id, mode);
+        *self.clock_mode.write().unwrap() = mode;
+    }
+
+    // Timestamp for log lines in the configured clock
+    pub fn log_time(&self) -> String {
+        match *self.clock_mode.read().unwrap() {
+            ClockMode::Lamport => self.lamport_time.read().unwrap().to_string(),
+            ClockMode::Hybrid => self.hlc.read().unwrap().to_string(),
+        }
+    }
+
+    // Remember an event with the current clock values
+    pub fn log_event(&self, description: String) {
+        let event = LoggedEvent {
+            lamport: *self.lamport_time.read().unwrap(),
+            vclock: self.vector_clock.read().unwrap().clone(),
+            hlc: *self.hlc.read().unwrap(),
+            description,
+        };
+        tracing::debug!("Node {} event T: {} HLC: {} VC: {} {}", self.id, event.lamport, event.hlc, event.vclock, event.description);
+        let mut log = self.event_log.write().unwrap();
+        if log.len() == EVENT_LOG_SIZE {
+            log.pop_front();
+        }
+        log.push_back(event);
+    }
+
+    pub fn events(&self) -> VecDeque<LoggedEvent> {
+        self.event_log.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(entries: &[(u16, u64)]) -> VectorClock {
+        VectorClock(entries.iter().map(|(port, time)| (SocketAddr::from(([127, 0, 0, 1], *port)), *time)).collect())
+    }
+
+    #[test]
+    fn compare_orders_causally_related_clocks() {
+        let before = clock(&[(2010, 1), (2020, 2)]);
+        let after = clock(&[(2010, 2), (2020, 2)]);
+        assert_eq!(before.compare(&after), Some(Ordering::Less));
+        assert_eq!(after.compare(&before), Some(Ordering::Greater));
+        assert_eq!(before.relation(&after), "happened_before");
+        assert_eq!(after.relation(&before), "happened_after");
+    }
+
+    #[test]
+    fn compare_detects_concurrent_clocks() {
+        let a = clock(&[(2010, 2), (2020, 1)]);
+        let b = clock(&[(2010, 1), (2020, 2)]);
+        assert_eq!(a.compare(&b), None);
+        assert_eq!(a.relation(&b), "concurrent");
+    }
+
+    #[test]
+    fn missing_entries_count_as_zero() {
+        let short = clock(&[(2010, 1)]);
+        let long = clock(&[(2010, 1), (2020, 0)]);
+        assert_eq!(short.compare(&long), Some(Ordering::Equal));
+        assert_eq!(short.compare(&clock(&[(2010, 1), (2020, 1)])), Some(Ordering::Less));
+        assert_eq!(clock(&[(2020, 1)]).compare(&short), None);
+    }
+
+    #[test]
+    fn merge_takes_the_maximum_of_every_entry() {
+        let mut a = clock(&[(2010, 3), (2020, 1)]);
+        a.merge(&clock(&[(2020, 4), (2030, 2)]));
+        assert_eq!(a, clock(&[(2010, 3), (2020, 4), (2030, 2)]));
+        a.tick(SocketAddr::from(([127, 0, 0, 1], 2010)));
+        assert_eq!(a.get(SocketAddr::from(([127, 0, 0, 1], 2010))), 4);
+    }
+}
@@ -1,7 +1,7 @@
-use std::collections::HashSet;
 use std::error::Error;
 use std::net::SocketAddr;
 use serde::{Deserialize, Serialize};
+use super::detector::DetectionMode;
 use super::node::Node;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -12,110 +12,138 @@ pub struct ProbeMessage {
     pub i: SocketAddr  // receiver
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CmhMessageType {
     // CMH Messages
     ProbeRequest(ProbeMessage),
     ProbeAnswer(SocketAddr, u64, SocketAddr, SocketAddr),
     DetectionStart,
 
+    // OR model diffusing computation
+    OrQuery(ProbeMessage),
+    OrReply(ProbeMessage),
+
     // Communication Messages
     RequestPermission(SocketAddr),
-    DenyPermission,
+    DenyPermission(SocketAddr),
     GrantPermission(SocketAddr),
 
     // Error Messages
     Success(u64),
     Error(u64)
 }
+// Detection state is kept per (initiator, test number), so runs started by
+// several initiators at the same time don't share counters or parents
+pub type RunId = (SocketAddr, u64);
+
 impl Node {
-    pub async fn start_detection(&self) -> Result<CmhMessageType, Box<dyn Error>> {
+    pub async fn start_and_detection(&self, automatic: bool) -> Result<CmhMessageType, Box<dyn Error>> {
         if *self.is_active.read().unwrap() {
             tracing::error!("Cannot start detection - node {} is active", self.id);
             return Ok(CmhMessageType::Error(*self.lamport_time.read().unwrap()));
         }
-        tracing::info!("T: {}. Starting detection for node {}", self.lamport_time.read().unwrap(), self.id);
+        tracing::info!("T: {}. Starting detection for node {}", self.log_time(), self.id);
 
         let k = self.addr;
+        let waiting_for = self.waiting_messages_from.read().unwrap().clone();
 
-        {
+        let test_num = {
             let mut last = self.last_test.write().unwrap();
-            *last.entry(k).or_insert(0) += 1;
-            let _test_num = last[&k];
+            let test_num = last.entry(k).or_insert(0);
+            *test_num += 1;
+            *test_num
+        };
+        // The count has to be in place before the first probe goes out, answers can come back while sending
+        self.engage_run((k, test_num), None, waiting_for.len() as u32);
+        self.record_detection_start(test_num, DetectionMode::And, automatic);
 
-            let mut wait = self.wait_status.write().unwrap();
-            wait.insert(k, true);
+        for owner in waiting_for {
+            let msg = CmhMessageType::ProbeRequest(ProbeMessage {
+                k,
+                m: test_num,
+                j: self.addr,
+                i: owner
+            });
+            tracing::debug!("Node {} sending probe to {}: {:?}", self.id, owner, msg);
+            self.send_cmh_msg(msg, self.addr);
         }
 
-        let test_num = self.last_test.read().unwrap()[&k];
-        let mut count = 0;
+        Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
+    }
 
-        // Get unique set of nodes we're waiting for
-        let waiting_for = self.waiting_messages_from.read().unwrap().clone();
-        let mut sent_to = HashSet::new();
+    // Take part in a run, older runs of the same initiator are dropped
+    pub fn engage_run(&self, run: RunId, parent: Option<SocketAddr>, pending: u32) {
+        let (k, m) = run;
+        self.last_test.write().unwrap().insert(k, m);
+        self.drop_runs(|(i, t)| i == k && t < m);
 
-        for owner in waiting_for {
-            if sent_to.insert(owner) {  // Only send once per unique owner
-                let msg = CmhMessageType::ProbeRequest(ProbeMessage {
-                    k,
-                    m: test_num,
-                    j: self.addr,
-                    i: owner
-                });
-                tracing::debug!("Node {} sending probe to {}: {:?}", self.id, owner, msg);
-                count += 1;
-                self.probe_count.write().unwrap().insert(k, count);
-                self.send_cmh_msg(msg, self.addr).await;
-            }
+        self.wait_status.write().unwrap().insert(run, true);
+        if let Some(parent) = parent {
+            self.parent_nodes.write().unwrap().insert(run, parent);
         }
+        self.probe_count.write().unwrap().insert(run, pending);
+    }
 
-        Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
+    pub fn is_waiting(&self, run: RunId) -> bool {
+        let latest = *self.last_test.read().unwrap().get(&run.0).unwrap_or(&0);
+        latest == run.1 && *self.wait_status.read().unwrap().get(&run).unwrap_or(&false)
+    }
+
+    // Count an answer for the run, returns how many are still outstanding
+    pub fn answer_received(&self, run: RunId) -> Option<u32> {
+        let mut count = self.probe_count.write().unwrap();
+        let n = count.get_mut(&run)?;
+        *n = n.saturating_sub(1);
+        Some(*n)
+    }
+
+    // Forget a run this node is done with
+    pub fn finish_run(&self, run: RunId) {
+        self.drop_runs(|r| r == run);
+    }
+
+    pub fn drop_runs(&self, stale: impl Fn(RunId) -> bool) {
+        self.wait_status.write().unwrap().retain(|r, _| !stale(*r));
+        self.parent_nodes.write().unwrap().retain(|r, _| !stale(*r));
+        self.probe_count.write().unwrap().retain(|r, _| !stale(*r));
     }
 
 
     // State Management
     pub async fn set_active(&self) -> Result<(), Box<dyn Error>> {
-        tracing::info!("T: {}.  Node {} setting active", self.lamport_time.read().unwrap(), self.id);
-        *self.is_active.write().unwrap() = true;
+        tracing::info!("T: {}.  Node {} setting active", self.log_time(), self.id);
+        self.mark_active(true);
+        self.local_event("set active".to_string());
         // if someone is waiting for me, send them receiveMessage messages from permission queue
         self.waiting_messages_from.write().unwrap().clear();
         let perm_list = self.permission_queue.write().unwrap().clone();
         for owner in perm_list {
             let msg = CmhMessageType::GrantPermission(owner);
             self.permission_queue.write().unwrap().remove(&owner);
-            self.send_cmh_msg(msg, self.addr).await;
+            self.basic_message_sent();
+            self.send_cmh_msg(msg, self.addr);
         }
         self.permission_queue.write().unwrap().clear();
-        tracing::info!("T: {}. Node {} is now active", self.lamport_time.read().unwrap(), self.id);
+        tracing::info!("T: {}. Node {} is now active", self.log_time(), self.id);
         Ok(())
     }
 
     pub async fn set_passive(&self) -> Result<(), Box<dyn Error>> {
-        *self.is_active.write().unwrap() = false;
+        self.mark_active(false);
+        self.local_event("set passive".to_string());
         Ok(())
     }
 
     pub async fn handle_waiting_for(&self, from: SocketAddr) -> Result<CmhMessageType, Box<dyn Error>> {
-        *self.is_active.write().unwrap() = false;
+        self.mark_active(false);
 
         self.waiting_messages_from.write().unwrap().insert(from);
 
         // now send a perm request, this is just dummy communication
+        // the answer comes back as a GrantPermission or DenyPermission message
         let msg = CmhMessageType::RequestPermission(from);
-        match self.send_cmh_msg(msg, self.addr).await {
-            CmhMessageType::GrantPermission(_addr) => {
-                tracing::info!("T: {}. Node {} granted permission to {}", self.lamport_time.read().unwrap(), from, self.id);
-                self.waiting_messages_from.write().unwrap().remove(&from);
-                *self.is_active.write().unwrap() = true;
-                Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
-            },
-            CmhMessageType::DenyPermission => {
-                tracing::info!("T: {}, Node {} denied permission to {}", self.lamport_time.read().unwrap(), self.id, from);
-                Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
-            },
-            CmhMessageType::Error(_lamp) => Err("Error sending message".into()),
-            _ => todo!(),
-        }
+        self.send_cmh_msg(msg, self.addr);
+        Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
     }
 
     pub async fn handle_permission_from(&self, from: SocketAddr) -> Result<CmhMessageType, Box<dyn Error>> {
@@ -129,7 +157,7 @@ impl Node {
     }
 
     pub async fn handle_cmh_message(&self, msg: CmhMessageType, from: SocketAddr) -> Result<CmhMessageType, Box<dyn Error>> {
-        tracing::info!("T: {}. Node {} received CMH message from {}: {:?}", self.lamport_time.read().unwrap(), self.id, from, msg);
+        tracing::info!("T: {}. Node {} received CMH message from {}: {:?}", self.log_time(), self.id, from, msg);
 
         // if from == self.addr {
         //     tracing::info!("Node {} received message from itself", self.id);
@@ -139,29 +167,44 @@ impl Node {
         match msg {
             CmhMessageType::RequestPermission(addr) => {
                 if addr == self.addr {
-                    tracing::info!("T: {}. Node {} received permission request from {}", self.lamport_time.read().unwrap(), self.id, from);
+                    tracing::info!("T: {}. Node {} received permission request from {}", self.log_time(), self.id, from);
                     if *self.is_active.read().unwrap() {
                         tracing::debug!("Node {} is active - granting permission to {}", self.id, from);
-                        Ok(CmhMessageType::GrantPermission(from))
+                        self.basic_message_sent();
+                        self.send_cmh_msg(CmhMessageType::GrantPermission(from), self.addr);
                     } else {
                         self.permission_queue.write().unwrap().insert(from);
-                        Ok(CmhMessageType::DenyPermission)
+                        self.send_cmh_msg(CmhMessageType::DenyPermission(from), self.addr);
                     }
+                    Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
 
                 } else {
                     tracing::debug!("Node {} forwarding permission request to {}", self.id, addr);
                     // Send to next node
-                    Ok(self.send_cmh_msg(CmhMessageType::RequestPermission(addr), from).await)
+                    self.send_cmh_msg(CmhMessageType::RequestPermission(addr), from);
+                    Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
                 }
             },
             CmhMessageType::GrantPermission(addr) => {
                 if addr == self.addr {
-                    tracing::info!("T: {}. Node {} received permission from {}", self.lamport_time.read().unwrap(), self.id, from);
+                    tracing::info!("T: {}. Node {} received permission from {}", self.log_time(), self.id, from);
+                    self.basic_message_received();
                     self.handle_permission_from(from).await
                 } else {
                     tracing::debug!("Node {} forwarding permission to {}", self.id, addr);
                     // Send to next node
-                    Ok(self.send_cmh_msg(CmhMessageType::GrantPermission(addr), from).await)
+                    self.send_cmh_msg(CmhMessageType::GrantPermission(addr), from);
+                    Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
+                }
+            },
+            CmhMessageType::DenyPermission(addr) => {
+                if addr == self.addr {
+                    tracing::info!("T: {}, Node {} denied permission by {}", self.log_time(), self.id, from);
+                    Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
+                } else {
+                    tracing::debug!("Node {} forwarding permission denial to {}", self.id, addr);
+                    self.send_cmh_msg(CmhMessageType::DenyPermission(addr), from);
+                    Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
                 }
             },
             CmhMessageType::ProbeRequest(probe) => {
@@ -170,7 +213,8 @@ impl Node {
                     self.handle_probe(probe).await
                 } else {
                     tracing::debug!("Node {} forwarding probe to {}: {:?}", self.id, probe.i, probe);
-                    Ok(self.send_cmh_msg(CmhMessageType::ProbeRequest(probe), from).await)
+                    self.send_cmh_msg(CmhMessageType::ProbeRequest(probe), from);
+                    Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
                 }
             },
             CmhMessageType::ProbeAnswer(k, m, i, j) => {
@@ -179,10 +223,29 @@ impl Node {
                     self.handle_probe_answer(k, m, i, j).await
                 } else {
                     tracing::debug!("Node {} forwarding probe to {}", self.id, i);
-                    Ok(self.send_cmh_msg(CmhMessageType::ProbeAnswer(k, m, i, j), from).await)
+                    self.send_cmh_msg(CmhMessageType::ProbeAnswer(k, m, i, j), from);
+                    Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
+                }
+            },
+            CmhMessageType::OrQuery(query) => {
+                if query.i == self.addr {
+                    self.handle_or_query(query).await
+                } else {
+                    tracing::debug!("Node {} forwarding OR query to {}: {:?}", self.id, query.i, query);
+                    self.send_cmh_msg(CmhMessageType::OrQuery(query), from);
+                    Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
+                }
+            },
+            CmhMessageType::OrReply(reply) => {
+                if reply.i == self.addr {
+                    self.handle_or_reply(reply).await
+                } else {
+                    tracing::debug!("Node {} forwarding OR reply to {}: {:?}", self.id, reply.i, reply);
+                    self.send_cmh_msg(CmhMessageType::OrReply(reply), from);
+                    Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
                 }
             },
-            CmhMessageType::DetectionStart => self.start_detection().await,
+            CmhMessageType::DetectionStart => self.start_detection(DetectionMode::And, false).await,
             _ => Ok(CmhMessageType::Error(*self.lamport_time.read().unwrap()))
         }
     }
@@ -193,9 +256,10 @@ impl Node {
             tracing::debug!("Node {} is active - ignoring probe from {}", self.id, probe.k);
             return Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()));
         }
-        tracing::info!("T: {}. Handling probe from {} for node {}", self.lamport_time.read().unwrap(), probe.j, probe.k);
+        tracing::info!("T: {}. Handling probe from {} for node {}", self.log_time(), probe.j, probe.k);
         tracing::debug!("Probe: {:?}", probe);
 
+        let run = (probe.k, probe.m);
         let test_num = {
             let last = self.last_test.read().unwrap();
             *last.get(&probe.k).unwrap_or(&0)
@@ -203,45 +267,25 @@ impl Node {
 
         if probe.m > test_num {
             tracing::debug!("Node {} received probe from {} with test number {} > {}", self.id, probe.j, probe.m, test_num);
-            {
-                let mut last = self.last_test.write().unwrap();
-                let mut wait = self.wait_status.write().unwrap();
-                let mut parent = self.parent_nodes.write().unwrap();
-
-                last.insert(probe.k, probe.m);
-                wait.insert(probe.k, true);
-                parent.insert(probe.k, probe.j);
-            }
-
-            let mut count = 0;
             let waiting_for = self.waiting_messages_from.read().unwrap().clone();
-            let mut sent_to = HashSet::new();
+            self.engage_run(run, Some(probe.j), waiting_for.len() as u32);
 
             for owner in waiting_for {
-                if sent_to.insert(owner) {
-                    let msg = CmhMessageType::ProbeRequest(ProbeMessage {
-                        k: probe.k,
-                        m: probe.m,
-                        j: self.addr,
-                        i: owner
-                    });
-                    tracing::debug!("-+- Node {} sending probe to {}: {:?}", self.id, owner, msg);
-                    count += 1;
-                    self.probe_count.write().unwrap().insert(probe.k, count);
-                    self.send_cmh_msg(msg, self.addr).await;
-                }
+                let msg = CmhMessageType::ProbeRequest(ProbeMessage {
+                    k: probe.k,
+                    m: probe.m,
+                    j: self.addr,
+                    i: owner
+                });
+                tracing::debug!("-+- Node {} sending probe to {}: {:?}", self.id, owner, msg);
+                self.send_cmh_msg(msg, self.addr);
             }
-
-            self.probe_count.write().unwrap().insert(probe.k, count);
-            tracing::debug!("--- {} {}", probe.k, self.probe_count.read().unwrap().get(&probe.k).unwrap());
-
-        } else if *self.wait_status.read().unwrap().get(&probe.k).unwrap_or(&false) &&
-            test_num == probe.m {
+        } else if self.is_waiting(run) {
             tracing::debug!("~~ Node {} sending probe answer to {}: {} {} {} {}", self.id, probe.j, probe.k, probe.m, probe.i, probe.j);
             self.send_cmh_msg(
                 CmhMessageType::ProbeAnswer(probe.k, probe.m, probe.i, probe.j),
                 self.addr
-            ).await;
+            );
         }
 
         Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
@@ -257,58 +301,31 @@ impl Node {
 
         tracing::debug!("~~ Node {} received probe answer {} {} {} {}", self.id, k, m, r, i);
 
-        // Check conditions without holding locks during await
-        let should_process = {
-            let last_test = self.last_test.read().unwrap();
-            let wait_status = self.wait_status.read().unwrap();
-            m == *last_test.get(&k).unwrap_or(&0) &&
-                *wait_status.get(&k).unwrap_or(&false)
-        };
-
-        if !should_process {
+        let run = (k, m);
+        if !self.is_waiting(run) {
+            tracing::debug!("Node {} dropping answer for finished or stale run {:?}", self.id, run);
             return Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()));
         }
 
-        // Update probe count and check if it's zero
-        let (is_zero, is_initiator, parent) = {
-            // Decrement counter
-            let mut count = self.probe_count.write().unwrap();
-            tracing::debug!("~`~ Node {} probe count for {} is {}", self.id, k, count.get(&k).unwrap());
-            if let Some(n) = count.get_mut(&k) {
-                *n -= 1;
-                tracing::debug!("Node {} decremented probe count for {} to {}", self.id, k, n);
-
-                // If zero, get additional info we need
-                if *n == 0 {
-                    let is_init = k == self.addr && i == self.addr;
-                    let parent_addr = self.parent_nodes.read().unwrap().get(&k).cloned();
-                    (true, is_init, parent_addr)
-                } else {
-                    (false, false, None)
+        match self.answer_received(run) {
+            Some(0) => {
+                tracing::debug!("Node {} probe count for {:?} is zero", self.id, run);
+                let parent = self.parent_nodes.read().unwrap().get(&run).cloned();
+                self.finish_run(run);
+
+                if k == self.addr {
+                    tracing::info!("T: {}. DEADLOCK DETECTED at node {}", self.log_time(), self.id);
+                    self.record_deadlock(m);
+                } else if let Some(parent_addr) = parent {
+                    tracing::debug!("Node {} sending probe answer to {}: {} {} {} {}", self.id, parent_addr, k, m, i, parent_addr);
+                    self.send_cmh_msg(
+                        CmhMessageType::ProbeAnswer(k, m, i, parent_addr),
+                        self.addr
+                    );
                 }
-            } else {
-                (false, false, None)
-            }
-        };
-
-        // Handle zero count case without holding locks
-        if is_zero {
-            tracing::debug!("Node {} probe count for {} is zero", self.id, k);
-            if is_initiator {
-                tracing::info!("T: {}. DEADLOCK DETECTED at node {}", self.lamport_time.read().unwrap(), self.id);
-            } else if let Some(parent_addr) = parent {
-                tracing::debug!("Node {} sending probe answer to {}: {} {} {} {}", self.id, parent_addr, k, m, i, parent_addr);
-                self.send_cmh_msg(
-                    CmhMessageType::ProbeAnswer(k, m, i, parent_addr),
-                    self.addr
-                ).await;
             }
-
-            // Set wait status after async operations
-            let mut wait_status = self.wait_status.write().unwrap();
-            wait_status.insert(k, false);
-        } else {
-            tracing::debug!("Node {} probe count for {} is not zero!!!!!", self.id, k);
+            Some(n) => tracing::debug!("Node {} still waiting for {} answers in run {:?}", self.id, n, run),
+            None => tracing::debug!("Node {} has no probe count for run {:?}", self.id, run),
         }
 
         Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
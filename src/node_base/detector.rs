@@ -0,0 +1,192 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use super::cmh_funcs::CmhMessageType;
+use super::node::Node;
+
+// How often the background task checks whether a detection round is due
+const DETECTION_TICK: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectionMode {
+    // Edge chasing probes, a node waits for all of its dependencies
+    #[default]
+    And,
+    // Diffusing computation, a node waits for any of its dependencies
+    Or,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoDetectionConfig {
+    pub enabled: bool,
+    pub mode: DetectionMode,
+    // How long a node has to stay passive before it starts probing on its own
+    pub passive_threshold_ms: u64,
+    // Wait between two automatic rounds, doubled after every round up to max
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    // A round that found no deadlock within this time is reported as finished,
+    // the message delay of this node is added once per ring member
+    pub timeout_ms: u64,
+}
+
+impl Default for AutoDetectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            mode: DetectionMode::And,
+            passive_threshold_ms: 5000,
+            initial_backoff_ms: 2000,
+            max_backoff_ms: 60000,
+            timeout_ms: 3000,
+        }
+    }
+}
+
+// Probes only report a deadlock, a round without one just runs out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DetectionStatus {
+    Running,
+    Deadlocked,
+    NoDeadlockAfterTimeout,
+}
+
+// Result of the last detection round started by this node
+#[derive(Debug, Clone, Serialize)]
+pub struct DetectionReport {
+    pub initiator: SocketAddr,
+    pub test_num: u64,
+    pub mode: DetectionMode,
+    pub lamport: u64,
+    pub automatic: bool,
+    pub deadlocked: bool,
+    pub status: DetectionStatus,
+    #[serde(skip)]
+    pub started: Instant,
+    #[serde(skip)]
+    pub timeout: Duration,
+}
+
+impl Node {
+    // Flip the active flag and remember since when the node is passive
+    pub fn mark_active(&self, active: bool) {
+        *self.is_active.write().unwrap() = active;
+        let mut passive_since = self.passive_since.write().unwrap();
+        if active {
+            *passive_since = None;
+            // An active node leaves every detection run it was engaged in
+            self.drop_runs(|_| true);
+        } else if passive_since.is_none() {
+            *passive_since = Some(Instant::now());
+        }
+        drop(passive_since);
+
+        // A termination token waits for us to become passive
+        if !active {
+            self.release_termination_token();
+        }
+    }
+
+    pub fn set_auto_detection(&self, config: AutoDetectionConfig) {
+        tracing::debug!("Node {} auto detection config: {:?}", self.id, config);
+        *self.auto_detection.write().unwrap() = config;
+    }
+
+    pub async fn start_detection(&self, mode: DetectionMode, automatic: bool) -> Result<CmhMessageType, Box<dyn Error>> {
+        match mode {
+            DetectionMode::And => self.start_and_detection(automatic).await,
+            DetectionMode::Or => self.start_or_detection(automatic).await,
+        }
+    }
+
+    pub fn record_detection_start(&self, test_num: u64, mode: DetectionMode, automatic: bool) {
+        self.local_event(format!("{:?} detection {} started", mode, test_num));
+        // Probes may wait at every hop around the ring
+        let hops = self.members.read().unwrap().len() as u32;
+        let timeout = Duration::from_millis(self.auto_detection.read().unwrap().timeout_ms)
+            + *self.message_delay.read().unwrap() * hops;
+        *self.last_detection.write().unwrap() = Some(DetectionReport {
+            initiator: self.addr,
+            test_num,
+            mode,
+            lamport: *self.lamport_time.read().unwrap(),
+            automatic,
+            deadlocked: false,
+            status: DetectionStatus::Running,
+            started: Instant::now(),
+            timeout,
+        });
+    }
+
+    // The last report, a round still running after the timeout found no deadlock
+    pub fn detection_report(&self) -> Option<DetectionReport> {
+        let mut last = self.last_detection.write().unwrap();
+        let report = last.as_mut()?;
+        if report.status == DetectionStatus::Running && report.started.elapsed() >= report.timeout {
+            report.status = DetectionStatus::NoDeadlockAfterTimeout;
+        }
+        Some(report.clone())
+    }
+
+    // A round already reported as finished keeps its result
+    pub fn record_deadlock(&self, test_num: u64) {
+        if let Some(report) = self.last_detection.write().unwrap().as_mut() {
+            if report.test_num == test_num && report.status == DetectionStatus::Running && report.started.elapsed() < report.timeout {
+                self.local_event(format!("deadlock detected in test {}", test_num));
+                report.deadlocked = true;
+                report.status = DetectionStatus::Deadlocked;
+                report.lamport = *self.lamport_time.read().unwrap();
+            }
+        }
+    }
+}
+
+// Background task that starts a CMH round once the node has been passive for too long
+pub async fn run_auto_detection(node: Arc<Node>) {
+    let mut backoff: Option<Duration> = None;
+    let mut next_attempt = Instant::now();
+    let mut seen_passive_since: Option<Instant> = None;
+
+    loop {
+        tokio::time::sleep(DETECTION_TICK).await;
+        node.wait_until_up().await;
+
+        let config = node.auto_detection.read().unwrap().clone();
+        let passive_since = *node.passive_since.read().unwrap();
+
+        // New passive period (or active again) resets the backoff
+        if passive_since != seen_passive_since {
+            seen_passive_since = passive_since;
+            backoff = None;
+            if let Some(since) = passive_since {
+                next_attempt = since + Duration::from_millis(config.passive_threshold_ms);
+            }
+        }
+
+        if !config.enabled || passive_since.is_none() || Instant::now() < next_attempt {
+            continue;
+        }
+
+        tracing::info!("Node {} passive for too long, starting automatic detection", node.id.bold().yellow());
+        if let Err(e) = node.start_detection(config.mode, true).await {
+            tracing::error!("Automatic detection on node {} failed: {}", node.id.bold().red(), e);
+        }
+
+        let wait = match backoff {
+            None => Duration::from_millis(config.initial_backoff_ms),
+            Some(prev) => std::cmp::min(prev * 2, Duration::from_millis(config.max_backoff_ms)),
+        };
+        backoff = Some(wait);
+        next_attempt = Instant::now() + wait;
+
+        if let Some(report) = node.last_detection.read().unwrap().as_ref() {
+            tracing::info!("Node {} automatic detection result: {:?}, next round in {}ms", node.id, report, wait.as_millis());
+        }
+    }
+}
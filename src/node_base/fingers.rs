@@ -0,0 +1,163 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use colored::Colorize;
+use super::cmh_funcs::CmhMessageType;
+use super::messaging::RingMessage;
+use super::node::Node;
+use super::resources::ResourceMessageType;
+use super::total_order::TotalOrderMessage;
+
+// How often the ring pointers are checked and the finger table is rebuilt
+const STABILIZE_INTERVAL: Duration = Duration::from_secs(2);
+
+impl Node {
+    // Node that should get a message next: the farthest finger that does not
+    // pass the destination, or next for undirected messages
+    pub fn next_hop(&self, msg: &RingMessage) -> SocketAddr {
+        let next = self.neighbor_info.read().unwrap().next;
+        let Some(destination) = self.destination(msg) else {
+            return next;
+        };
+        let members = self.members.read().unwrap();
+        let hops = |addr: &SocketAddr| members.iter().position(|m| m == addr);
+        let Some(target_hops) = hops(&destination) else {
+            return next;
+        };
+        self.fingers.read().unwrap().iter()
+            .filter_map(|finger| hops(finger).map(|h| (h, *finger)))
+            .filter(|(h, _)| *h > 0 && *h <= target_hops)
+            .max_by_key(|(h, _)| *h)
+            .map(|(_, finger)| finger)
+            .unwrap_or(next)
+    }
+
+    // Wrap a directed message to go over prev when that takes fewer hops than
+    // going clockwise, where fingers cover a distance in one hop per set bit
+    pub fn choose_direction(&self, msg: RingMessage) -> RingMessage {
+        if matches!(msg, RingMessage::Counterclockwise(..)) {
+            return msg;
+        }
+        let Some(destination) = self.destination(&msg) else {
+            return msg;
+        };
+        let members = self.members.read().unwrap();
+        let Some(clockwise) = members.iter().position(|m| *m == destination).filter(|h| *h > 0) else {
+            return msg;
+        };
+        let counterclockwise = members.len() - clockwise;
+        let clockwise_hops = if self.fingers.read().unwrap().len() > 1 {
+            clockwise.count_ones() as usize
+        } else {
+            clockwise
+        };
+        if counterclockwise < clockwise_hops {
+            tracing::debug!("Node {} sending to {} counter-clockwise ({} hops)", self.id, destination, counterclockwise);
+            return RingMessage::Counterclockwise(destination, Box::new(msg));
+        }
+        msg
+    }
+
+    // Next is unreachable: send a directed message the other way around,
+    // unless it was meant for the dead node itself
+    pub async fn detour(&self, dead_next: SocketAddr, msg: &RingMessage) -> bool {
+        let Some(destination) = self.destination(msg) else {
+            return false;
+        };
+        let prev = self.neighbor_info.read().unwrap().prev;
+        if destination == dead_next || destination == self.addr || prev == dead_next || prev == self.addr {
+            return false;
+        }
+        tracing::info!("Node {} next {} is down, detouring message for {} over prev {}",
+            self.id.bold().yellow(), dead_next, destination, prev);
+        self.try_deliver(prev, &RingMessage::Counterclockwise(destination, Box::new(msg.clone()))).await
+    }
+
+    // A counter-clockwise message is handled where it is meant for, every other
+    // node passes it on to its prev
+    pub fn handle_counterclockwise(&self, destination: SocketAddr, msg: RingMessage) {
+        if destination == self.addr {
+            self.receive(self.addr, msg);
+        } else {
+            self.post(RingMessage::Counterclockwise(destination, Box::new(msg)));
+        }
+    }
+
+    // Address a message is meant for, if it is meant for a single node
+    fn destination(&self, msg: &RingMessage) -> Option<SocketAddr> {
+        match msg {
+            RingMessage::Cmh(m, _) => match m {
+                CmhMessageType::ProbeRequest(probe) => Some(probe.i),
+                CmhMessageType::ProbeAnswer(_, _, _, j) => Some(*j),
+                CmhMessageType::OrQuery(query) | CmhMessageType::OrReply(query) => Some(query.i),
+                CmhMessageType::RequestPermission(addr)
+                | CmhMessageType::GrantPermission(addr)
+                | CmhMessageType::DenyPermission(addr) => Some(*addr),
+                _ => None,
+            },
+            RingMessage::Resource(m, from, _) => match m {
+                ResourceMessageType::Reply(_, to, _) => Some(*to),
+                ResourceMessageType::Granted(_, user) => Some(*user),
+                ResourceMessageType::Acquire(resource)
+                | ResourceMessageType::Release(resource)
+                | ResourceMessageType::ResourceQuery(resource) => self.known_owner(resource, *from),
+                _ => None,
+            },
+            RingMessage::TotalOrder(TotalOrderMessage::Resend(to, _))
+            | RingMessage::TotalOrder(TotalOrderMessage::SyncReply(to, _, _)) => Some(*to),
+            RingMessage::Members(round) if round.done => Some(round.origin),
+            _ => None,
+        }
+    }
+
+    // Only the origin of a request jumps to an owner it learned, every other
+    // node walks on clockwise. A stale entry then skips nodes once, the
+    // request comes back unanswered and the origin walks the whole ring.
+    fn known_owner(&self, resource: &str, origin: SocketAddr) -> Option<SocketAddr> {
+        self.hashed_owner(resource).or_else(|| {
+            (origin == self.addr).then(|| self.known_owners.read().unwrap().get(resource).copied()).flatten()
+        })
+    }
+
+    // A finger that could not be reached is left out until the next rebuild
+    pub fn drop_finger(&self, finger: SocketAddr) {
+        tracing::info!("Node {} dropping dead finger {}", self.id.bold().yellow(), finger);
+        self.fingers.write().unwrap().retain(|f| *f != finger);
+    }
+
+    pub fn finger(&self, index: usize) -> SocketAddr {
+        self.fingers.read().unwrap().get(index).copied().unwrap_or(self.neighbor_info.read().unwrap().next)
+    }
+
+    // Finger i is 2^i hops ahead: our finger i-1 knows it as its own finger i-1
+    pub async fn stabilize_fingers(&self) {
+        let next = self.neighbor_info.read().unwrap().next;
+        let ring_size = self.members.read().unwrap().len();
+        let mut fingers = vec![next];
+        let mut hops = 1;
+        while next != self.addr && hops * 2 < ring_size {
+            let previous = fingers[fingers.len() - 1];
+            let (client, ctx) = self.rpc.get_c(previous).await;
+            let Ok(client) = client else {
+                break;
+            };
+            match client.get_finger(ctx, fingers.len() - 1, self.stamp_send()).await {
+                Ok(finger) if finger != self.addr && !fingers.contains(&finger) => fingers.push(finger),
+                _ => break,
+            }
+            hops *= 2;
+        }
+        tracing::trace!("Node {} fingers: {:?}", self.id, fingers);
+        *self.fingers.write().unwrap() = fingers;
+    }
+}
+
+// Fixes the ring pointers first, then rebuilds the fingers on top of them
+pub async fn run_stabilization(node: Arc<Node>) {
+    loop {
+        tokio::time::sleep(STABILIZE_INTERVAL).await;
+        node.wait_until_up().await;
+        node.stabilize_ring().await;
+        node.stabilize_fingers().await;
+    }
+}
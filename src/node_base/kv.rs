@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use super::node::Node;
+
+// Stored value, deletes are kept as tombstones so older copies cannot bring a key back
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KvEntry {
+    pub key: String,
+    pub value: Option<String>,
+    pub version: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum KvOp {
+    Get(String),
+    Put(String, String),
+    Delete(String),
+}
+
+// 64-bit FNV-1a, positions node addresses and keys on the hash ring
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// FNV-1a with a final mix, so similar names like node addresses spread over the ring
+pub fn ring_position(bytes: &[u8]) -> u64 {
+    let mut hash = fnv1a(bytes);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+// First node at or after the key's position, wrapping around
+pub fn responsible_node(key: &str, members: &[SocketAddr]) -> Option<SocketAddr> {
+    let key_hash = ring_position(key.as_bytes());
+    let position = |addr: &SocketAddr| ring_position(addr.to_string().as_bytes());
+    members.iter()
+        .filter(|addr| position(addr) >= key_hash)
+        .min_by_key(|addr| position(addr))
+        .or_else(|| members.iter().min_by_key(|addr| position(addr)))
+        .copied()
+}
+
+// Primary and the two nodes after it on the ring, `members` is in ring order
+fn replica_set(primary: SocketAddr, members: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(index) = members.iter().position(|m| *m == primary) else {
+        return vec![primary];
+    };
+    let mut replicas = Vec::new();
+    for i in 0..3.min(members.len()) {
+        replicas.push(members[(index + i) % members.len()]);
+    }
+    replicas
+}
+
+impl Node {
+    // Entry point of the API, sends the operation to the node responsible for
+    // the key. The cached member list names it; only if nobody answers from
+    // there the ring is walked for a fresh list.
+    pub async fn kv_route(&self, op: KvOp) -> Result<Option<KvEntry>, Box<dyn Error>> {
+        let key = match &op {
+            KvOp::Get(key) | KvOp::Put(key, _) | KvOp::Delete(key) => key.clone(),
+        };
+        let cached = self.members.read().unwrap().clone();
+        if let Some(reply) = self.kv_send(&key, &op, &cached).await {
+            return Ok(reply);
+        }
+        self.refresh_members().await;
+        let members = self.members.read().unwrap().clone();
+        if members != cached {
+            if let Some(reply) = self.kv_send(&key, &op, &members).await {
+                return Ok(reply);
+            }
+        }
+        Err(format!("No replica of {} reachable", key).into())
+    }
+
+    // None if no candidate for the key answered
+    async fn kv_send(&self, key: &str, op: &KvOp, members: &[SocketAddr]) -> Option<Option<KvEntry>> {
+        let primary = responsible_node(key, members)?;
+
+        // Reads can fall back to the replicas if the primary is gone
+        let candidates = match op {
+            KvOp::Get(_) => replica_set(primary, members),
+            _ => vec![primary],
+        };
+        for node in candidates {
+            if node == self.addr {
+                return Some(self.kv_apply(op.clone()).await);
+            }
+            let (client, ctx) = self.rpc.get_c(node).await;
+            let Ok(client) = client else {
+                tracing::error!("Node {} cannot reach {} for key {}", self.id.bold().red(), node, key);
+                continue;
+            };
+            match client.kv_request(ctx, op.clone(), self.stamp_send()).await {
+                Ok(reply) => return Some(reply),
+                Err(e) => tracing::error!("Node {} kv request to {} failed: {}", self.id.bold().red(), node, e),
+            }
+        }
+        None
+    }
+
+    // Runs on the primary, writes are copied to its replicas before answering
+    pub async fn kv_apply(&self, op: KvOp) -> Option<KvEntry> {
+        let (key, value) = match op {
+            KvOp::Get(key) => return self.kv_store.read().unwrap().get(&key).filter(|e| e.value.is_some()).cloned(),
+            KvOp::Put(key, value) => (key, Some(value)),
+            KvOp::Delete(key) => (key, None),
+        };
+        let entry = {
+            let mut store = self.kv_store.write().unwrap();
+            let version = store.get(&key).map(|e| e.version).unwrap_or(0) + 1;
+            let entry = KvEntry { key: key.clone(), value, version };
+            store.insert(key, entry.clone());
+            entry
+        };
+        tracing::info!("T: {}. Node {} stored {:?}", self.log_time(), self.id.bold().green(), entry);
+        self.local_event(format!("kv {} version {}", entry.key, entry.version));
+        self.kv_replicate(vec![entry.clone()]).await;
+        Some(entry)
+    }
+
+    // Same replica set that reads and the rebalance use, from the cached members
+    async fn kv_replicate(&self, entries: Vec<KvEntry>) {
+        let replicas = replica_set(self.addr, &self.members.read().unwrap());
+        for replica in replicas.into_iter().filter(|r| *r != self.addr) {
+            let (client, ctx) = self.rpc.get_c(replica).await;
+            let stored = match client {
+                Ok(client) => client.kv_store(ctx, entries.clone(), false, self.stamp_send()).await.is_ok(),
+                Err(_) => false,
+            };
+            if !stored {
+                tracing::error!("Node {} could not replicate {} entries to {}", self.id.bold().red(), entries.len(), replica);
+            }
+        }
+    }
+
+    // Keep the newer copies, a primary passes what it accepted on to its replicas.
+    // Equal versions written on both sides of a partition are settled by value.
+    pub async fn kv_merge(&self, entries: Vec<KvEntry>, primary: bool) {
+        let mut accepted = Vec::new();
+        {
+            let mut store = self.kv_store.write().unwrap();
+            for entry in entries {
+                if store.get(&entry.key).is_none_or(|e| (e.version, &e.value) < (entry.version, &entry.value)) {
+                    store.insert(entry.key.clone(), entry.clone());
+                    accepted.push(entry);
+                }
+            }
+        }
+        tracing::debug!("Node {} accepted {} kv entries", self.id, accepted.len());
+        if primary && !accepted.is_empty() {
+            self.kv_replicate(accepted).await;
+        }
+    }
+
+    // After the ring changed every entry goes to its current primary, which
+    // copies it to its replicas. Entries we are no longer a replica of are dropped.
+    pub async fn rebalance_kv(&self) -> Result<(), Box<dyn Error>> {
+        let members = self.members.read().unwrap().clone();
+        let entries: Vec<KvEntry> = self.kv_store.read().unwrap().values().cloned().collect();
+        if entries.is_empty() {
+            return Ok(());
+        }
+        tracing::info!("Node {} rebalancing {} kv entries over {} nodes", self.id.bold().yellow(), entries.len(), members.len());
+
+        let mut by_primary: HashMap<SocketAddr, Vec<KvEntry>> = HashMap::new();
+        for entry in entries {
+            let primary = responsible_node(&entry.key, &members).unwrap_or(self.addr);
+            by_primary.entry(primary).or_default().push(entry);
+        }
+
+        for (primary, entries) in by_primary {
+            let keep = replica_set(primary, &members).contains(&self.addr);
+            if primary == self.addr {
+                self.kv_replicate(entries).await;
+                continue;
+            }
+            let (client, ctx) = self.rpc.get_c(primary).await;
+            let handed_off = match client {
+                Ok(client) => client.kv_store(ctx, entries.clone(), true, self.stamp_send()).await.is_ok(),
+                Err(_) => false,
+            };
+            if handed_off && !keep {
+                let mut store = self.kv_store.write().unwrap();
+                for entry in &entries {
+                    store.remove(&entry.key);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    // Members sorted by their hash position, which is not ring order; the
+    // owner of a key only depends on the set of members
+    fn ring(ports: &[u16]) -> Vec<SocketAddr> {
+        let mut members: Vec<SocketAddr> = ports.iter().map(|p| addr(*p)).collect();
+        members.sort_by_key(|m| ring_position(m.to_string().as_bytes()));
+        members
+    }
+
+    #[test]
+    fn responsible_node_is_first_at_or_after_the_key() {
+        let members = ring(&[2010, 2020, 2030, 2040, 2050]);
+        for key in ["a", "b", "key-1", "key-2", "resource"] {
+            let position = ring_position(key.as_bytes());
+            let owner = responsible_node(key, &members).unwrap();
+            let expected = members.iter()
+                .find(|m| ring_position(m.to_string().as_bytes()) >= position)
+                .unwrap_or(&members[0]);
+            assert_eq!(owner, *expected, "owner of {}", key);
+        }
+    }
+
+    #[test]
+    fn responsible_node_does_not_depend_on_member_order() {
+        let members = ring(&[2010, 2020, 2030, 2040, 2050]);
+        let mut reversed = members.clone();
+        reversed.reverse();
+        for n in 0..20 {
+            let key = format!("key-{}", n);
+            assert_eq!(responsible_node(&key, &members), responsible_node(&key, &reversed));
+        }
+        assert_eq!(responsible_node("key", &[]), None);
+    }
+
+    #[test]
+    fn keys_only_move_to_a_joining_node() {
+        let before = ring(&[2010, 2020, 2030, 2040]);
+        let after = ring(&[2010, 2020, 2030, 2040, 2050]);
+        for n in 0..50 {
+            let key = format!("key-{}", n);
+            let old = responsible_node(&key, &before).unwrap();
+            let new = responsible_node(&key, &after).unwrap();
+            assert!(new == old || new == addr(2050), "{} moved from {} to {}", key, old, new);
+        }
+    }
+
+    #[test]
+    fn replica_set_is_primary_and_the_two_nodes_after_it() {
+        let members: Vec<SocketAddr> = [2010, 2020, 2030, 2040].iter().map(|p| addr(*p)).collect();
+        assert_eq!(replica_set(addr(2020), &members), vec![addr(2020), addr(2030), addr(2040)]);
+        assert_eq!(replica_set(addr(2040), &members), vec![addr(2040), addr(2010), addr(2020)]);
+        assert_eq!(replica_set(addr(2010), &members[..2]), vec![addr(2010), addr(2020)]);
+        assert_eq!(replica_set(addr(2050), &members), vec![addr(2050)]);
+    }
+}
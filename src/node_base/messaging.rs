@@ -0,0 +1,256 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use super::broadcast::CausalMessage;
+use super::cmh_funcs::CmhMessageType;
+use super::node::{MemberRound, Node};
+use super::resources::ResourceMessageType;
+use super::snapshot::SnapshotMarker;
+use super::termination::TerminationMessage;
+use super::total_order::TotalOrderMessage;
+
+// How long a node waits for the reply to a resource request travelling the ring
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// One way message passed hop by hop along the ring
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RingMessage {
+    Cmh(CmhMessageType, SocketAddr),
+    // Resource message, origin and the id of the request it belongs to (if any)
+    Resource(ResourceMessageType, SocketAddr, Option<u64>),
+    Broadcast(CausalMessage),
+    TotalOrder(TotalOrderMessage),
+    Marker(SnapshotMarker),
+    Termination(TerminationMessage),
+    // The ring changed (join or repair), carries the node that noticed
+    RingChanged(SocketAddr),
+    // Member round going around the ring
+    Members(MemberRound),
+    // Directed message travelling counter-clockwise over prev pointers, with its destination
+    Counterclockwise(SocketAddr, Box<RingMessage>),
+}
+
+// Receiving ends of the inbox and outbox, taken once by the workers
+#[derive(Debug)]
+pub struct Mailboxes {
+    pub inbox: mpsc::UnboundedReceiver<(SocketAddr, RingMessage)>,
+    pub outbox: mpsc::UnboundedReceiver<(Instant, RingMessage)>,
+}
+
+impl Node {
+    // Queue a CMH message for next, returns right away
+    pub fn send_cmh_msg(&self, msg: CmhMessageType, from: SocketAddr) {
+        self.post(RingMessage::Cmh(msg, from));
+    }
+
+    // Queue a resource message for next, returns right away
+    pub fn send_resource_msg(&self, msg: ResourceMessageType, from: SocketAddr) {
+        self.post(RingMessage::Resource(msg, from, None));
+    }
+
+    pub fn forward_resource_msg(&self, msg: ResourceMessageType, from: SocketAddr, request_id: Option<u64>) {
+        self.post(RingMessage::Resource(msg, from, request_id));
+    }
+
+    // Send a resource request and wait until its reply has been routed back to us
+    pub async fn request_resource_msg(&self, msg: ResourceMessageType) -> Result<ResourceMessageType, Box<dyn Error>> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        self.pending_requests.lock().unwrap().insert(request_id, tx);
+
+        self.post(RingMessage::Resource(msg, self.addr, Some(request_id)));
+
+        match tokio::time::timeout(REPLY_TIMEOUT, rx).await {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => Err("Request dropped".into()),
+            Err(_) => {
+                self.pending_requests.lock().unwrap().remove(&request_id);
+                tracing::error!("Node {} timed out waiting for reply to request {}", self.id.bold().red(), request_id);
+                Err("Timed out waiting for reply".into())
+            }
+        }
+    }
+
+    // Route the answer to a request back to the node that asked
+    pub fn reply_resource_msg(&self, request_id: Option<u64>, to: SocketAddr, reply: ResourceMessageType) {
+        let Some(request_id) = request_id else {
+            return;
+        };
+        if to == self.addr {
+            self.complete_request(request_id, reply);
+        } else {
+            self.send_resource_msg(ResourceMessageType::Reply(request_id, to, Box::new(reply)), self.addr);
+        }
+    }
+
+    pub fn complete_request(&self, request_id: u64, reply: ResourceMessageType) {
+        match self.pending_requests.lock().unwrap().remove(&request_id) {
+            Some(tx) => { let _ = tx.send(reply); },
+            None => tracing::debug!("Node {} got reply for unknown request {}", self.id, request_id),
+        }
+    }
+
+    // Called by the RPC server with the sender of the message, the message is
+    // handled later by the inbox worker
+    pub fn receive(&self, from: SocketAddr, msg: RingMessage) -> bool {
+        self.inbox.send((from, msg)).is_ok()
+    }
+
+    pub(crate) fn post(&self, msg: RingMessage) {
+        if self.outbox.send((Instant::now(), msg)).is_err() {
+            tracing::error!("Node {} outbox is closed", self.id.bold().red());
+            return;
+        }
+        self.outbox_pending.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Hand a message to the best finger or to prev if the destination is closer
+    // that way, falling back to next. If next is gone a directed message takes the
+    // counter-clockwise detour while the ring is repaired.
+    async fn deliver(self: &Arc<Self>, msg: RingMessage) {
+        // A marker closes one channel, so it goes straight to its node
+        if let RingMessage::Marker(marker) = &msg {
+            if !self.try_deliver(marker.to, &msg).await {
+                tracing::error!("Node {} could not send snapshot {} marker to {}", self.id.bold().red(), marker.id, marker.to);
+            }
+            return;
+        }
+        let mut msg = self.choose_direction(msg);
+        if let RingMessage::Counterclockwise(..) = msg {
+            let prev = self.neighbor_info.read().unwrap().prev;
+            if prev != self.addr && self.try_deliver(prev, &msg).await {
+                return;
+            }
+            tracing::info!("Node {} cannot reach prev {}, sending clockwise", self.id.bold().yellow(), prev);
+            let RingMessage::Counterclockwise(_, inner) = msg else { unreachable!() };
+            msg = *inner;
+        }
+
+        let hop = self.next_hop(&msg);
+        let next = self.neighbor_info.read().unwrap().next;
+        if hop != next {
+            if self.try_deliver(hop, &msg).await {
+                return;
+            }
+            self.drop_finger(hop);
+        }
+        if self.try_deliver(next, &msg).await {
+            return;
+        }
+
+        // The repair runs in its own task so the outbox keeps going, a message
+        // that could not take the detour waits for it and is retried once
+        let detoured = self.detour(next, &msg).await;
+        if !detoured {
+            self.outbox_pending.fetch_add(1, Ordering::SeqCst);
+        }
+        let node = self.clone();
+        tokio::spawn(async move {
+            node.repair_topology(next).await;
+            if detoured {
+                return;
+            }
+            node.repair_finished(next).await;
+            let new_next = node.neighbor_info.read().unwrap().next;
+            if new_next == next || !node.try_deliver(new_next, &msg).await {
+                tracing::error!("Node {} dropping message {:?}", node.id.bold().red(), msg);
+            }
+            node.outbox_pending.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    pub(crate) async fn try_deliver(&self, next: SocketAddr, msg: &RingMessage) -> bool {
+        let (client_result, ctx) = self.rpc.get_c(next).await;
+        let Ok(client) = client_result else {
+            tracing::error!("Error getting client for {}", next.to_string().bold().red());
+            return false;
+        };
+
+        let header = self.stamp_send();
+        self.log_event(format!("send to {}: {:?}", next, msg));
+        let result = match msg {
+            RingMessage::Cmh(m, from) => client.handle_cmh_msg(ctx, m.clone(), *from, header).await,
+            RingMessage::Resource(m, from, request_id) => client.handle_resource_msg(ctx, m.clone(), *from, *request_id, header).await,
+            RingMessage::Broadcast(m) => client.handle_broadcast_msg(ctx, m.clone(), header).await,
+            RingMessage::TotalOrder(m) => client.handle_total_order_msg(ctx, m.clone(), header).await,
+            RingMessage::Marker(m) => client.handle_snapshot_marker(ctx, m.clone(), header).await,
+            RingMessage::Termination(m) => client.handle_termination_msg(ctx, m.clone(), header).await,
+            RingMessage::RingChanged(origin) => client.ring_changed(ctx, *origin, header).await,
+            RingMessage::Members(m) => client.handle_member_round(ctx, m.clone(), header).await,
+            RingMessage::Counterclockwise(to, m) => client.handle_counterclockwise_msg(ctx, *to, (**m).clone(), header).await,
+        };
+        match result {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!("Error sending message to {}: {}", next.to_string().bold().red(), e);
+                false
+            }
+        }
+    }
+}
+
+// Start the inbox and outbox workers of a node
+pub fn start_workers(node: Arc<Node>) {
+    let Some(mailboxes) = node.mailboxes.lock().unwrap().take() else {
+        tracing::error!("Workers of node {} are already running", node.id);
+        return;
+    };
+
+    let inbox_node = node.clone();
+    tokio::spawn(run_inbox(inbox_node, mailboxes.inbox));
+    tokio::spawn(run_outbox(node, mailboxes.outbox));
+}
+
+// Handles received messages one after another
+async fn run_inbox(node: Arc<Node>, mut inbox: mpsc::UnboundedReceiver<(SocketAddr, RingMessage)>) {
+    while let Some((from, msg)) = inbox.recv().await {
+        // A crashed node loses what was queued
+        if node.is_down() {
+            continue;
+        }
+        // A counter-clockwise message is recorded on the channel from next it
+        // came over; at its destination it is handed to us again from ourselves,
+        // which is no channel
+        if !matches!(msg, RingMessage::Marker(_)) {
+            node.record_in_flight(from, &msg);
+        }
+        let result = match msg {
+            RingMessage::Cmh(m, from) => node.handle_cmh_message(m, from).await.map(|_| ()),
+            RingMessage::Resource(m, from, request_id) => node.handle_message(m, from, request_id).await,
+            RingMessage::Broadcast(m) => { node.handle_broadcast(m); Ok(()) },
+            RingMessage::TotalOrder(m) => { node.handle_total_order(m); Ok(()) },
+            RingMessage::Marker(m) => { node.handle_marker(from, m); Ok(()) },
+            RingMessage::Termination(m) => { node.handle_termination(m); Ok(()) },
+            RingMessage::RingChanged(origin) => {
+                // The ring walk and the rebalance RPCs must not hold up the inbox
+                let node = node.clone();
+                tokio::spawn(async move { node.handle_ring_changed(origin).await });
+                Ok(())
+            },
+            RingMessage::Members(m) => { node.handle_member_round(m); Ok(()) },
+            RingMessage::Counterclockwise(to, m) => { node.handle_counterclockwise(to, *m); Ok(()) },
+        };
+        if let Err(e) = result {
+            tracing::error!("Node {} failed handling message: {}", node.id.bold().red(), e);
+        }
+    }
+}
+
+// Sends queued messages to next in order. Each message is held back for the
+// configured delay from the time it was posted, so the delays of queued
+// messages run at the same time instead of adding up.
+async fn run_outbox(node: Arc<Node>, mut outbox: mpsc::UnboundedReceiver<(Instant, RingMessage)>) {
+    while let Some((posted, msg)) = outbox.recv().await {
+        if !node.is_down() {
+            let delay = *node.message_delay.read().unwrap();
+            tokio::time::sleep_until((posted + delay).into()).await;
+            node.deliver(msg).await;
+        }
+        node.outbox_pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -1,3 +1,23 @@
 pub(crate) mod node;
 pub(crate) mod cmh_funcs;
 pub(crate) mod resources;
+pub(crate) mod detector;
+pub(crate) mod or_funcs;
+pub(crate) mod wait_for_graph;
+pub(crate) mod messaging;
+pub(crate) mod clocks;
+pub(crate) mod broadcast;
+pub(crate) mod total_order;
+pub(crate) mod snapshot;
+pub(crate) mod termination;
+pub(crate) mod barrier;
+pub(crate) mod kv;
+pub(crate) mod placement;
+pub(crate) mod fingers;
+pub(crate) mod stabilize;
+pub(crate) mod partition;
+pub(crate) mod swim;
+pub(crate) mod repair;
+pub(crate) mod shutdown;
+pub(crate) mod rejoin;
+pub(crate) mod wal;
\ No newline at end of file
@@ -1,17 +1,35 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use colored::Colorize;
 use serde::{Deserialize, Serialize};
-use tokio::sync::watch;
-use crate::node_base::cmh_funcs::CmhMessageType;
+use tokio::sync::{mpsc, oneshot, watch};
+use crate::node_base::barrier::{BarrierState, BarrierWaiter};
+use crate::node_base::broadcast::{CausalMessage, DeliveredBroadcast};
+use crate::node_base::clocks::{ClockMode, HybridTimestamp, LoggedEvent, VectorClock};
+use crate::node_base::cmh_funcs::RunId;
+use crate::node_base::detector::{AutoDetectionConfig, DetectionReport};
+use crate::node_base::kv::KvEntry;
+use crate::node_base::placement::PlacementMode;
+use crate::node_base::repair::RepairBook;
+use crate::node_base::messaging::{Mailboxes, RingMessage};
 use crate::node_base::resources::ResourceMessageType;
+use crate::node_base::snapshot::{SnapshotCollection, SnapshotRecording};
+use crate::node_base::stabilize::DEFAULT_SUCCESSORS;
+use crate::node_base::swim::SwimState;
+use crate::node_base::termination::TerminationState;
+use crate::node_base::total_order::TotalOrderState;
+use crate::node_base::wal::Wal;
 use crate::rpc_base::rpc_client_manager::RpcClientManager;
 use crate::rpc_base::server;
 
-#[derive(Debug, Clone)]
+// How long a node waits for its member round to come back
+const MEMBERS_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ResourceState {
     pub current_user: Option<SocketAddr>,
     pub request_queue: Vec<SocketAddr>,
@@ -24,10 +42,51 @@ pub struct Node {
     pub message_delay: RwLock<Duration>,
     pub neighbor_info: RwLock<NeighborInfo>,
     pub lamport_time: RwLock<u64>,
+    pub vector_clock: RwLock<VectorClock>,
+    pub hlc: RwLock<HybridTimestamp>,
+    pub clock_mode: RwLock<ClockMode>,
+    pub event_log: RwLock<VecDeque<LoggedEvent>>,
     pub repairing: RwLock<bool>,
+    // Repairs started here, passed through here and recently finished
+    pub repairs: Mutex<RepairBook>,
+    // Held while this node rewires its neighbors for a join, leave or stabilization
+    pub membership_lock: tokio::sync::Mutex<()>,
+    // Held while this node updates its view after a ring change
+    pub ring_change_lock: tokio::sync::Mutex<()>,
+    pub successor_count: RwLock<usize>,
+    // Nodes removed by repair and when, probed to notice a healed partition
+    pub evicted: RwLock<HashMap<SocketAddr, Instant>>,
+    // SWIM member table and the gossip still to be piggybacked
+    pub swim: RwLock<SwimState>,
 
     pub rpc: RpcClientManager,
     pub stop_signal: watch::Sender<()>,
+    // False while killed, background tasks wait for it
+    pub up: watch::Sender<bool>,
+    // True while a revived node rejoins: messages flow again but REST still answers 503
+    pub rejoining: AtomicBool,
+    // Where a killed node rejoins when revived: its prev, then the other
+    // members it knew
+    pub revive_via: RwLock<Vec<SocketAddr>>,
+    // Highest incarnation each revived node reconciled with us
+    pub rejoined: RwLock<HashMap<SocketAddr, u64>>,
+    // Write-ahead log in the data directory, if the node was given one
+    pub wal: Mutex<Option<Wal>>,
+
+    // Ring messages are queued and handled by the inbox/outbox workers
+    // Received messages with the node that sent them over its channel
+    pub inbox: mpsc::UnboundedSender<(SocketAddr, RingMessage)>,
+    // Queued messages with the time they were posted
+    pub outbox: mpsc::UnboundedSender<(Instant, RingMessage)>,
+    pub mailboxes: Mutex<Option<Mailboxes>>,
+    pub pending_requests: Mutex<HashMap<u64, oneshot::Sender<ResourceMessageType>>>,
+    pub next_request_id: AtomicU64,
+    // Member rounds started here, by id
+    pub pending_members: Mutex<HashMap<u64, oneshot::Sender<Vec<SocketAddr>>>>,
+    // Posted ring messages not yet delivered and RPC handlers still running,
+    // shutdown waits for both
+    pub outbox_pending: AtomicUsize,
+    pub rpcs_in_flight: AtomicUsize,
 
     // Used for resource management which is not used
     // And can be ignored
@@ -43,23 +102,95 @@ pub struct Node {
     pub permission_queue: RwLock<HashSet<SocketAddr>>,
 
     pub last_test: RwLock<HashMap<SocketAddr, u64>>,
-    pub wait_status: RwLock<HashMap<SocketAddr, bool>>,
-    pub parent_nodes: RwLock<HashMap<SocketAddr, SocketAddr>>,
-    pub probe_count: RwLock<HashMap<SocketAddr, u32>>,
+    pub wait_status: RwLock<HashMap<RunId, bool>>,
+    pub parent_nodes: RwLock<HashMap<RunId, SocketAddr>>,
+    pub probe_count: RwLock<HashMap<RunId, u32>>,
+
+    // Automatic detection
+    pub passive_since: RwLock<Option<Instant>>,
+    pub auto_detection: RwLock<AutoDetectionConfig>,
+    pub last_detection: RwLock<Option<DetectionReport>>,
+
+    // Causal broadcast, delivered count per origin, held back messages and feed
+    pub bcast_clock: RwLock<VectorClock>,
+    pub bcast_pending: RwLock<Vec<CausalMessage>>,
+    pub bcast_delivered: RwLock<VecDeque<DeliveredBroadcast>>,
+
+    // Total order broadcast, sequenced by a token going around the ring
+    pub total_order: RwLock<TotalOrderState>,
+
+    // Snapshots we recorded our state for, by initiator and id, whose
+    // incoming channels are still being recorded
+    pub snapshots: Mutex<HashMap<(SocketAddr, u64), SnapshotRecording>>,
+    // Snapshots started by this node, collecting the members' reports
+    pub snapshot_reports: Mutex<HashMap<u64, SnapshotCollection>>,
+    pub next_snapshot_id: AtomicU64,
+
+    // Safra's termination detection
+    pub termination: RwLock<TerminationState>,
+
+    // Barriers coordinated here and barriers we are blocked in
+    pub barriers: Mutex<HashMap<String, BarrierState>>,
+    pub barrier_waiters: Mutex<HashMap<String, BarrierWaiter>>,
+    // Coordinator each barrier was pinned to at its first arrival
+    pub barrier_pins: Mutex<HashMap<String, SocketAddr>>,
+
+    // Key-value entries this node is primary or replica for
+    pub kv_store: RwLock<HashMap<String, KvEntry>>,
+
+    // Ring members as of the last ring change, in ring order from this node
+    pub members: RwLock<Vec<SocketAddr>>,
+    pub placement: RwLock<PlacementMode>,
+
+    // Nodes 1, 2, 4, ... hops ahead, and resource owners learned from queries
+    pub fingers: RwLock<Vec<SocketAddr>>,
+    pub known_owners: RwLock<HashMap<String, SocketAddr>>,
+}
+
+// Round collecting the ring members in ring order, started by origin
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemberRound {
+    pub origin: SocketAddr,
+    pub id: u64,
+    pub members: Vec<SocketAddr>,
+    // Set once the round came to a node twice, it then goes straight to origin
+    pub done: bool,
+}
+
+// Marks a revived node as rejoining until dropped, on every way out of revive
+struct RejoinGuard<'a>(&'a AtomicBool);
+
+impl<'a> RejoinGuard<'a> {
+    fn new(rejoining: &'a AtomicBool) -> Self {
+        rejoining.store(true, Ordering::SeqCst);
+        Self(rejoining)
+    }
+}
+
+impl Drop for RejoinGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
 }
 
 // Circle topology with a leader
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NeighborInfo {
     pub next: SocketAddr,
     pub nnext: SocketAddr,
     pub prev: SocketAddr,
+    // next, nnext and the nodes after them, up to successor_count entries
+    #[serde(default)]
+    pub successors: Vec<SocketAddr>,
 }
 
 impl Node {
     pub fn new(id: String, addr: SocketAddr) -> Arc<Self> {
         tracing::debug!("Creating new node with id: {}, addr: {}", id, addr);
         let (stop_signal, _) = watch::channel(());
+        let (up, _) = watch::channel(true);
+        let (inbox, inbox_rx) = mpsc::unbounded_channel();
+        let (outbox, outbox_rx) = mpsc::unbounded_channel();
         Arc::new(Self {
             id,
             addr,
@@ -68,11 +199,36 @@ impl Node {
                 next: addr,
                 nnext: addr,
                 prev: addr,
+                successors: Vec::new(),
             }),
             lamport_time: RwLock::new(0),
+            vector_clock: RwLock::new(VectorClock::default()),
+            hlc: RwLock::new(HybridTimestamp::default()),
+            clock_mode: RwLock::new(ClockMode::default()),
+            event_log: RwLock::new(VecDeque::new()),
             repairing: RwLock::new(false),
+            repairs: Mutex::new(RepairBook::default()),
+            membership_lock: tokio::sync::Mutex::new(()),
+            ring_change_lock: tokio::sync::Mutex::new(()),
+            successor_count: RwLock::new(DEFAULT_SUCCESSORS),
+            evicted: RwLock::new(HashMap::new()),
+            swim: RwLock::new(SwimState::new(addr)),
             rpc: RpcClientManager::new(),
             stop_signal,
+            up,
+            rejoining: AtomicBool::new(false),
+            revive_via: RwLock::new(Vec::new()),
+            rejoined: RwLock::new(HashMap::new()),
+            wal: Mutex::new(None),
+
+            inbox,
+            outbox,
+            mailboxes: Mutex::new(Some(Mailboxes { inbox: inbox_rx, outbox: outbox_rx })),
+            pending_requests: Mutex::new(HashMap::new()),
+            next_request_id: AtomicU64::new(0),
+            pending_members: Mutex::new(HashMap::new()),
+            outbox_pending: AtomicUsize::new(0),
+            rpcs_in_flight: AtomicUsize::new(0),
             
             owned_resources: RwLock::new(HashMap::new()),
             waiting_for: RwLock::new(HashMap::new()),
@@ -87,17 +243,53 @@ impl Node {
             wait_status: RwLock::new(HashMap::new()),
             parent_nodes: RwLock::new(HashMap::new()),
             probe_count: RwLock::new(HashMap::new()),
+
+            passive_since: RwLock::new(None),
+            auto_detection: RwLock::new(AutoDetectionConfig::default()),
+            last_detection: RwLock::new(None),
+
+            bcast_clock: RwLock::new(VectorClock::default()),
+            bcast_pending: RwLock::new(Vec::new()),
+            bcast_delivered: RwLock::new(VecDeque::new()),
+
+            total_order: RwLock::new(TotalOrderState::new(addr)),
+
+            snapshots: Mutex::new(HashMap::new()),
+            snapshot_reports: Mutex::new(HashMap::new()),
+            next_snapshot_id: AtomicU64::new(0),
+
+            termination: RwLock::new(TerminationState::default()),
+
+            barriers: Mutex::new(HashMap::new()),
+            barrier_waiters: Mutex::new(HashMap::new()),
+            barrier_pins: Mutex::new(HashMap::new()),
+
+            kv_store: RwLock::new(HashMap::new()),
+
+            members: RwLock::new(vec![addr]),
+            placement: RwLock::new(PlacementMode::default()),
+
+            fingers: RwLock::new(Vec::new()),
+            known_owners: RwLock::new(HashMap::new()),
         })
     }
 
     pub fn increment_lamport(&self) -> u64 {
-        *self.lamport_time.write().unwrap() += 1;
-        *self.lamport_time.read().unwrap()
+        let time = {
+            let mut clock = self.lamport_time.write().unwrap();
+            *clock += 1;
+            *clock
+        };
+        self.log_clock();
+        time
     }
 
     pub fn update_clock(&self, received_time: u64) {
-        let mut clock = self.lamport_time.write().unwrap();
-        *clock = std::cmp::max(*clock, received_time) + 1;
+        {
+            let mut clock = self.lamport_time.write().unwrap();
+            *clock = std::cmp::max(*clock, received_time) + 1;
+        }
+        self.log_clock();
     }
 
     pub fn set_delay(&self, delay_ms: u64) {
@@ -109,9 +301,13 @@ impl Node {
     pub fn print_status(&self) {
         tracing::info!("{} Node {}", "STATUS".on_yellow().bold().bright_red(), self.id.bold().green());
         tracing::info!("Node id: {}, addr: {}", self.id.bold().green(), self.addr.to_string().bold().green());
+        tracing::info!("Lamport: {}, HLC: {}, Vector clock: {}", *self.lamport_time.read().unwrap(), *self.hlc.read().unwrap(), *self.vector_clock.read().unwrap());
         // now print the neighbor info
         let neighbor_info = self.neighbor_info.read().unwrap();
         tracing::info!("Next: {}, NNext: {}, Prev: {}", neighbor_info.next.to_string().green(), neighbor_info.nnext.to_string().green(), neighbor_info.prev.to_string().green());
+        tracing::info!("Successors: {:?}", neighbor_info.successors);
+        tracing::info!("Fingers: {:?}", *self.fingers.read().unwrap());
+        tracing::info!("Live members (incarnation {}): {:?}", self.swim.read().unwrap().incarnation(), self.live_members());
         // show resources
         let owned = self.owned_resources.read().unwrap();
         tracing::info!("Owned resources: {:?}", owned.keys());
@@ -129,114 +325,122 @@ impl Node {
         // show deadlock stuff
         tracing::info!("Active: {}, Waiting messages from: {:?}", *self.is_active.read().unwrap(), *self.waiting_messages_from.read().unwrap());
         tracing::info!("Permission queue: {:?}", *self.permission_queue.read().unwrap());
+        tracing::info!("Detection runs (initiator, test) -> pending answers: {:?}", *self.probe_count.read().unwrap());
+        tracing::info!("Termination: {:?}", *self.termination.read().unwrap());
+        tracing::info!("KV entries: {:?}", self.kv_store.read().unwrap().values());
+        tracing::info!("Broadcasts delivered: {}, held back: {}", *self.bcast_clock.read().unwrap(), self.bcast_pending.read().unwrap().len());
+        let tob = self.total_order.read().unwrap();
+        tracing::info!("Total order: delivered up to {}, buffered {:?}, epoch {}, holding token: {}", tob.delivered_upto, tob.buffer.keys(), tob.epoch, tob.token.is_some());
     }
 
-    pub async fn try_join_other(&self, other_addr: SocketAddr) {
+    pub async fn try_join_other(&self, other_addr: SocketAddr) -> Result<(), Box<dyn Error>> {
         if self.addr == other_addr {
             tracing::info!("Node {} cannot join itself", self.id.bold().red());
-            return;
+            return Err("Cannot join itself".into());
         }
+        // The other node takes its own membership lock for the join, holding
+        // ours meanwhile could deadlock with a join going the other way
         let (n_client, ctx) = self.rpc.get_c(other_addr).await;
-        let neighbor_new_info = match n_client.unwrap().other_joining(ctx, self.addr).await {
+        let Ok(n_client) = n_client else {
+            tracing::error!("Node {} cannot reach {} to join", self.id.bold().red(), other_addr.to_string().bold().red());
+            return Err(format!("Cannot reach {}", other_addr).into());
+        };
+        let neighbor_new_info = match n_client.other_joining(ctx, self.addr, self.stamp_send()).await {
             Ok(n_info) => {n_info}
-            Err(e) => {tracing::error!("Error during joining node {}: {}", other_addr.to_string().bold().red(), e); return;}
+            Err(e) => {tracing::error!("Error during joining node {}: {}", other_addr.to_string().bold().red(), e); return Err(e.into());}
         };
         tracing::debug!("Node {} received neighbor info: {:?} from {}", self.id.bold().green(), neighbor_new_info, other_addr.to_string().bold().green());
-        *self.neighbor_info.write().unwrap() = neighbor_new_info;
+        {
+            let _membership = self.membership_lock.lock().await;
+            *self.neighbor_info.write().unwrap() = neighbor_new_info;
+            self.log_ring();
+        }
+        self.adopt_placement(other_addr).await;
+        self.swim_rejoin();
+        self.reset_total_order();
+        self.announce_ring_change().await;
+        Ok(())
     }
 
-    pub async fn send_resource_msg(&self, msg: ResourceMessageType, from: SocketAddr) -> ResourceMessageType {
-        let next = {
-            let neighbor_info = self.neighbor_info.read().unwrap();
-            neighbor_info.next
-        };
-        let delay = *self.message_delay.read().unwrap();
-        tokio::time::sleep(delay).await;
-
-        let (client_result, ctx) = self.rpc.get_c(next).await;
-        if let Ok(client) = client_result {
-            self.increment_lamport();
-            match client.handle_resource_msg(ctx, msg, from).await {
-                Ok(response) => {
-                    // tracing::info!("Node {} sent message to {}", self.id.bold().green(), next.to_string().bold().green());
-                    response
-                }
-                Err(e) => {
-                    tracing::error!("Error sending message to {}: {}", next.to_string().bold().red(), e);
-                    self.repair_topology(next).await;
-                    ResourceMessageType::Error
-                }
-            }
-        } else if let Err(_e) = client_result {
-            tracing::error!("Error getting client for {}", next.to_string().bold().red());
-            self.repair_topology(next).await;
-            ResourceMessageType::Error
+    // Addresses of all nodes on the ring in ring order from this node, collected
+    // by a round posted along next that comes back to us
+    pub async fn ring_members(&self) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+        if self.neighbor_info.read().unwrap().next == self.addr {
+            return Ok(vec![self.addr]);
         }
-        else {
-            ResourceMessageType::Error
+        let id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        self.pending_members.lock().unwrap().insert(id, tx);
+
+        self.post(RingMessage::Members(MemberRound { origin: self.addr, id, members: vec![self.addr], done: false }));
+
+        match tokio::time::timeout(MEMBERS_TIMEOUT, rx).await {
+            Ok(Ok(members)) => Ok(members),
+            Ok(Err(_)) => Err("Member round dropped".into()),
+            Err(_) => {
+                self.pending_members.lock().unwrap().remove(&id);
+                Err("Timed out waiting for the member round".into())
+            }
         }
     }
 
-    pub async fn send_cmh_msg(&self, msg: CmhMessageType, from: SocketAddr) -> CmhMessageType {
-        let next = {
-            let neighbor_info = self.neighbor_info.read().unwrap();
-            neighbor_info.next
-        };
-        // tracing::debug!("Node {} sending message to {} with delay {}ms", self.id.bold().green(), next.to_string().bold().green(), self.message_delay.read().unwrap().as_millis());
-        let delay = *self.message_delay.read().unwrap();
-        tokio::time::sleep(delay).await;
-
-        let (client_result, ctx) = self.rpc.get_c(next).await;
-        if let Ok(client) = client_result {
-            let lamport_time = self.increment_lamport();
-            match client.handle_cmh_msg(ctx, msg, from, lamport_time).await {
-                Ok(response) => {
-                    // tracing::info!("Node {} sent message to {}", self.id.bold().green(), next.to_string().bold().green());
-                    if let CmhMessageType::Success(new_lamport) = response {
-                        self.update_clock(new_lamport);
-                    }
-                    response
-                }
-                Err(e) => {
-                    tracing::error!("Error sending message to {}: {}", next.to_string().bold().red(), e);
-                    self.repair_topology(next).await;
-                    CmhMessageType::Error(*self.lamport_time.read().unwrap())
-                }
+    // A round is passed on until it is back at its origin. On a broken ring it
+    // reaches a node it already went through, from there it goes straight back.
+    pub fn handle_member_round(&self, mut round: MemberRound) {
+        if round.origin == self.addr {
+            match self.pending_members.lock().unwrap().remove(&round.id) {
+                Some(tx) => { let _ = tx.send(round.members); },
+                None => tracing::debug!("Node {} got member round {} it no longer waits for", self.id, round.id),
             }
-        } else if let Err(_e) = client_result {
-            tracing::error!("Error getting client for {}", next.to_string().bold().red());
-            self.repair_topology(next).await;
-            CmhMessageType::Error(*self.lamport_time.read().unwrap())
+            return;
         }
-        else {
-            CmhMessageType::Error(*self.lamport_time.read().unwrap())
+        if round.members.contains(&self.addr) {
+            round.done = true;
+        } else {
+            round.members.push(self.addr);
         }
+        self.post(RingMessage::Members(round));
     }
 
-    pub async fn repair_topology(&self, missing_node: SocketAddr) {
-        tracing::info!("Node {} repairing topology with missing node: {}", self.id.bold().green(), missing_node.to_string().bold().red());
-        if *self.repairing.read().unwrap() {
-            return;
-        }
-        let (client, ctx) = self.rpc.get_c(self.addr).await;
-        match client.unwrap().missing_node(ctx, self.addr, missing_node).await {
-            Ok(_) => {
-                tracing::info!("Node {} repaired topology with missing node: {}", self.id.bold().green(), missing_node.to_string().bold().red());
-            }
-            Err(e) => {
-                tracing::error!("Error during repairing topology: {}", e);
-            }
+    pub async fn refresh_members(&self) {
+        match self.ring_members().await {
+            Ok(members) => *self.members.write().unwrap() = members,
+            Err(e) => tracing::error!("Node {} could not refresh ring members: {}", self.id.bold().red(), e),
         }
+    }
 
-        *self.repairing.write().unwrap() = false;
+    // Every node updates its view of the ring after a join, leave or repair,
+    // the round ends at the node whose next is the origin
+    pub async fn announce_ring_change(&self) {
+        self.handle_ring_changed(self.addr).await;
     }
-    
+
+    pub async fn handle_ring_changed(&self, origin: SocketAddr) {
+        // Resources may have moved with the ring, owners are looked up again
+        self.known_owners.write().unwrap().clear();
+        let next = self.neighbor_info.read().unwrap().next;
+        if next != origin && next != self.addr {
+            self.post(RingMessage::RingChanged(origin));
+        }
+        // Rounds close to each other update the view one after another
+        let _ring_change = self.ring_change_lock.lock().await;
+        self.refresh_members().await;
+        self.announce_barrier_pins().await;
+        self.rebalance_resources().await;
+        if let Err(e) = self.rebalance_kv().await {
+            tracing::error!("Node {} kv rebalance failed: {}", self.id.bold().red(), e);
+        }
+    }
+
     pub async fn delete_dependencies(&self, missing_node: SocketAddr) {
         self.waiting_messages_from.write().unwrap().remove(&missing_node);
         if self.waiting_messages_from.read().unwrap().is_empty() {
-            *self.is_active.write().unwrap() = true;
+            self.mark_active(true);
         }
         self.permission_queue.write().unwrap().remove(&missing_node);
+        self.known_owners.write().unwrap().retain(|_, owner| *owner != missing_node);
+        self.barrier_participant_lost(missing_node).await;
+        self.remember_evicted(missing_node);
         self.rpc.delete_client(missing_node).await;
     }
 
@@ -245,61 +449,163 @@ impl Node {
         tracing::info!("Node {} leaving", self.id.bold().red());
         self.waiting_messages_from.write().unwrap().clear();
         self.permission_queue.write().unwrap().clear();
-        *self.is_active.write().unwrap() = true;
+        self.mark_active(true);
+        self.hand_off_token().await;
+        self.hand_off_all_resources().await;
 
+        let old_next = self.neighbor_info.read().unwrap().next;
+        self.swim_leave();
         let (client, ctx) = self.rpc.get_c(self.addr).await;
-        match client.unwrap().leave_topology(ctx).await {
+        match client.unwrap().leave_topology(ctx, self.stamp_send()).await {
             Ok(_) => {
                 // clean own neighbor info
-                let mut neighbor_info = self.neighbor_info.write().unwrap();
-                neighbor_info.next = self.addr;
-                neighbor_info.nnext = self.addr;
-                neighbor_info.prev = self.addr;
+                {
+                    let mut neighbor_info = self.neighbor_info.write().unwrap();
+                    neighbor_info.next = self.addr;
+                    neighbor_info.nnext = self.addr;
+                    neighbor_info.successors.clear();
+                    neighbor_info.prev = self.addr;
+                }
+                *self.members.write().unwrap() = vec![self.addr];
+                // let the remaining ring update its view, starting at our old next
+                if old_next != self.addr {
+                    self.try_deliver(old_next, &RingMessage::RingChanged(old_next)).await;
+                }
                 Ok(())
             }
             Err(e) => {tracing::error!("Error during leaving {}: {}", self.id.bold().red(), e); Err(e.into())}
         }
     }
 
-    // kill - mark node as inactive/dead
-    pub async fn kill(&self, node_addr: SocketAddr) -> Result<(), Box<dyn Error>> {
-        if node_addr == self.addr {
-            // send stop signal to the RPC server
-            // TODO: KILL CLIENT AS WELL
-            let _ = self.stop_signal.send(());
-            Ok(())
-        } else {
-            Err("Cannot kill other nodes directly".into())
+    // kill - simulated crash: the RPC server stops, nothing goes out, REST
+    // answers 503 and background tasks pause until revive
+    pub async fn kill(&self, node_addr: SocketAddr, wipe_state: bool) -> Result<(), Box<dyn Error>> {
+        if node_addr != self.addr {
+            return Err("Cannot kill other nodes directly".into());
+        }
+        if self.is_down() {
+            return Err("Node is already down".into());
         }
+        let prev = self.neighbor_info.read().unwrap().prev;
+        let mut revive_via = vec![prev];
+        revive_via.extend(self.members.read().unwrap().iter().copied().filter(|m| *m != prev));
+        revive_via.retain(|addr| *addr != self.addr);
+        *self.revive_via.write().unwrap() = revive_via;
+        self.up.send_replace(false);
+        let _ = self.stop_signal.send(());
+        self.rpc.set_down(true).await;
+        if wipe_state {
+            self.wipe_state();
+        }
+        tracing::info!("T: {}. Node {} is down{}", self.log_time(), self.id.bold().red(), if wipe_state { ", state wiped" } else { "" });
+        Ok(())
     }
 
-    // revive - mark node as active and rejoin topology
+    // revive - restart the RPC server, rejoin through the prev we had when
+    // killed and reconcile resources with the ring
     pub async fn revive(self: &Arc<Self>, node_addr: SocketAddr) -> Result<(), Box<dyn Error>> {
-        if node_addr == self.addr {
-            // // restart the RPC server
-            let rpc_node = self.clone();
-            tokio::spawn(async move {
-                if let Err(e) = server::serve_rpc(rpc_node).await {
-                    tracing::error!("RPC server error: {}", e);
-                }
-            });
-            
-            // clean dependencies
-            self.waiting_messages_from.write().unwrap().clear();
-            self.permission_queue.write().unwrap().clear();
-            *self.is_active.write().unwrap() = true;
-            
+        if node_addr != self.addr {
+            return Err("Cannot revive other nodes directly".into());
+        }
+        if !self.is_down() {
+            return Err("Node is not down".into());
+        }
+        let rpc_node = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server::serve_rpc(rpc_node).await {
+                tracing::error!("RPC server error: {}", e);
+            }
+        });
+        self.rpc.set_down(false).await;
+
+        // clean dependencies
+        self.waiting_messages_from.write().unwrap().clear();
+        self.permission_queue.write().unwrap().clear();
+        self.mark_active(true);
 
-            // try to rejoin using the last known neighbor
-            let last_known_prev = self.neighbor_info.read().unwrap().prev;
-            if last_known_prev != self.addr {
-                self.try_join_other(last_known_prev).await;
+        // The workers have to run while we rejoin, or the ring change and the
+        // total order sync we post would be dropped
+        let _rejoining = RejoinGuard::new(&self.rejoining);
+        self.up.send_replace(true);
+        let candidates = std::mem::take(&mut *self.revive_via.write().unwrap());
+        let mut joined = candidates.is_empty();
+        for addr in candidates {
+            if self.try_join_other(addr).await.is_err() {
+                continue;
+            }
+            joined = true;
+            // What the ring decided while we were gone wins over what we remember
+            if let Err(e) = self.reconcile_after_rejoin().await {
+                tracing::error!("Node {} could not reconcile after rejoin: {}", self.id.bold().red(), e);
             }
+            break;
+        }
+        if !joined {
+            tracing::error!("Node {} reached nobody of its old ring, staying alone", self.id.bold().red());
+        }
+        tracing::info!("T: {}. Node {} is up again", self.log_time(), self.id.bold().green());
+        Ok(())
+    }
 
-            Ok(())
-        } else {
-            Err("Cannot revive other nodes directly".into())
+    pub fn is_down(&self) -> bool {
+        !*self.up.borrow()
+    }
+
+    pub fn is_rejoining(&self) -> bool {
+        self.rejoining.load(Ordering::SeqCst)
+    }
+
+    pub async fn wait_until_up(&self) {
+        let _ = self.up.subscribe().wait_for(|up| *up).await;
+    }
+
+    // What a restarted process would have lost. Clocks, broadcast counters,
+    // the SWIM incarnation and repair epochs are kept as if stored on disk,
+    // so nothing this node sends later looks older than what it sent before.
+    // The data directory is left alone, as a disk would be.
+    fn wipe_state(&self) {
+        {
+            let mut neighbor_info = self.neighbor_info.write().unwrap();
+            neighbor_info.next = self.addr;
+            neighbor_info.nnext = self.addr;
+            neighbor_info.prev = self.addr;
+            neighbor_info.successors.clear();
         }
+        *self.members.write().unwrap() = vec![self.addr];
+        self.fingers.write().unwrap().clear();
+        self.known_owners.write().unwrap().clear();
+        self.evicted.write().unwrap().clear();
+        self.swim.write().unwrap().restart();
+        self.repairs.lock().unwrap().restart();
+
+        for state in self.owned_resources.write().unwrap().values_mut() {
+            state.current_user = None;
+            state.request_queue.clear();
+        }
+        self.waiting_for.write().unwrap().clear();
+        self.used_resources.write().unwrap().clear();
+        self.blocked_processes.write().unwrap().clear();
+        self.pending_requests.lock().unwrap().clear();
+        self.pending_members.lock().unwrap().clear();
+
+        self.waiting_messages_from.write().unwrap().clear();
+        self.permission_queue.write().unwrap().clear();
+        self.last_test.write().unwrap().clear();
+        self.wait_status.write().unwrap().clear();
+        self.parent_nodes.write().unwrap().clear();
+        self.probe_count.write().unwrap().clear();
+        *self.last_detection.write().unwrap() = None;
+
+        self.bcast_pending.write().unwrap().clear();
+        self.bcast_delivered.write().unwrap().clear();
+        *self.total_order.write().unwrap() = TotalOrderState::new(self.addr);
+        self.snapshots.lock().unwrap().clear();
+        self.snapshot_reports.lock().unwrap().clear();
+        *self.termination.write().unwrap() = TerminationState::default();
+        self.barriers.lock().unwrap().clear();
+        self.barrier_pins.lock().unwrap().clear();
+        self.barrier_waiters.lock().unwrap().clear();
+        self.kv_store.write().unwrap().clear();
     }
 }
 
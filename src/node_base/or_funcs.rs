@@ -0,0 +1,121 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use super::cmh_funcs::{CmhMessageType, ProbeMessage};
+use super::detector::DetectionMode;
+use super::node::Node;
+
+// CMH diffusion computation for the OR (communication) model.
+// A blocked node waits for a message from ANY node in its dependent set, so the
+// initiator is only deadlocked if every query it sent was answered.
+impl Node {
+    pub async fn start_or_detection(&self, automatic: bool) -> Result<CmhMessageType, Box<dyn Error>> {
+        if *self.is_active.read().unwrap() {
+            tracing::error!("Cannot start OR detection - node {} is active", self.id);
+            return Ok(CmhMessageType::Error(*self.lamport_time.read().unwrap()));
+        }
+        tracing::info!("T: {}. Starting OR detection for node {}", self.log_time(), self.id);
+
+        let k = self.addr;
+        let dependents = self.waiting_messages_from.read().unwrap().clone();
+
+        let test_num = {
+            let mut last = self.last_test.write().unwrap();
+            let test_num = last.entry(k).or_insert(0);
+            *test_num += 1;
+            *test_num
+        };
+        self.engage_run((k, test_num), None, dependents.len() as u32);
+        self.record_detection_start(test_num, DetectionMode::Or, automatic);
+
+        for dependent in dependents {
+            let msg = CmhMessageType::OrQuery(ProbeMessage {
+                k,
+                m: test_num,
+                j: self.addr,
+                i: dependent,
+            });
+            tracing::debug!("Node {} sending OR query to {}: {:?}", self.id, dependent, msg);
+            self.send_cmh_msg(msg, self.addr);
+        }
+
+        Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
+    }
+
+    pub async fn handle_or_query(&self, query: ProbeMessage) -> Result<CmhMessageType, Box<dyn Error>> {
+        if *self.is_active.read().unwrap() {
+            tracing::debug!("Node {} is active - discarding OR query from {}", self.id, query.j);
+            return Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()));
+        }
+
+        let run = (query.k, query.m);
+        let latest = *self.last_test.read().unwrap().get(&query.k).unwrap_or(&0);
+
+        if query.m > latest {
+            // Engaging query, propagate it to our own dependent set
+            tracing::info!("T: {}. Node {} engaged by OR query from {} for {}", self.log_time(), self.id, query.j, query.k);
+            let dependents = self.waiting_messages_from.read().unwrap().clone();
+            self.engage_run(run, Some(query.j), dependents.len() as u32);
+
+            // A passive node without dependents can still be woken from outside
+            // (setActive), so it never replies and the computation doesn't finish
+            for dependent in dependents {
+                let msg = CmhMessageType::OrQuery(ProbeMessage {
+                    k: query.k,
+                    m: query.m,
+                    j: self.addr,
+                    i: dependent,
+                });
+                tracing::debug!("Node {} propagating OR query to {}: {:?}", self.id, dependent, msg);
+                self.send_cmh_msg(msg, self.addr);
+            }
+        } else if self.is_waiting(run) {
+            // Already engaged in this computation and still blocked - answer right away
+            tracing::debug!("Node {} answering non-engaging OR query from {}", self.id, query.j);
+            self.send_or_reply(query.k, query.m, query.j);
+        }
+
+        Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
+    }
+
+    pub async fn handle_or_reply(&self, reply: ProbeMessage) -> Result<CmhMessageType, Box<dyn Error>> {
+        if *self.is_active.read().unwrap() {
+            return Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()));
+        }
+
+        let run = (reply.k, reply.m);
+        if !self.is_waiting(run) {
+            tracing::debug!("Node {} dropping stale OR reply {:?}", self.id, reply);
+            return Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()));
+        }
+
+        let remaining = self.answer_received(run);
+        tracing::debug!("Node {} OR reply from {}, {:?} remaining for {:?}", self.id, reply.j, remaining, run);
+
+        if remaining == Some(0) {
+            if reply.k == self.addr {
+                tracing::info!("T: {}. OR DEADLOCK DETECTED at node {}", self.log_time(), self.id);
+                self.record_deadlock(reply.m);
+                self.finish_run(run);
+            } else {
+                // Stay engaged so later non-engaging queries of this run still get a reply
+                let engager = self.parent_nodes.read().unwrap().get(&run).cloned();
+                if let Some(engager) = engager {
+                    self.send_or_reply(reply.k, reply.m, engager);
+                }
+            }
+        }
+
+        Ok(CmhMessageType::Success(*self.lamport_time.read().unwrap()))
+    }
+
+    fn send_or_reply(&self, k: SocketAddr, m: u64, to: SocketAddr) {
+        let msg = CmhMessageType::OrReply(ProbeMessage {
+            k,
+            m,
+            j: self.addr,
+            i: to,
+        });
+        tracing::debug!("Node {} sending OR reply to {}: {:?}", self.id, to, msg);
+        self.send_cmh_msg(msg, self.addr);
+    }
+}
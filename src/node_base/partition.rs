@@ -0,0 +1,227 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use colored::Colorize;
+use super::kv::responsible_node;
+use super::node::{Node, ResourceState};
+use super::placement::PlacementMode;
+
+// How often evicted nodes are probed
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+// Evicted nodes that stay unreachable this long are forgotten
+const EVICTED_TTL: Duration = Duration::from_secs(600);
+
+impl Node {
+    // Called for every node repair removed from the ring, it may only be on the
+    // other side of a partition
+    pub fn remember_evicted(&self, addr: SocketAddr) {
+        if addr != self.addr {
+            self.evicted.write().unwrap().insert(addr, Instant::now());
+        }
+    }
+
+    // Simulated partition: RPCs to these peers fail until the set is cleared
+    pub async fn set_partition(&self, peers: HashSet<SocketAddr>) {
+        tracing::info!("T: {}. Node {} cut off from {:?}", self.log_time(), self.id.bold().yellow(), peers);
+        self.rpc.set_blocked(peers).await;
+    }
+
+    // Run by the lowest member of a ring only. An evicted node that answers
+    // again and is on another ring means a partition healed: the ring with the
+    // lower lowest member splices the other one in.
+    pub async fn probe_evicted(&self) -> Result<(), Box<dyn Error>> {
+        let members = self.members.read().unwrap().clone();
+        if members.iter().min() != Some(&self.addr) {
+            return Ok(());
+        }
+        let evicted: Vec<SocketAddr> = {
+            let mut evicted = self.evicted.write().unwrap();
+            evicted.retain(|addr, since| since.elapsed() < EVICTED_TTL && !members.contains(addr));
+            evicted.keys().copied().collect()
+        };
+
+        for addr in evicted {
+            if !self.is_alive(addr).await {
+                continue;
+            }
+            let (client, ctx) = self.rpc.get_c(addr).await;
+            let Ok(client) = client else {
+                continue;
+            };
+            let other_ring = match client.collect_members(ctx, self.stamp_send()).await {
+                Ok(other_ring) => other_ring,
+                Err(e) => {
+                    tracing::error!("Node {} could not get the ring of {}: {}", self.id.bold().red(), addr, e);
+                    continue;
+                }
+            };
+            if other_ring.contains(&self.addr) {
+                // Came back through a normal join
+                self.evicted.write().unwrap().remove(&addr);
+                continue;
+            }
+            if other_ring.iter().min().is_some_and(|other| *other < self.addr) {
+                tracing::debug!("Node {} leaves merging with {:?} to the other ring", self.id, other_ring);
+                continue;
+            }
+            self.merge_rings(addr, &members, &other_ring).await?;
+            return Ok(());
+        }
+        Ok(())
+    }
+
+    // Swap our next with the next of a node on the other ring, which joins
+    // both cycles into one: self -> other.next ... other -> our old next ... self
+    async fn merge_rings(&self, other: SocketAddr, members: &[SocketAddr], other_ring: &[SocketAddr]) -> Result<(), Box<dyn Error>> {
+        tracing::info!("T: {}. Node {} merging with the ring of {}", self.log_time(), self.id.bold().yellow(), other);
+        // The ring with the older sequencer epoch gives up its total order,
+        // the other ring's if both are equal
+        let (client, ctx) = self.rpc.get_c(other).await;
+        let other_epoch = client.map_err(|_| format!("Cannot reach {}", other))?
+            .total_order_epoch(ctx, self.stamp_send()).await?;
+        let epoch = self.total_order_epoch();
+        let yielding = if other_epoch > epoch { members } else { other_ring };
+        {
+            let _membership = self.membership_lock.lock().await;
+            let my_next = self.neighbor_info.read().unwrap().next;
+            let other_next = self.neighbors_of(other).await.ok_or("Other ring is gone")?.next;
+
+            let (client, ctx) = self.rpc.get_c(other).await;
+            let client = client.map_err(|_| format!("Cannot reach {}", other))?;
+            client.change_next(ctx, my_next, self.stamp_send()).await?;
+            {
+                let mut neighbor_info = self.neighbor_info.write().unwrap();
+                neighbor_info.next = other_next;
+                neighbor_info.successors = vec![other_next];
+            }
+            self.log_ring();
+            for (node, prev) in [(other_next, self.addr), (my_next, other)] {
+                let (client, ctx) = self.rpc.get_c(node).await;
+                let client = client.map_err(|_| format!("Cannot reach {}", node))?;
+                client.change_prev(ctx, prev, self.stamp_send()).await?;
+            }
+        }
+        // nnext and the successor lists are fixed by stabilization
+        self.stabilize_ring().await;
+        self.local_event(format!("merged ring of {}", other));
+
+        let epoch = epoch.max(other_epoch) + 1;
+        tracing::info!("Node {} {:?} give up their total order for epoch {}", self.id.bold().yellow(), yielding, epoch);
+        for node in yielding {
+            self.yield_total_order_at(*node, epoch).await;
+        }
+        self.restart_sequencer(epoch);
+
+        let members = self.ring_members().await?;
+        self.reconcile_ownership(&members).await;
+        self.announce_ring_change().await;
+        Ok(())
+    }
+
+    // Both sides may have given the same resource an owner. One owner is kept,
+    // the hashed owner if there is one, otherwise the lowest address.
+    pub async fn reconcile_ownership(&self, members: &[SocketAddr]) {
+        let mut owners: HashMap<String, Vec<SocketAddr>> = HashMap::new();
+        for member in members {
+            let names = if *member == self.addr {
+                self.owned_resources.read().unwrap().keys().cloned().collect()
+            } else {
+                let (client, ctx) = self.rpc.get_c(*member).await;
+                match client {
+                    Ok(client) => client.owned_resource_names(ctx, self.stamp_send()).await.unwrap_or_default(),
+                    Err(_) => Vec::new(),
+                }
+            };
+            for name in names {
+                owners.entry(name).or_default().push(*member);
+            }
+        }
+
+        let hashed = *self.placement.read().unwrap() == PlacementMode::Hashed;
+        for (resource, owners) in owners.into_iter().filter(|(_, o)| o.len() > 1) {
+            let winner = responsible_node(&resource, members)
+                .filter(|w| hashed && owners.contains(w))
+                .unwrap_or_else(|| *owners.iter().min().unwrap());
+            tracing::info!("Node {} resource {} owned by {:?}, keeping it at {}", self.id.bold().yellow(), resource, owners, winner);
+            for loser in owners.into_iter().filter(|o| *o != winner) {
+                let states = self.give_up_at(loser, vec![resource.clone()]).await;
+                if states.is_empty() || self.take_over_at(winner, states.clone()).await {
+                    continue;
+                }
+                // The winner did not take it, the loser keeps what it had
+                if !self.take_over_at(loser, states).await {
+                    tracing::error!("Node {} lost the state of resource {} from {}", self.id.bold().red(), resource, loser);
+                }
+            }
+        }
+    }
+
+    async fn yield_total_order_at(&self, node: SocketAddr, epoch: u64) {
+        if node == self.addr {
+            self.yield_total_order(epoch);
+            return;
+        }
+        let (client, ctx) = self.rpc.get_c(node).await;
+        let yielded = match client {
+            Ok(client) => client.yield_total_order(ctx, epoch, self.stamp_send()).await.unwrap_or(false),
+            Err(_) => false,
+        };
+        if !yielded {
+            tracing::error!("Node {} could not make {} give up its total order", self.id.bold().red(), node);
+        }
+    }
+
+    async fn give_up_at(&self, node: SocketAddr, names: Vec<String>) -> Vec<(String, ResourceState)> {
+        if node == self.addr {
+            return self.give_up_resources(names);
+        }
+        let (client, ctx) = self.rpc.get_c(node).await;
+        match client {
+            Ok(client) => client.give_up_resources(ctx, names, self.stamp_send()).await.unwrap_or_default(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    // True once the node has the resources
+    async fn take_over_at(&self, node: SocketAddr, resources: Vec<(String, ResourceState)>) -> bool {
+        if node == self.addr {
+            self.take_over_resources(resources).await;
+            return true;
+        }
+        let (client, ctx) = self.rpc.get_c(node).await;
+        let Ok(client) = client else {
+            tracing::error!("Node {} cannot reach {} to hand resources to", self.id.bold().red(), node);
+            return false;
+        };
+        match client.take_over_resources(ctx, resources, self.stamp_send()).await {
+            Ok(taken) => taken,
+            Err(e) => {
+                tracing::error!("Node {} could not hand resources to {}: {}", self.id.bold().red(), node, e);
+                false
+            }
+        }
+    }
+
+    pub fn give_up_resources(&self, names: Vec<String>) -> Vec<(String, ResourceState)> {
+        let given: Vec<(String, ResourceState)> = {
+            let mut owned = self.owned_resources.write().unwrap();
+            names.into_iter().filter_map(|name| owned.remove(&name).map(|state| (name, state))).collect()
+        };
+        for (name, _) in &given {
+            self.log_owned(name);
+        }
+        given
+    }
+}
+
+pub async fn run_partition_probe(node: Arc<Node>) {
+    loop {
+        tokio::time::sleep(PROBE_INTERVAL).await;
+        node.wait_until_up().await;
+        if let Err(e) = node.probe_evicted().await {
+            tracing::error!("Node {} partition probe failed: {}", node.id.bold().red(), e);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use super::kv::responsible_node;
+use super::node::{Node, ResourceState};
+
+// Where resources live: with the node started with them, or wherever the
+// resource name hashes to on the ring
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlacementMode {
+    #[default]
+    Static,
+    Hashed,
+}
+
+impl Node {
+    // Owner computed from the cached membership, only in hashed mode
+    pub fn hashed_owner(&self, resource: &str) -> Option<SocketAddr> {
+        if *self.placement.read().unwrap() != PlacementMode::Hashed {
+            return None;
+        }
+        responsible_node(resource, &self.members.read().unwrap())
+    }
+
+    // Switch every node on the ring to the same placement mode
+    pub async fn set_placement_everywhere(&self, mode: PlacementMode) -> Result<(), Box<dyn Error>> {
+        let members = self.ring_members().await?;
+        for member in members {
+            if member == self.addr {
+                self.set_placement(mode).await;
+                continue;
+            }
+            let (client, ctx) = self.rpc.get_c(member).await;
+            let client = client.map_err(|_| format!("Cannot reach {}", member))?;
+            client.set_placement(ctx, mode, self.stamp_send()).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn set_placement(&self, mode: PlacementMode) {
+        tracing::info!("Node {} resource placement: {:?}", self.id.bold().green(), mode);
+        *self.placement.write().unwrap() = mode;
+        self.refresh_members().await;
+        self.rebalance_resources().await;
+    }
+
+    // A joining node places resources like the ring it joins
+    pub async fn adopt_placement(&self, other: SocketAddr) {
+        let (client, ctx) = self.rpc.get_c(other).await;
+        let Ok(client) = client else {
+            tracing::error!("Node {} cannot reach {} for its placement mode", self.id.bold().red(), other);
+            return;
+        };
+        match client.placement(ctx, self.stamp_send()).await {
+            Ok(mode) => *self.placement.write().unwrap() = mode,
+            Err(e) => tracing::error!("Node {} could not get the placement mode of {}: {}", self.id.bold().red(), other, e),
+        }
+    }
+
+    // Hand every owned resource that hashes elsewhere to its new owner
+    pub async fn rebalance_resources(&self) {
+        if *self.placement.read().unwrap() != PlacementMode::Hashed {
+            return;
+        }
+        let members = self.members.read().unwrap().clone();
+        let mut moves: HashMap<SocketAddr, Vec<(String, ResourceState)>> = HashMap::new();
+        for (resource, state) in self.owned_resources.read().unwrap().iter() {
+            if let Some(owner) = responsible_node(resource, &members).filter(|o| *o != self.addr) {
+                moves.entry(owner).or_default().push((resource.clone(), state.clone()));
+            }
+        }
+        for (owner, resources) in moves {
+            self.hand_off_resources(owner, resources).await;
+        }
+    }
+
+    // A leaving node gives its resources to their owners among the remaining nodes
+    pub async fn hand_off_all_resources(&self) {
+        if *self.placement.read().unwrap() != PlacementMode::Hashed {
+            return;
+        }
+        let remaining: Vec<SocketAddr> = self.members.read().unwrap().iter().filter(|m| **m != self.addr).cloned().collect();
+        let mut moves: HashMap<SocketAddr, Vec<(String, ResourceState)>> = HashMap::new();
+        for (resource, state) in self.owned_resources.read().unwrap().iter() {
+            if let Some(owner) = responsible_node(resource, &remaining) {
+                moves.entry(owner).or_default().push((resource.clone(), state.clone()));
+            }
+        }
+        for (owner, resources) in moves {
+            self.hand_off_resources(owner, resources).await;
+        }
+    }
+
+    async fn hand_off_resources(&self, owner: SocketAddr, resources: Vec<(String, ResourceState)>) {
+        let names: Vec<String> = resources.iter().map(|(r, _)| r.clone()).collect();
+        tracing::info!("T: {}. Node {} handing resources {:?} to {}", self.log_time(), self.id.bold().yellow(), names, owner);
+        let (client, ctx) = self.rpc.get_c(owner).await;
+        let accepted = match client {
+            Ok(client) => client.take_over_resources(ctx, resources, self.stamp_send()).await.unwrap_or(false),
+            Err(_) => false,
+        };
+        if !accepted {
+            tracing::error!("Node {} could not hand resources {:?} to {}", self.id.bold().red(), names, owner);
+            return;
+        }
+        {
+            let mut owned = self.owned_resources.write().unwrap();
+            for name in &names {
+                owned.remove(name);
+            }
+        }
+        for name in &names {
+            self.log_owned(name);
+        }
+        self.local_event(format!("handed resources {:?} to {}", names, owner));
+    }
+
+    // A resource we already own keeps its user, the other user and queue line
+    // up behind. The other user is told it no longer holds the resource.
+    pub async fn take_over_resources(&self, resources: Vec<(String, ResourceState)>) {
+        let names: Vec<String> = resources.iter().map(|(r, _)| r.clone()).collect();
+        let mut displaced = Vec::new();
+        {
+            let mut owned = self.owned_resources.write().unwrap();
+            for (resource, state) in resources {
+                tracing::info!("Node {} now owns resource {}", self.id.bold().green(), resource.bold().green());
+                let Some(current) = owned.get_mut(&resource) else {
+                    owned.insert(resource, state);
+                    continue;
+                };
+                if let (Some(kept), Some(other)) = (current.current_user, state.current_user) {
+                    if kept != other {
+                        tracing::warn!("Node {} resource {} was held by {} and {}, {} keeps it", self.id.bold().yellow(), resource, kept, other, kept);
+                        displaced.push((resource.clone(), other));
+                    }
+                }
+                let waiting = state.current_user.into_iter().chain(state.request_queue);
+                for user in waiting {
+                    if current.current_user.is_none() {
+                        current.current_user = Some(user);
+                    } else if current.current_user != Some(user) && !current.request_queue.contains(&user) {
+                        current.request_queue.push(user);
+                    }
+                }
+            }
+        }
+        for name in &names {
+            self.log_owned(name);
+        }
+        for (resource, user) in displaced {
+            self.revoke_at(user, resource).await;
+        }
+    }
+
+    async fn revoke_at(&self, user: SocketAddr, resource: String) {
+        if user == self.addr {
+            self.revoke_resource(resource, self.addr);
+            return;
+        }
+        let (client, ctx) = self.rpc.get_c(user).await;
+        let revoked = match client {
+            Ok(client) => client.revoke_resource(ctx, resource.clone(), self.stamp_send()).await.unwrap_or(false),
+            Err(_) => false,
+        };
+        if !revoked {
+            tracing::error!("Node {} could not revoke {} from {}", self.id.bold().red(), resource, user);
+        }
+    }
+
+    // Another holder kept the resource, we are queued at its owner and wait
+    // for the grant like any queued request
+    pub fn revoke_resource(&self, resource: String, owner: SocketAddr) {
+        tracing::warn!("T: {}. Node {} lost resource {} to a conflicting holder, queued at {}", self.log_time(), self.id.bold().red(), resource, owner);
+        self.used_resources.write().unwrap().remove(&resource);
+        self.waiting_for.write().unwrap().insert(resource.clone(), owner);
+        self.blocked_processes.write().unwrap().insert(owner);
+        self.local_event(format!("resource {} revoked, queued at {}", resource, owner));
+        self.log_used(&resource);
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::net::SocketAddr;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use super::node::Node;
+use super::resources::ResourceMessageType::Granted;
+use super::swim::{MemberStatus, MemberUpdate};
+
+// What a revived node believes about its resources, sent to every member
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RejoinReport {
+    pub node: SocketAddr,
+    pub incarnation: u64,
+    pub owned: Vec<String>,
+    pub held: Vec<String>,
+    pub waiting: Vec<String>,
+}
+
+// A member's side after it reconciled the report with its own state
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RejoinReply {
+    // Resources the member owns, and of those the ones the revived node
+    // now holds or is queued for
+    pub owned: Vec<String>,
+    pub granted: Vec<String>,
+    pub queued: Vec<String>,
+    // Resources of the revived node the member holds or waits for
+    pub holding: Vec<String>,
+    pub waiting: Vec<String>,
+}
+
+impl Node {
+    // Member side. The owner's view of a resource wins, except that a request
+    // the node still waits for is queued again and a resource it forgot or
+    // released while it could not be heard is passed on. Reports from an
+    // incarnation already seen are ignored.
+    pub fn handle_rejoin(&self, report: RejoinReport) -> Option<RejoinReply> {
+        {
+            let mut rejoined = self.rejoined.write().unwrap();
+            if rejoined.get(&report.node).is_some_and(|seen| *seen >= report.incarnation) {
+                tracing::info!("Node {} ignores stale rejoin of {} (incarnation {})", self.id.bold().yellow(), report.node, report.incarnation);
+                return None;
+            }
+            rejoined.insert(report.node, report.incarnation);
+        }
+        self.apply_gossip(&[MemberUpdate { addr: report.node, status: MemberStatus::Alive, incarnation: report.incarnation }]);
+
+        let node = report.node;
+        let mut reply = RejoinReply::default();
+        let mut grants = Vec::new();
+        {
+            let mut owned = self.owned_resources.write().unwrap();
+            for (resource, state) in owned.iter_mut() {
+                reply.owned.push(resource.clone());
+                let holds = report.held.contains(resource);
+                let waits = report.waiting.contains(resource);
+                if state.current_user == Some(node) && !holds && !waits {
+                    tracing::info!("Node {} takes back {} from rejoined {}", self.id.bold().yellow(), resource, node);
+                    state.current_user = state.request_queue.pop();
+                    if let Some(next) = state.current_user {
+                        grants.push((resource.clone(), next));
+                    }
+                }
+                if !waits {
+                    state.request_queue.retain(|user| *user != node);
+                }
+                if (holds || waits) && state.current_user.is_none() {
+                    state.current_user = Some(node);
+                } else if waits && state.current_user != Some(node) && !state.request_queue.contains(&node) {
+                    state.request_queue.push(node);
+                }
+
+                if state.current_user == Some(node) {
+                    reply.granted.push(resource.clone());
+                } else if state.request_queue.contains(&node) {
+                    reply.queued.push(resource.clone());
+                }
+            }
+        }
+        for (resource, next) in grants {
+            self.local_event(format!("granted resource {} to {}", resource, next));
+            self.send_resource_msg(Granted(resource, next), self.addr);
+        }
+        self.log_resources();
+
+        reply.holding = self.used_resources.read().unwrap().iter()
+            .filter(|(resource, owner)| **owner == node && report.owned.contains(resource))
+            .map(|(resource, _)| resource.clone())
+            .collect();
+        reply.waiting = self.waiting_for.read().unwrap().iter()
+            .filter(|(resource, owner)| **owner == node && report.owned.contains(resource))
+            .map(|(resource, _)| resource.clone())
+            .collect();
+        Some(reply)
+    }
+
+    // Revived node side: tell every member what we believe, then take over
+    // what the ring decided for the resources we use and rebuild the state of
+    // the resources we own from what their users report
+    pub async fn reconcile_after_rejoin(&self) -> Result<(), Box<dyn Error>> {
+        let members = self.ring_members().await?;
+        let report = RejoinReport {
+            node: self.addr,
+            incarnation: self.swim.read().unwrap().incarnation(),
+            owned: self.owned_resources.read().unwrap().keys().cloned().collect(),
+            held: self.used_resources.read().unwrap().keys().cloned().collect(),
+            waiting: self.waiting_for.read().unwrap().keys().cloned().collect(),
+        };
+        tracing::info!("T: {}. Node {} reconciling after rejoin (incarnation {})", self.log_time(), self.id.bold().yellow(), report.incarnation);
+
+        let mut granted = HashMap::new();
+        let mut queued = HashMap::new();
+        let mut holders: HashMap<String, Vec<SocketAddr>> = HashMap::new();
+        let mut waiters: HashMap<String, Vec<SocketAddr>> = HashMap::new();
+        let mut duplicate_owner = false;
+        let mut unanswered = HashSet::new();
+        for member in members.iter().copied().filter(|m| *m != self.addr) {
+            let (client, ctx) = self.rpc.get_c(member).await;
+            let Ok(client) = client else {
+                tracing::error!("Node {} cannot reach {} to reconcile", self.id.bold().red(), member);
+                unanswered.insert(member);
+                continue;
+            };
+            let reply = match client.rejoin(ctx, report.clone(), self.stamp_send()).await {
+                Ok(Some(reply)) => reply,
+                Ok(None) => continue,
+                Err(e) => {
+                    tracing::error!("Node {} could not reconcile with {}: {}", self.id.bold().red(), member, e);
+                    unanswered.insert(member);
+                    continue;
+                }
+            };
+            duplicate_owner |= reply.owned.iter().any(|r| report.owned.contains(r));
+            granted.extend(reply.granted.into_iter().map(|r| (r, member)));
+            queued.extend(reply.queued.into_iter().map(|r| (r, member)));
+            for resource in reply.holding {
+                holders.entry(resource).or_default().push(member);
+            }
+            for resource in reply.waiting {
+                waiters.entry(resource).or_default().push(member);
+            }
+        }
+        // Resources we use from ourselves need no message
+        for (resource, owner) in self.used_resources.read().unwrap().iter().filter(|(_, o)| **o == self.addr) {
+            granted.insert(resource.clone(), *owner);
+            holders.entry(resource.clone()).or_default().push(self.addr);
+        }
+        for (resource, owner) in self.waiting_for.read().unwrap().iter().filter(|(_, o)| **o == self.addr) {
+            queued.insert(resource.clone(), *owner);
+            waiters.entry(resource.clone()).or_default().push(self.addr);
+        }
+        // What we believed about members that did not answer stays as it was
+        for (resource, owner) in self.used_resources.read().unwrap().iter().filter(|(_, o)| unanswered.contains(*o)) {
+            granted.insert(resource.clone(), *owner);
+        }
+        for (resource, owner) in self.waiting_for.read().unwrap().iter().filter(|(_, o)| unanswered.contains(*o)) {
+            queued.insert(resource.clone(), *owner);
+        }
+        for (resource, state) in self.owned_resources.read().unwrap().iter() {
+            for user in state.current_user.iter().filter(|u| unanswered.contains(*u)) {
+                holders.entry(resource.clone()).or_default().push(*user);
+            }
+            for user in state.request_queue.iter().filter(|u| unanswered.contains(*u)) {
+                waiters.entry(resource.clone()).or_default().push(*user);
+            }
+        }
+
+        self.adopt_ring_view(granted, queued);
+        self.rebuild_owned(&holders, &waiters);
+        self.log_resources();
+        if duplicate_owner {
+            self.reconcile_ownership(&members).await;
+        }
+        self.local_event(format!("reconciled resources after rejoin, incarnation {}", report.incarnation));
+        Ok(())
+    }
+
+    fn adopt_ring_view(&self, granted: HashMap<String, SocketAddr>, queued: HashMap<String, SocketAddr>) {
+        let mut used = self.used_resources.write().unwrap();
+        let mut waiting = self.waiting_for.write().unwrap();
+        for resource in used.keys().filter(|r| !granted.contains_key(*r)) {
+            tracing::info!("Node {} lost resource {} while it was down", self.id.bold().yellow(), resource);
+        }
+        for resource in granted.keys().filter(|r| waiting.contains_key(*r)) {
+            tracing::info!("Node {} acquired resource {} while it was down", self.id.bold().green(), resource);
+        }
+        for resource in waiting.keys().filter(|r| !granted.contains_key(*r) && !queued.contains_key(*r)) {
+            tracing::info!("Node {} no longer waits for resource {}, its owner is gone", self.id.bold().yellow(), resource);
+        }
+        *used = granted;
+        *waiting = queued;
+        *self.blocked_processes.write().unwrap() = waiting.values().copied().collect::<HashSet<_>>();
+    }
+
+    fn rebuild_owned(&self, holders: &HashMap<String, Vec<SocketAddr>>, waiters: &HashMap<String, Vec<SocketAddr>>) {
+        let mut grants = Vec::new();
+        {
+            let mut owned = self.owned_resources.write().unwrap();
+            for (resource, state) in owned.iter_mut() {
+                let holding = holders.get(resource).cloned().unwrap_or_default();
+                let waiting = waiters.get(resource).cloned().unwrap_or_default();
+                if let Some(user) = state.current_user.filter(|u| !holding.contains(u)) {
+                    tracing::info!("Node {} resource {} no longer used by {}", self.id.bold().yellow(), resource, user);
+                    state.current_user = None;
+                }
+                state.request_queue.retain(|user| waiting.contains(user));
+                for holder in holding {
+                    match state.current_user {
+                        None => state.current_user = Some(holder),
+                        Some(user) if user != holder => {
+                            tracing::error!("Node {} resource {} held by both {} and {}", self.id.bold().red(), resource, user, holder);
+                            if !state.request_queue.contains(&holder) {
+                                state.request_queue.push(holder);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                for waiter in waiting {
+                    if state.current_user != Some(waiter) && !state.request_queue.contains(&waiter) {
+                        state.request_queue.push(waiter);
+                    }
+                }
+                if state.current_user.is_none() {
+                    state.current_user = state.request_queue.pop();
+                    if let Some(next) = state.current_user {
+                        grants.push((resource.clone(), next));
+                    }
+                }
+            }
+        }
+        for (resource, next) in grants {
+            self.local_event(format!("granted resource {} to {}", resource, next));
+            self.send_resource_msg(Granted(resource, next), self.addr);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use super::node::Node;
+
+// A repair that did not come back around the ring by then is given up
+const REPAIR_TIMEOUT: Duration = Duration::from_secs(10);
+// How often a message waiting for a repair checks whether it finished
+const REPAIR_POLL: Duration = Duration::from_millis(100);
+// Finished repairs kept for GET /repairs
+const REPAIR_HISTORY: usize = 32;
+
+// Repairs of the same missing node are ordered by origin and epoch, the
+// smallest one is the one that keeps going when two meet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RepairId {
+    pub missing: SocketAddr,
+    pub origin: SocketAddr,
+    pub epoch: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepairStatus {
+    Running,
+    // Went around the ring back to its origin
+    Done,
+    // Dropped in favour of another repair of the same node
+    Merged,
+    // Passed on by this node
+    Forwarded,
+    TimedOut,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RepairRecord {
+    pub id: RepairId,
+    pub status: RepairStatus,
+    // Other dead nodes found next to the missing one
+    pub skipped: Vec<SocketAddr>,
+    pub merged_into: Option<RepairId>,
+    pub age_ms: u128,
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl RepairRecord {
+    fn new(id: RepairId, status: RepairStatus) -> Self {
+        Self { id, status, skipped: Vec::new(), merged_into: None, age_ms: 0, started: Instant::now() }
+    }
+}
+
+type RepairOutcome = (RepairStatus, Vec<SocketAddr>);
+
+#[derive(Debug, Default)]
+pub struct RepairBook {
+    // Repairs started here by missing node, with the task waiting for them
+    running: HashMap<SocketAddr, (RepairRecord, oneshot::Sender<RepairOutcome>)>,
+    // Repairs that passed through here, so a message is never handled twice
+    seen: HashMap<RepairId, Instant>,
+    history: VecDeque<RepairRecord>,
+    next_epoch: u64,
+}
+
+impl RepairBook {
+    // Epochs keep counting so new repair ids never match old ones
+    pub fn restart(&mut self) {
+        *self = Self { next_epoch: self.next_epoch, ..Self::default() };
+    }
+
+    fn remember(&mut self, record: RepairRecord) {
+        if self.history.len() == REPAIR_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(record);
+    }
+}
+
+impl Node {
+    // Starts a repair around the missing node unless one is already running
+    // here, and waits until it went around the ring or timed out
+    pub async fn repair_topology(&self, missing_node: SocketAddr) {
+        let (id, done) = {
+            let mut book = self.repairs.lock().unwrap();
+            if let Some((record, _)) = book.running.get(&missing_node) {
+                tracing::info!("Node {} already repairing {} (epoch {})", self.id.bold().yellow(), missing_node, record.id.epoch);
+                return;
+            }
+            let id = RepairId { missing: missing_node, origin: self.addr, epoch: book.next_epoch };
+            book.next_epoch += 1;
+            let (tx, rx) = oneshot::channel();
+            book.running.insert(missing_node, (RepairRecord::new(id, RepairStatus::Running), tx));
+            *self.repairing.write().unwrap() = true;
+            (id, rx)
+        };
+        tracing::info!("Node {} repairing topology with missing node: {} (epoch {})", self.id.bold().green(), missing_node.to_string().bold().red(), id.epoch);
+
+        self.process_repair(id, Vec::new()).await;
+        let (status, skipped) = match tokio::time::timeout(REPAIR_TIMEOUT, done).await {
+            Ok(Ok(outcome)) => outcome,
+            _ => {
+                self.finish_repair(id, RepairStatus::TimedOut, None, Vec::new());
+                (RepairStatus::TimedOut, Vec::new())
+            }
+        };
+        match status {
+            RepairStatus::Done => {
+                tracing::info!("Node {} repaired topology with missing node: {}", self.id.bold().green(), missing_node.to_string().bold().red());
+                for missing in std::iter::once(missing_node).chain(skipped) {
+                    self.regenerate_token(missing);
+                }
+                self.announce_ring_change().await;
+            }
+            RepairStatus::Merged => {
+                tracing::info!("Node {} repair of {} merged into another one", self.id.bold().green(), missing_node);
+            }
+            _ => tracing::error!("Node {} repair of {} ended as {:?}", self.id.bold().red(), missing_node, status),
+        }
+    }
+
+    // missing_node RPC: returns at once, the repair is handled in its own task
+    pub async fn handle_repair_message(self: &Arc<Self>, id: RepairId, skipped: Vec<SocketAddr>) -> bool {
+        let next = self.neighbor_info.read().unwrap().next;
+        if id.origin == self.addr && next != id.missing {
+            tracing::info!("Node {} repair of {} went around the ring", self.id.bold().green(), id.missing);
+            self.finish_repair(id, RepairStatus::Done, None, skipped);
+            return true;
+        }
+        let covered_by = {
+            let mut book = self.repairs.lock().unwrap();
+            book.seen.retain(|_, at| at.elapsed() < REPAIR_TIMEOUT);
+            if book.seen.insert(id, Instant::now()).is_some() {
+                return false;
+            }
+            // Our own repair of the same node is ahead of it, unless we are
+            // the node that still has to fix its next
+            book.running.get(&id.missing).map(|(record, _)| record.id).filter(|own| *own < id && next != id.missing)
+        };
+
+        let node = self.clone();
+        if let Some(own) = covered_by {
+            tracing::info!("Node {} drops repair {:?}, covered by {:?}", self.id.bold().yellow(), id, own);
+            tokio::spawn(async move {
+                let (client, ctx) = node.rpc.get_c(id.origin).await;
+                if let Ok(client) = client {
+                    let _ = client.repair_merged(ctx, id, own, node.stamp_send()).await;
+                }
+            });
+            return true;
+        }
+        self.repairs.lock().unwrap().remember(RepairRecord::new(id, RepairStatus::Forwarded));
+        tokio::spawn(async move {
+            node.process_repair(id, skipped).await;
+        });
+        true
+    }
+
+    // Resolves once no repair of the missing node runs here any more
+    pub async fn repair_finished(&self, missing_node: SocketAddr) {
+        let deadline = Instant::now() + REPAIR_TIMEOUT;
+        while self.repairs.lock().unwrap().running.contains_key(&missing_node) && Instant::now() < deadline {
+            tokio::time::sleep(REPAIR_POLL).await;
+        }
+    }
+
+    // Ends a repair started here, late or unknown ids are ignored
+    pub fn finish_repair(&self, id: RepairId, status: RepairStatus, merged_into: Option<RepairId>, skipped: Vec<SocketAddr>) {
+        let mut book = self.repairs.lock().unwrap();
+        if book.running.get(&id.missing).is_none_or(|(record, _)| record.id != id) {
+            return;
+        }
+        let (mut record, waiter) = book.running.remove(&id.missing).unwrap();
+        record.status = status;
+        record.merged_into = merged_into;
+        record.skipped = skipped.clone();
+        book.remember(record);
+        *self.repairing.write().unwrap() = !book.running.is_empty();
+        let _ = waiter.send((status, skipped));
+    }
+
+    pub fn repairs(&self) -> Vec<RepairRecord> {
+        let book = self.repairs.lock().unwrap();
+        book.running.values().map(|(record, _)| record).chain(book.history.iter().rev()).map(|record| {
+            let mut record = record.clone();
+            record.age_ms = record.started.elapsed().as_millis();
+            record
+        }).collect()
+    }
+
+    // Drops the dependencies on the missing nodes, fixes our next if it is
+    // one of them and passes the repair on along the ring
+    async fn process_repair(&self, id: RepairId, mut skipped: Vec<SocketAddr>) {
+        tracing::debug!("Node {} received missing node from {} will remove deps", self.id.bold().green(), id.origin.to_string().bold().red());
+        self.delete_dependencies(id.missing).await;
+        for dead in &skipped {
+            self.delete_dependencies(*dead).await;
+        }
+
+        let next = self.neighbor_info.read().unwrap().next;
+        let target = if next != id.missing && self.is_alive(next).await {
+            next
+        } else {
+            let (new_next, dead) = self.skip_dead_successors(id.missing).await;
+            for dead in dead {
+                if dead != id.missing && !skipped.contains(&dead) {
+                    self.delete_dependencies(dead).await;
+                    skipped.push(dead);
+                }
+            }
+            new_next
+        };
+        if target == self.addr {
+            // Nobody else is left
+            if id.origin == self.addr {
+                self.finish_repair(id, RepairStatus::Done, None, skipped);
+            }
+            return;
+        }
+
+        let (client, ctx) = self.rpc.get_c(target).await;
+        let sent = match client {
+            Ok(client) => client.missing_node(ctx, id, skipped, self.stamp_send()).await.is_ok(),
+            Err(_) => false,
+        };
+        if !sent {
+            tracing::error!("Node {} could not pass repair of {} on to {}", self.id.bold().red(), id.missing, target);
+            if id.origin == self.addr {
+                self.finish_repair(id, RepairStatus::Failed, None, Vec::new());
+            }
+        }
+    }
+
+    // Our next is gone: skip every dead node at the start of the successor
+    // list, returns the new next and the nodes skipped
+    async fn skip_dead_successors(&self, missing_node: SocketAddr) -> (SocketAddr, Vec<SocketAddr>) {
+        tracing::debug!("Node {} fixing topology with missing node: {}", self.id.bold().green(), missing_node.to_string().bold().red());
+        let (candidates, prev) = {
+            let neighbor_info = self.neighbor_info.read().unwrap();
+            let candidates = if neighbor_info.successors.is_empty() {
+                vec![neighbor_info.next, neighbor_info.nnext]
+            } else {
+                neighbor_info.successors.clone()
+            };
+            (candidates, neighbor_info.prev)
+        };
+        let mut dead = Vec::new();
+        let mut new_next = self.addr;
+        for candidate in candidates {
+            if candidate == self.addr {
+                break;
+            }
+            if candidate != missing_node && self.is_alive(candidate).await {
+                new_next = candidate;
+                break;
+            }
+            dead.push(candidate);
+        }
+        if new_next == self.addr {
+            // the whole list is unreachable (e.g. cut off by a partition), walk back instead
+            if let Some(first) = self.first_alive_behind(&dead).await {
+                new_next = first;
+            }
+        }
+        tracing::info!("Node {} skipping dead successors {:?}, next is now {}", self.id.bold().yellow(), dead, new_next);
+        for dead_node in &dead {
+            self.rpc.delete_client(*dead_node).await;
+        }
+
+        // to my new next send msg ChPrev with myaddr -> my nnext = its next
+        self.neighbor_info.write().unwrap().next = new_next;
+        let (client, ctx) = self.rpc.get_c(new_next).await;
+        let nnext = match client {
+            Ok(client) => client.change_prev(ctx, self.addr, self.stamp_send()).await.unwrap_or(new_next),
+            Err(_) => new_next,
+        };
+        {
+            let mut neighbor_info = self.neighbor_info.write().unwrap();
+            neighbor_info.nnext = nnext;
+            neighbor_info.successors = self.successor_list(new_next, &[nnext]);
+        }
+        // to my prev send msg ChNNext to my new next
+        if dead.contains(&prev) {
+            // everyone behind us died as well
+            self.neighbor_info.write().unwrap().prev = new_next;
+        } else {
+            let (client, ctx) = self.rpc.get_c(prev).await;
+            if let Ok(client) = client {
+                let _ = client.change_nnext(ctx, new_next, self.stamp_send()).await;
+            }
+        }
+        self.log_ring();
+        (new_next, dead)
+    }
+}
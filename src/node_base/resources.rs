@@ -5,7 +5,7 @@ use colored::Colorize;
 use serde::{Deserialize, Serialize};
 use super::resources::ResourceMessageType::*;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ResourceMessageType {
     ResourceQuery(String),
     Acquire(String),
@@ -16,16 +16,18 @@ pub enum ResourceMessageType {
     Unknown,
     Error,
     Success,
+    // Answer to request id, routed back to the requesting node
+    Reply(u64, SocketAddr, Box<ResourceMessageType>),
 }
 
 impl Node {
     
     pub async fn assign_resource(&self, resource: String) -> Result<(), Box<dyn Error>> {
-        let mut owned = self.owned_resources.write().unwrap();
-        owned.insert(resource.clone(), ResourceState {
+        self.owned_resources.write().unwrap().insert(resource.clone(), ResourceState {
             current_user: None,
             request_queue: Vec::new(),
         });
+        self.log_owned(&resource);
         Ok(())
     }
 
@@ -38,16 +40,35 @@ impl Node {
                 return Ok(self.addr);
             }
         }
+
+        // With hashed placement the owner follows from the name
+        if let Some(owner) = self.hashed_owner(resource_id) {
+            return Ok(owner);
+        }
     
-        // Forward query to next node
-        let msg = ResourceQuery(resource_id.to_string());
-        match self.send_resource_msg(msg, self.addr).await {
-            Owner(s) => {
-                Ok(s)
-            },
-            _ => Err("Resource not found".into())
+        // Forward query to next node, once more over the whole ring if a
+        // stale owner we knew made it skip nodes
+        loop {
+            let msg = ResourceQuery(resource_id.to_string());
+            match self.request_resource_msg(msg).await? {
+                Owner(s) => {
+                    self.known_owners.write().unwrap().insert(resource_id.to_string(), s);
+                    return Ok(s);
+                },
+                _ if self.forget_owner(resource_id) => {}
+                _ => return Err("Resource not found".into()),
+            }
         }
     }
+
+    // Drops what we learned about the owner of a resource, returns whether there was anything
+    fn forget_owner(&self, resource: &str) -> bool {
+        let Some(owner) = self.known_owners.write().unwrap().remove(resource) else {
+            return false;
+        };
+        tracing::info!("Node {} no longer takes {} for the owner of {}, walking the ring", self.id.bold().yellow(), owner, resource);
+        true
+    }
     
     async fn use_resource(&self, resource: String, user: SocketAddr, owner: SocketAddr) -> Result<String, Box<dyn Error>>{
         if user != self.addr {
@@ -60,7 +81,9 @@ impl Node {
         //     self.set_active().await?;
         // }
         tracing::info!("Node {} acquired resource {}", self.id.bold().green(), resource.bold().green());
+        self.local_event(format!("acquired resource {} from {}", resource, owner));
         self.used_resources.write().unwrap().insert(resource.clone(), owner);
+        self.log_used(&resource);
         Ok("GRANTED".to_string())
     }
 
@@ -68,25 +91,30 @@ impl Node {
     pub async fn acquire_resource(&self, resource: String) -> Result<String, Box<dyn Error>> {
         tracing::info!("Node {} trying to acquire resource {}", self.id.bold().green(), resource.bold().green());
 
-        // TODO: Optimize this, since this isnt needed
-        let owner = self.find_resource_owner(&resource).await?;
-        // self.set_passive().await?;
-
-        tracing::info!("Node {} got owner {}", self.id.bold().green(), resource.bold().green());
-        let msg = Acquire(resource.clone());
-        let response = self.send_resource_msg(msg, self.addr).await;
-
-        match response {
-            Granted(resource, addr) => {
-                self.use_resource(resource, addr, owner).await
-            },
-            Queued => {
-                self.waiting_for.write().unwrap().insert(resource.clone(), owner);
-                self.blocked_processes.write().unwrap().insert(owner);
-                tracing::debug!("Node {} queued for resource {}", self.id.bold().green(), resource.bold().green());
-                Ok("QUEUED".to_string())
-            },
-            _ => Ok("UNKNOWN".to_string())
+        loop {
+            // TODO: Optimize this, since this isnt needed
+            let owner = self.find_resource_owner(&resource).await?;
+            // self.set_passive().await?;
+
+            tracing::info!("Node {} got owner {}", self.id.bold().green(), resource.bold().green());
+            let msg = Acquire(resource.clone());
+            let response = self.request_resource_msg(msg).await?;
+
+            return match response {
+                Granted(resource, addr) => {
+                    self.use_resource(resource, addr, owner).await
+                },
+                Queued => {
+                    self.waiting_for.write().unwrap().insert(resource.clone(), owner);
+                    self.blocked_processes.write().unwrap().insert(owner);
+                    self.log_used(&resource);
+                    tracing::debug!("Node {} queued for resource {}", self.id.bold().green(), resource.bold().green());
+                    Ok("QUEUED".to_string())
+                },
+                // The owner we found gave the resource away meanwhile
+                Unknown if self.forget_owner(&resource) => continue,
+                _ => Ok("UNKNOWN".to_string())
+            };
         }
     }
 
@@ -98,13 +126,15 @@ impl Node {
             return Ok(());
         }
         let _owner = self.used_resources.write().unwrap().remove(&resource).unwrap();
+        self.log_used(&resource);
+        self.local_event(format!("released resource {}", resource));
         let msg = Release(resource.clone());
-        self.send_resource_msg(msg, self.addr).await;
+        self.send_resource_msg(msg, self.addr);
         Ok(())
     }
 
     // Process resource request
-    async fn process_resource_request(&self, resource: String, from: SocketAddr) -> Result<ResourceMessageType, Box<dyn Error>> {
+    async fn process_resource_request(&self, resource: String, from: SocketAddr, request_id: Option<u64>) -> Result<(), Box<dyn Error>> {
         let mut is_owned = false;
         let mut current_user = None;
 
@@ -122,19 +152,27 @@ impl Node {
         }
 
         if is_owned {
+            self.log_owned(&resource);
             if current_user.is_none() {
                 tracing::debug!("Node {} granting resource {} to {}", self.id.bold().green(), resource.bold().green(), from.to_string().bold().green());
-                Ok(Granted(resource, from))
+                self.local_event(format!("granted resource {} to {}", resource, from));
+                self.reply_resource_msg(request_id, from, Granted(resource, from));
             } else {
                 tracing::debug!("Node {} queuing resource {} for {}", self.id.bold().green(), resource.bold().green(), from.to_string().bold().green());
-                Ok(Queued)
+                self.local_event(format!("queued {} for resource {}", from, resource));
+                self.reply_resource_msg(request_id, from, Queued);
             }
+        } else if from == self.neighbor_info.read().unwrap().next {
+            // Went around the whole ring without finding the owner
+            tracing::debug!("Node {} is last node, resource {} not found", self.id.bold().green(), resource);
+            self.reply_resource_msg(request_id, from, Unknown);
         } else {
             // We don't own the resource, forward to next node
             tracing::debug!("Node {} forwarding resource {} to {}", self.id.bold().green(), resource.bold().green(), from.to_string().bold().green());
             let msg = Acquire(resource);
-            Ok(self.send_resource_msg(msg, from).await)
+            self.forward_resource_msg(msg, from, request_id);
         }
+        Ok(())
     }
 
     // Process resource release
@@ -157,24 +195,26 @@ impl Node {
         }
 
         if is_owned {
+            self.log_owned(&resource);
             tracing::debug!("Node {} reacquiring resource {}", self.id.bold().green(), resource.bold().green());
             if let Some(next) = next_user {
                 tracing::debug!("Node {} granting resource {} to {}", self.id.bold().green(), resource.bold().green(), next.to_string().bold().green());
+                self.local_event(format!("granted resource {} to {}", resource, next));
                 let msg = Granted(resource, next);
-                self.send_resource_msg(msg, self.addr).await;
+                self.send_resource_msg(msg, self.addr);
             }
             Ok(())
         } else {
             // We don't own the resource, forward to next node
             tracing::debug!("Node {} forwarding resource {} to {}", self.id.bold().green(), resource.bold().green(), from.to_string().bold().green());
             let msg = Release(resource);
-            self.send_resource_msg(msg, from).await;
+            self.send_resource_msg(msg, from);
             Ok(())
         }
     }
 
-    // Handle messages
-    pub async fn handle_message(&self, msg: ResourceMessageType, from: SocketAddr) -> Result<ResourceMessageType, Box<dyn Error>> {
+    // Handle messages, requests carry an id so the answer can be routed back to `from`
+    pub async fn handle_message(&self, msg: ResourceMessageType, from: SocketAddr, request_id: Option<u64>) -> Result<(), Box<dyn Error>> {
         // let _lock = self.critical_section.lock().await;
         tracing::debug!("Node {} starting handling of message {:?}", self.id.bold().green(), msg);
 
@@ -184,28 +224,27 @@ impl Node {
                 tracing::debug!("Node {} handling resource query for {}", self.id.bold().green(), resource_id);
                 if self.owned_resources.read().unwrap().contains_key(&resource_id) {
                     tracing::debug!("Node {} is owner of {}", self.id.bold().green(), resource_id);
-                    Ok(Owner(self.addr))
+                    self.reply_resource_msg(request_id, from, Owner(self.addr));
                 } else {
                     // Forward to next node
                     if from == self.neighbor_info.read().unwrap().next {
                         // We have already checked all nodes
                         tracing::debug!("Node {} is last node, resource not found", self.id.bold().green());
-                        Ok(Unknown)
+                        self.reply_resource_msg(request_id, from, Unknown);
                     } else {
                         tracing::debug!("Node {} forwarding resource query for {}", self.id.bold().green(), resource_id);
-                        Ok(self.send_resource_msg(ResourceQuery(resource_id), from).await)
+                        self.forward_resource_msg(ResourceQuery(resource_id), from, request_id);
                     }
                 }
+                Ok(())
             }
             ResourceMessageType::Acquire(resource) => {
                 tracing::debug!("Node {} handling acquire request for {}", self.id.bold().green(), resource);
-                let response = self.process_resource_request(resource.clone(), from).await?;
-                Ok(response)
+                self.process_resource_request(resource.clone(), from, request_id).await
             }
             ResourceMessageType::Release(resource) => {
                 tracing::debug!("Node {} handling release request for {}", self.id.bold().green(), resource);
-                self.process_resource_release(resource.clone(), from).await?;
-                Ok(Success)
+                self.process_resource_release(resource.clone(), from).await
             }
             ResourceMessageType::Granted(resource, user) => {
                 tracing::debug!("Node {} handling granted request for {}", self.id.bold().green(), resource);
@@ -213,13 +252,21 @@ impl Node {
                     // Forward to next node
                     tracing::debug!("Node {} forwarding granted request for {}", self.id.bold().green(), resource);
                     let msg = Granted(resource, user);
-                    Ok(self.send_resource_msg(msg, from).await)
+                    self.send_resource_msg(msg, from);
                 } else {
                     self.use_resource(resource, user, from).await?;
-                    Ok(Success)
                 }
+                Ok(())
+            }
+            ResourceMessageType::Reply(id, to, reply) => {
+                if to == self.addr {
+                    self.complete_request(id, *reply);
+                } else {
+                    self.send_resource_msg(Reply(id, to, reply), from);
+                }
+                Ok(())
             }
-            _ => {Ok(Unknown)}
+            _ => {Ok(())}
         }
     }
 }
\ No newline at end of file
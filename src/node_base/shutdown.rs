@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use colored::Colorize;
+use tokio::signal::unix::{signal, SignalKind};
+use super::node::Node;
+
+// Longest wait for the outbox and for RPC handlers to finish
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+const DRAIN_POLL: Duration = Duration::from_millis(50);
+
+// Exit codes: 0 left the ring cleanly, 2 the ring has to repair around us
+// or messages may have been lost, 130 a second signal cut the shutdown short
+pub const EXIT_CLEAN: i32 = 0;
+pub const EXIT_UNCLEAN: i32 = 2;
+pub const EXIT_FORCED: i32 = 130;
+
+// Resolves with the name of the first SIGINT or SIGTERM
+pub async fn wait_for_signal() -> &'static str {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            tracing::error!("Cannot listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+impl Node {
+    // Leaves the ring with resource and token handoff, waits for the outbox
+    // to empty, then stops the RPC server and waits for running handlers.
+    // With a data directory the last ring and state are compacted into a
+    // snapshot for the next start. Returns whether all of it went through.
+    pub async fn shutdown(&self) -> bool {
+        let mut clean = true;
+        self.log_ring();
+        if self.is_down() {
+            tracing::info!("Node {} is killed, nothing to hand off", self.id.bold().yellow());
+        } else if self.neighbor_info.read().unwrap().next != self.addr {
+            if let Err(e) = self.leave().await {
+                tracing::error!("Node {} could not leave the ring: {}", self.id.bold().red(), e);
+                clean = false;
+            }
+        }
+        clean &= drain("outbox", &self.outbox_pending).await;
+
+        let _ = self.stop_signal.send(());
+        clean &= drain("RPC handlers", &self.rpcs_in_flight).await;
+        self.compact_wal();
+        clean
+    }
+}
+
+async fn drain(what: &str, pending: &AtomicUsize) -> bool {
+    let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+        while pending.load(Ordering::SeqCst) > 0 {
+            tokio::time::sleep(DRAIN_POLL).await;
+        }
+    }).await.is_ok();
+    if !drained {
+        tracing::error!("Gave up waiting for {}, {} still pending", what, pending.load(Ordering::SeqCst));
+    }
+    drained
+}
@@ -0,0 +1,212 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use super::clocks::VectorClock;
+use super::messaging::RingMessage;
+use super::node::{NeighborInfo, Node, ResourceState};
+
+// How long the initiator waits for every member to report its part of the cut
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Marker of a snapshot. Ring messages also go over fingers and prev, so every
+// channel between two members carries a marker, sent straight to its node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotMarker {
+    pub initiator: SocketAddr,
+    pub id: u64,
+    // Ring members when the snapshot started
+    pub members: Vec<SocketAddr>,
+    pub to: SocketAddr,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalSnapshot {
+    pub addr: SocketAddr,
+    pub lamport: u64,
+    pub vclock: VectorClock,
+    pub neighbors: NeighborInfo,
+    pub owned_resources: HashMap<String, ResourceState>,
+    pub waiting_for: HashMap<String, SocketAddr>,
+    pub used_resources: HashMap<String, SocketAddr>,
+    pub active: bool,
+    pub waiting_messages_from: HashSet<SocketAddr>,
+    pub permission_queue: HashSet<SocketAddr>,
+    pub last_test: HashMap<SocketAddr, u64>,
+}
+
+// Message that was on the channel from one member to another at the cut
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelMessage {
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+    pub message: RingMessage,
+}
+
+// Consistent cut: every member's state plus the messages on the channels between them
+#[derive(Debug, Serialize)]
+pub struct GlobalSnapshot {
+    pub initiator: SocketAddr,
+    pub id: u64,
+    pub nodes: Vec<LocalSnapshot>,
+    pub in_flight: Vec<ChannelMessage>,
+}
+
+// Our recorded state, and the incoming channels still recorded until their marker arrives
+#[derive(Debug)]
+pub struct SnapshotRecording {
+    pub local: LocalSnapshot,
+    pub open: HashSet<SocketAddr>,
+    pub in_flight: Vec<ChannelMessage>,
+    pub started: Instant,
+}
+
+// Parts of a snapshot started here, reported by the members as they finish
+#[derive(Debug)]
+pub struct SnapshotCollection {
+    pub waiting_for: HashSet<SocketAddr>,
+    pub nodes: Vec<LocalSnapshot>,
+    pub in_flight: Vec<ChannelMessage>,
+    pub done: Option<oneshot::Sender<()>>,
+}
+
+impl Node {
+    pub fn local_snapshot(&self) -> LocalSnapshot {
+        LocalSnapshot {
+            addr: self.addr,
+            lamport: *self.lamport_time.read().unwrap(),
+            vclock: self.vector_clock.read().unwrap().clone(),
+            neighbors: self.neighbor_info.read().unwrap().clone(),
+            owned_resources: self.owned_resources.read().unwrap().clone(),
+            waiting_for: self.waiting_for.read().unwrap().clone(),
+            used_resources: self.used_resources.read().unwrap().clone(),
+            active: *self.is_active.read().unwrap(),
+            waiting_messages_from: self.waiting_messages_from.read().unwrap().clone(),
+            permission_queue: self.permission_queue.read().unwrap().clone(),
+            last_test: self.last_test.read().unwrap().clone(),
+        }
+    }
+
+    pub async fn take_snapshot(&self) -> Result<GlobalSnapshot, Box<dyn Error>> {
+        let id = self.next_snapshot_id.fetch_add(1, Ordering::SeqCst);
+        tracing::info!("T: {}. Node {} starting snapshot {}", self.log_time(), self.id.bold().green(), id);
+        let members = self.members.read().unwrap().clone();
+
+        if self.neighbor_info.read().unwrap().next == self.addr || members.len() < 2 {
+            self.local_event(format!("snapshot {} recorded", id));
+            return Ok(GlobalSnapshot { initiator: self.addr, id, nodes: vec![self.local_snapshot()], in_flight: Vec::new() });
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.snapshot_reports.lock().unwrap().insert(id, SnapshotCollection {
+            waiting_for: members.iter().copied().collect(),
+            nodes: Vec::new(),
+            in_flight: Vec::new(),
+            done: Some(tx),
+        });
+        // Our own marker goes through the inbox too, so the state is recorded
+        // between two handled messages
+        self.receive(self.addr, RingMessage::Marker(SnapshotMarker { initiator: self.addr, id, members: members.clone(), to: self.addr }));
+
+        let result = tokio::time::timeout(SNAPSHOT_TIMEOUT, rx).await;
+        let collection = self.snapshot_reports.lock().unwrap().remove(&id).ok_or("Snapshot vanished")?;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => return Err("Snapshot dropped".into()),
+            Err(_) => {
+                tracing::error!("Node {} timed out waiting for snapshot {} from {:?}", self.id.bold().red(), id, collection.waiting_for);
+                return Err("Timed out waiting for snapshot reports".into());
+            }
+        }
+
+        let mut nodes = collection.nodes;
+        nodes.sort_by_key(|node| members.iter().position(|m| *m == node.addr));
+        tracing::info!("Node {} snapshot {} done: {} nodes, {} messages in flight", self.id.bold().green(), id, nodes.len(), collection.in_flight.len());
+        Ok(GlobalSnapshot { initiator: self.addr, id, nodes, in_flight: collection.in_flight })
+    }
+
+    // The first marker of a snapshot records our state and sends a marker to
+    // every other member; the channel it came over is empty. Every later
+    // marker closes its channel. Once all are closed our part goes to the initiator.
+    pub fn handle_marker(self: &Arc<Self>, from: SocketAddr, marker: SnapshotMarker) {
+        let key = (marker.initiator, marker.id);
+        let finished = {
+            let mut snapshots = self.snapshots.lock().unwrap();
+            match snapshots.get_mut(&key) {
+                Some(recording) => {
+                    recording.open.remove(&from);
+                    recording.open.is_empty()
+                }
+                None => {
+                    tracing::info!("T: {}. Node {} recording state for snapshot {} of {}", self.log_time(), self.id.bold().green(), marker.id, marker.initiator);
+                    self.local_event(format!("snapshot {} of {} recorded", marker.id, marker.initiator));
+                    // Markers of snapshots that never finished, e.g. a member died
+                    snapshots.retain(|_, recording| recording.started.elapsed() < SNAPSHOT_TIMEOUT);
+                    let open: HashSet<SocketAddr> = marker.members.iter().copied()
+                        .filter(|member| *member != self.addr && *member != from)
+                        .collect();
+                    let finished = open.is_empty();
+                    snapshots.insert(key, SnapshotRecording { local: self.local_snapshot(), open, in_flight: Vec::new(), started: Instant::now() });
+                    for member in marker.members.iter().copied().filter(|member| *member != self.addr) {
+                        self.post(RingMessage::Marker(SnapshotMarker { to: member, ..marker.clone() }));
+                    }
+                    finished
+                }
+            }
+        };
+        if finished {
+            let recording = self.snapshots.lock().unwrap().remove(&key).unwrap();
+            let node = self.clone();
+            tokio::spawn(async move {
+                node.report_snapshot(marker.initiator, marker.id, recording).await;
+            });
+        }
+    }
+
+    async fn report_snapshot(&self, initiator: SocketAddr, id: u64, recording: SnapshotRecording) {
+        if initiator == self.addr {
+            self.add_snapshot_report(id, recording.local, recording.in_flight);
+            return;
+        }
+        let (client, ctx) = self.rpc.get_c(initiator).await;
+        let reported = match client {
+            Ok(client) => client.snapshot_report(ctx, id, recording.local, recording.in_flight, self.stamp_send()).await.unwrap_or(false),
+            Err(_) => false,
+        };
+        if !reported {
+            tracing::error!("Node {} could not report snapshot {} to {}", self.id.bold().red(), id, initiator);
+        }
+    }
+
+    // Initiator side, the snapshot is done once every member reported
+    pub fn add_snapshot_report(&self, id: u64, local: LocalSnapshot, in_flight: Vec<ChannelMessage>) -> bool {
+        let mut reports = self.snapshot_reports.lock().unwrap();
+        let Some(collection) = reports.get_mut(&id) else {
+            tracing::debug!("Node {} got report of unknown snapshot {}", self.id, id);
+            return false;
+        };
+        collection.waiting_for.remove(&local.addr);
+        collection.nodes.push(local);
+        collection.in_flight.extend(in_flight);
+        if collection.waiting_for.is_empty() {
+            if let Some(done) = collection.done.take() {
+                let _ = done.send(());
+            }
+        }
+        true
+    }
+
+    // A message handled after we recorded our state but before the marker of
+    // its channel was in flight at the cut
+    pub fn record_in_flight(&self, from: SocketAddr, msg: &RingMessage) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        for recording in snapshots.values_mut().filter(|r| r.open.contains(&from)) {
+            recording.in_flight.push(ChannelMessage { from, to: self.addr, message: msg.clone() });
+        }
+    }
+}
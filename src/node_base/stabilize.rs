@@ -0,0 +1,215 @@
+use std::net::SocketAddr;
+use colored::Colorize;
+use super::node::{NeighborInfo, Node};
+
+// Length of the successor list unless configured otherwise
+pub const DEFAULT_SUCCESSORS: usize = 3;
+
+impl Node {
+    // Chord-like stabilization: ask next for its prev. If that node sits
+    // between us and next (its next is our next) it becomes our next. Then
+    // nnext and the successor list are taken from next and next is told that
+    // we are its prev.
+    pub async fn stabilize_ring(&self) {
+        // Next is read under the lock, a join handled meanwhile may have changed it
+        let membership = self.membership_lock.lock().await;
+        let next = self.neighbor_info.read().unwrap().next;
+        if next == self.addr || *self.repairing.read().unwrap() {
+            return;
+        }
+
+        let Some(next_info) = self.neighbors_of(next).await else {
+            drop(membership);
+            tracing::info!("Node {} cannot reach next {} while stabilizing", self.id.bold().yellow(), next);
+            self.repair_topology(next).await;
+            return;
+        };
+        // Next left the ring, skip it
+        if next_info.next == next && next_info.prev == next {
+            {
+                let mut neighbor_info = self.neighbor_info.write().unwrap();
+                let skip_to = neighbor_info.successors.iter().copied().find(|s| *s != next).unwrap_or(neighbor_info.prev);
+                tracing::info!("Node {} next {} has left, next is now {}", self.id.bold().yellow(), next, skip_to);
+                neighbor_info.next = skip_to;
+            }
+            self.log_ring();
+            drop(membership);
+            self.announce_ring_change().await;
+            return;
+        }
+        let mut new_next = (next, next_info.next, self.successor_list(next, &next_info.successors));
+        let candidate = next_info.prev;
+        if candidate != self.addr && candidate != next {
+            if let Some(candidate_info) = self.neighbors_of(candidate).await {
+                if candidate_info.next == next {
+                    new_next = (candidate, next, self.successor_list(candidate, &candidate_info.successors));
+                }
+            }
+        }
+
+        let (changed, prev) = {
+            let mut neighbor_info = self.neighbor_info.write().unwrap();
+            let changed = neighbor_info.next != new_next.0;
+            if changed || neighbor_info.nnext != new_next.1 {
+                tracing::info!("T: {}. Node {} stabilized: next {} -> {}, nnext {} -> {}", self.log_time(), self.id.bold().yellow(),
+                    neighbor_info.next, new_next.0, neighbor_info.nnext, new_next.1);
+            }
+            neighbor_info.next = new_next.0;
+            neighbor_info.nnext = new_next.1;
+            neighbor_info.successors = new_next.2;
+            (changed, neighbor_info.prev)
+        };
+        self.log_ring();
+        // Released before notifying, next may be stabilizing towards its own next
+        drop(membership);
+
+        let (client, ctx) = self.rpc.get_c(new_next.0).await;
+        if let Ok(client) = client {
+            if let Err(e) = client.notify(ctx, self.addr, prev, self.stamp_send()).await {
+                tracing::error!("Node {} could not notify {}: {}", self.id.bold().red(), new_next.0, e);
+            }
+        }
+
+        if changed {
+            self.local_event(format!("stabilized next to {}", new_next.0));
+            self.announce_ring_change().await;
+        }
+    }
+
+    // The candidate thinks it is our prev. It is accepted if our prev is gone
+    // or no longer points at us, or if the candidate joined at the same spot
+    // as our prev did (both claim the same prev). A node that is not on a ring
+    // (it left) turns everyone away.
+    pub async fn handle_notify(&self, candidate: SocketAddr, candidate_prev: SocketAddr) -> bool {
+        let (prev, next) = {
+            let _membership = self.membership_lock.lock().await;
+            let neighbor_info = self.neighbor_info.read().unwrap();
+            (neighbor_info.prev, neighbor_info.next)
+        };
+        if prev == candidate {
+            return true;
+        }
+        if next == self.addr {
+            return false;
+        }
+        // Asked without the lock, prev may be stabilizing towards us
+        let accept = candidate_prev == prev || match self.neighbors_of(prev).await {
+            Some(prev_info) => prev_info.next != self.addr,
+            None => true,
+        };
+        if !accept {
+            return false;
+        }
+        let _membership = self.membership_lock.lock().await;
+        let mut neighbor_info = self.neighbor_info.write().unwrap();
+        // Prev changed while we were asking, the candidate notifies again
+        if neighbor_info.prev != prev {
+            return neighbor_info.prev == candidate;
+        }
+        tracing::info!("T: {}. Node {} prev {} -> {}", self.log_time(), self.id.bold().yellow(), prev, candidate);
+        neighbor_info.prev = candidate;
+        drop(neighbor_info);
+        self.log_ring();
+        true
+    }
+
+    // `first` followed by its own successors, cut where the list wraps around
+    // to us and at the configured length
+    pub fn successor_list(&self, first: SocketAddr, rest: &[SocketAddr]) -> Vec<SocketAddr> {
+        let count = *self.successor_count.read().unwrap();
+        let mut successors = Vec::new();
+        for addr in std::iter::once(first).chain(rest.iter().copied()) {
+            if addr == self.addr || successors.len() >= count {
+                break;
+            }
+            if !successors.contains(&addr) {
+                successors.push(addr);
+            }
+        }
+        successors
+    }
+
+    // At least next and nnext are always kept
+    pub fn set_successor_count(&self, count: usize) {
+        let count = count.max(2);
+        tracing::info!("Node {} keeps {} successors", self.id.bold().green(), count);
+        *self.successor_count.write().unwrap() = count;
+    }
+
+    // Walking back along prev, the last node before the gap of dead nodes
+    pub async fn first_alive_behind(&self, dead: &[SocketAddr]) -> Option<SocketAddr> {
+        let mut current = self.neighbor_info.read().unwrap().prev;
+        let limit = self.members.read().unwrap().len();
+        for _ in 0..limit {
+            if current == self.addr || dead.contains(&current) {
+                return None;
+            }
+            let info = self.neighbors_of(current).await?;
+            if info.prev == self.addr || dead.contains(&info.prev) || !self.is_alive(info.prev).await {
+                return Some(current);
+            }
+            current = info.prev;
+        }
+        None
+    }
+
+    pub async fn is_alive(&self, addr: SocketAddr) -> bool {
+        let (client, ctx) = self.rpc.get_c(addr).await;
+        match client {
+            Ok(client) => client.heartbeat(ctx, self.stamp_send()).await.is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    pub async fn neighbors_of(&self, addr: SocketAddr) -> Option<NeighborInfo> {
+        if addr == self.addr {
+            return Some(self.neighbor_info.read().unwrap().clone());
+        }
+        let (client, ctx) = self.rpc.get_c(addr).await;
+        client.ok()?.get_neighbors(ctx, self.stamp_send()).await.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn node() -> std::sync::Arc<Node> {
+        Node::new("node_2010".to_string(), addr(2010))
+    }
+
+    #[test]
+    fn successor_list_is_cut_at_the_configured_length() {
+        let node = node();
+        let list = node.successor_list(addr(2020), &[addr(2030), addr(2040), addr(2050)]);
+        assert_eq!(list, vec![addr(2020), addr(2030), addr(2040)]);
+        node.set_successor_count(4);
+        let list = node.successor_list(addr(2020), &[addr(2030), addr(2040), addr(2050)]);
+        assert_eq!(list, vec![addr(2020), addr(2030), addr(2040), addr(2050)]);
+    }
+
+    #[test]
+    fn successor_list_stops_where_it_wraps_around_to_us() {
+        let node = node();
+        assert_eq!(node.successor_list(addr(2020), &[addr(2010), addr(2020)]), vec![addr(2020)]);
+        assert_eq!(node.successor_list(addr(2010), &[addr(2020)]), Vec::<SocketAddr>::new());
+    }
+
+    #[test]
+    fn successor_list_skips_duplicates() {
+        let node = node();
+        let list = node.successor_list(addr(2020), &[addr(2020), addr(2030), addr(2030), addr(2040)]);
+        assert_eq!(list, vec![addr(2020), addr(2030), addr(2040)]);
+    }
+
+    #[test]
+    fn at_least_two_successors_are_kept() {
+        let node = node();
+        node.set_successor_count(1);
+        assert_eq!(node.successor_list(addr(2020), &[addr(2030), addr(2040)]), vec![addr(2020), addr(2030)]);
+    }
+}
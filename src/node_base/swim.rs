@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use colored::Colorize;
+use futures::future::join_all;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use super::node::Node;
+
+// One member is probed per period
+const PROTOCOL_PERIOD: Duration = Duration::from_secs(1);
+const PING_TIMEOUT: Duration = Duration::from_millis(500);
+// Members asked to ping a target that did not answer us
+const INDIRECT_PINGS: usize = 3;
+// A suspect that does not refute within this time is declared dead
+const SUSPECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Each update is piggybacked RETRANSMIT_MULT * log2(n) times, at most
+// MAX_PIGGYBACK updates per RPC
+const RETRANSMIT_MULT: usize = 3;
+const MAX_PIGGYBACK: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemberStatus {
+    Alive,
+    Suspect,
+    Dead,
+    Left,
+}
+
+// Gossip entry, carried in RpcHeader
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct MemberUpdate {
+    pub addr: SocketAddr,
+    pub status: MemberStatus,
+    pub incarnation: u64,
+}
+
+impl MemberUpdate {
+    // Alive needs a newer incarnation, suspect wins over alive of the same
+    // incarnation and dead or left over everything of the same incarnation
+    fn overrides(&self, status: MemberStatus, incarnation: u64) -> bool {
+        use MemberStatus::*;
+        match (self.status, status) {
+            (_, Dead | Left) => self.incarnation > incarnation,
+            (Alive, _) => self.incarnation > incarnation,
+            (Suspect, Alive) => self.incarnation >= incarnation,
+            (Suspect, Suspect) => self.incarnation > incarnation,
+            (Dead | Left, _) => self.incarnation >= incarnation,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MemberView {
+    pub addr: SocketAddr,
+    pub status: MemberStatus,
+    pub incarnation: u64,
+    // Time since the status last changed
+    pub since_ms: u128,
+}
+
+#[derive(Debug)]
+struct MemberEntry {
+    status: MemberStatus,
+    incarnation: u64,
+    since: Instant,
+}
+
+#[derive(Debug)]
+pub struct SwimState {
+    addr: SocketAddr,
+    members: HashMap<SocketAddr, MemberEntry>,
+    // Updates still to be piggybacked, with how often each was sent
+    pending: Vec<(MemberUpdate, usize)>,
+    // Probe order, refilled and shuffled once every member was probed
+    probe_order: Vec<SocketAddr>,
+}
+
+impl SwimState {
+    pub fn new(addr: SocketAddr) -> Self {
+        let mut members = HashMap::new();
+        members.insert(addr, MemberEntry { status: MemberStatus::Alive, incarnation: 0, since: Instant::now() });
+        Self { addr, members, pending: Vec::new(), probe_order: Vec::new() }
+    }
+
+    // Forgets every other member but keeps our incarnation, which must only grow
+    pub fn restart(&mut self) {
+        let incarnation = self.incarnation();
+        *self = Self::new(self.addr);
+        self.members.get_mut(&self.addr).unwrap().incarnation = incarnation;
+    }
+
+    // Continues from the incarnation a restarted process read from disk
+    pub fn resume(&mut self, incarnation: u64) {
+        let own = self.members.get_mut(&self.addr).unwrap();
+        own.incarnation = own.incarnation.max(incarnation);
+    }
+
+    pub fn incarnation(&self) -> u64 {
+        self.members[&self.addr].incarnation
+    }
+
+    // Returns true if the update changed our table
+    fn apply(&mut self, update: MemberUpdate) -> bool {
+        if update.addr == self.addr {
+            // Refute suspicion, or a death we did not die, with a newer incarnation
+            let own = &self.members[&self.addr];
+            if update.status == MemberStatus::Alive || own.status == MemberStatus::Left || update.incarnation < own.incarnation {
+                return false;
+            }
+            self.set(self.addr, MemberStatus::Alive, update.incarnation + 1);
+            return true;
+        }
+        let changed = match self.members.get(&update.addr) {
+            Some(entry) => update.overrides(entry.status, entry.incarnation),
+            None => true,
+        };
+        if changed {
+            self.set(update.addr, update.status, update.incarnation);
+        }
+        changed
+    }
+
+    fn set(&mut self, addr: SocketAddr, status: MemberStatus, incarnation: u64) {
+        self.members.insert(addr, MemberEntry { status, incarnation, since: Instant::now() });
+        self.pending.retain(|(pending, _)| pending.addr != addr);
+        self.pending.push((MemberUpdate { addr, status, incarnation }, 0));
+    }
+
+    // Least sent updates first, dropped once sent often enough
+    fn piggyback(&mut self) -> Vec<MemberUpdate> {
+        let limit = RETRANSMIT_MULT * (usize::BITS - self.members.len().leading_zeros()) as usize;
+        self.pending.sort_by_key(|(_, sent)| *sent);
+        let updates = self.pending.iter_mut().take(MAX_PIGGYBACK).map(|(update, sent)| {
+            *sent += 1;
+            *update
+        }).collect();
+        self.pending.retain(|(_, sent)| *sent < limit);
+        updates
+    }
+
+    fn is_live(&self, addr: &SocketAddr) -> bool {
+        matches!(self.members.get(addr).map(|e| e.status), Some(MemberStatus::Alive | MemberStatus::Suspect))
+    }
+
+    fn next_probe_target(&mut self) -> Option<SocketAddr> {
+        if self.probe_order.is_empty() {
+            self.probe_order = self.members.keys().copied().filter(|a| *a != self.addr && self.is_live(a)).collect();
+            self.probe_order.shuffle(&mut rand::thread_rng());
+        }
+        while let Some(addr) = self.probe_order.pop() {
+            if self.is_live(&addr) {
+                return Some(addr);
+            }
+        }
+        None
+    }
+
+    fn random_live(&self, exclude: &[SocketAddr], count: usize) -> Vec<SocketAddr> {
+        let mut live: Vec<SocketAddr> = self.members.keys().copied()
+            .filter(|a| *a != self.addr && !exclude.contains(a) && self.is_live(a))
+            .collect();
+        live.shuffle(&mut rand::thread_rng());
+        live.truncate(count);
+        live
+    }
+}
+
+impl Node {
+    pub fn apply_gossip(&self, updates: &[MemberUpdate]) {
+        if updates.is_empty() {
+            return;
+        }
+        {
+            let mut swim = self.swim.write().unwrap();
+            for update in updates {
+                if swim.apply(*update) {
+                    tracing::debug!("Node {} member {} is {:?} (incarnation {})", self.id, update.addr, update.status, update.incarnation);
+                }
+            }
+        }
+        // A refutation raised our incarnation
+        self.log_clock();
+    }
+
+    pub fn gossip_to_send(&self) -> Vec<MemberUpdate> {
+        self.swim.write().unwrap().piggyback()
+    }
+
+    // Alive or suspected members, the member list other algorithms can use
+    pub fn live_members(&self) -> Vec<SocketAddr> {
+        let swim = self.swim.read().unwrap();
+        let mut live: Vec<SocketAddr> = swim.members.keys().copied().filter(|a| swim.is_live(a)).collect();
+        live.sort();
+        live
+    }
+
+    pub fn cluster_members(&self) -> Vec<MemberView> {
+        let swim = self.swim.read().unwrap();
+        let mut members: Vec<MemberView> = swim.members.iter().map(|(addr, entry)| MemberView {
+            addr: *addr,
+            status: entry.status,
+            incarnation: entry.incarnation,
+            since_ms: entry.since.elapsed().as_millis(),
+        }).collect();
+        members.sort_by_key(|m| m.addr);
+        members
+    }
+
+    // Announced with the leave RPCs
+    pub fn swim_leave(&self) {
+        let mut swim = self.swim.write().unwrap();
+        let incarnation = swim.incarnation();
+        swim.set(self.addr, MemberStatus::Left, incarnation);
+    }
+
+    // Back on a ring after leaving or being declared dead
+    pub fn swim_rejoin(&self) {
+        {
+            let mut swim = self.swim.write().unwrap();
+            let incarnation = swim.incarnation() + 1;
+            swim.set(self.addr, MemberStatus::Alive, incarnation);
+            tracing::info!("Node {} joined with incarnation {}", self.id.bold().green(), incarnation);
+        }
+        self.log_clock();
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> bool {
+        tokio::time::timeout(PING_TIMEOUT, self.is_alive(addr)).await.unwrap_or(false)
+    }
+
+    // Ring neighbors and the last collected ring are members even if no
+    // gossip about them reached us yet
+    fn learn_ring(&self) {
+        let known: Vec<SocketAddr> = {
+            let neighbor_info = self.neighbor_info.read().unwrap();
+            [neighbor_info.next, neighbor_info.nnext, neighbor_info.prev].into_iter()
+                .chain(neighbor_info.successors.iter().copied())
+                .chain(self.members.read().unwrap().iter().copied())
+                .collect()
+        };
+        let mut swim = self.swim.write().unwrap();
+        for addr in known {
+            if !swim.members.contains_key(&addr) {
+                swim.set(addr, MemberStatus::Alive, 0);
+            }
+        }
+    }
+
+    fn expire_suspects(&self) {
+        let mut swim = self.swim.write().unwrap();
+        let expired: Vec<(SocketAddr, u64)> = swim.members.iter()
+            .filter(|(_, e)| e.status == MemberStatus::Suspect && e.since.elapsed() >= SUSPECT_TIMEOUT)
+            .map(|(addr, e)| (*addr, e.incarnation))
+            .collect();
+        for (addr, incarnation) in expired {
+            tracing::info!("T: {}. Node {} declares {} dead", self.log_time(), self.id.bold().red(), addr);
+            swim.set(addr, MemberStatus::Dead, incarnation);
+        }
+    }
+
+    // One protocol period: ping a member, if it does not answer ask nnext and
+    // random members to ping it, if none of them gets an answer suspect it
+    pub async fn swim_round(&self) {
+        if self.neighbor_info.read().unwrap().next == self.addr {
+            return;
+        }
+        self.learn_ring();
+        self.expire_suspects();
+        let Some(target) = self.swim.write().unwrap().next_probe_target() else {
+            return;
+        };
+        if self.ping(target).await {
+            return;
+        }
+
+        let helpers = {
+            let nnext = self.neighbor_info.read().unwrap().nnext;
+            let swim = self.swim.read().unwrap();
+            let mut helpers: Vec<SocketAddr> = Vec::new();
+            if nnext != self.addr && nnext != target && swim.is_live(&nnext) {
+                helpers.push(nnext);
+            }
+            let mut exclude = helpers.clone();
+            exclude.push(target);
+            helpers.extend(swim.random_live(&exclude, INDIRECT_PINGS - helpers.len()));
+            helpers
+        };
+        let acks = join_all(helpers.into_iter().map(|helper| async move {
+            let (client, ctx) = self.rpc.get_c(helper).await;
+            let Ok(client) = client else {
+                return false;
+            };
+            let ping = client.ping_req(ctx, target, self.stamp_send());
+            matches!(tokio::time::timeout(PING_TIMEOUT * 2, ping).await, Ok(Ok(true)))
+        })).await;
+        if acks.contains(&true) {
+            return;
+        }
+
+        let mut swim = self.swim.write().unwrap();
+        if let Some(entry) = swim.members.get(&target) {
+            if entry.status == MemberStatus::Alive {
+                let incarnation = entry.incarnation;
+                tracing::info!("T: {}. Node {} suspects {}", self.log_time(), self.id.bold().yellow(), target);
+                swim.set(target, MemberStatus::Suspect, incarnation);
+            }
+        }
+    }
+}
+
+pub async fn run_swim(node: Arc<Node>) {
+    loop {
+        tokio::time::sleep(PROTOCOL_PERIOD).await;
+        node.wait_until_up().await;
+        node.swim_round().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MemberStatus::*;
+
+    fn update(status: MemberStatus, incarnation: u64) -> MemberUpdate {
+        MemberUpdate { addr: SocketAddr::from(([127, 0, 0, 1], 2010)), status, incarnation }
+    }
+
+    #[test]
+    fn alive_needs_a_newer_incarnation() {
+        for known in [Alive, Suspect, Dead, Left] {
+            assert!(!update(Alive, 3).overrides(known, 3), "alive over {:?}", known);
+            assert!(update(Alive, 4).overrides(known, 3), "alive over {:?}", known);
+        }
+    }
+
+    #[test]
+    fn suspect_wins_over_alive_of_the_same_incarnation() {
+        assert!(update(Suspect, 3).overrides(Alive, 3));
+        assert!(!update(Suspect, 2).overrides(Alive, 3));
+        assert!(!update(Suspect, 3).overrides(Suspect, 3));
+        assert!(update(Suspect, 4).overrides(Suspect, 3));
+    }
+
+    #[test]
+    fn dead_and_left_win_over_everything_of_the_same_incarnation() {
+        for status in [Dead, Left] {
+            assert!(update(status, 3).overrides(Alive, 3));
+            assert!(update(status, 3).overrides(Suspect, 3));
+            assert!(!update(status, 2).overrides(Suspect, 3));
+            assert!(!update(status, 3).overrides(Dead, 3));
+            assert!(!update(status, 3).overrides(Left, 3));
+            assert!(update(status, 4).overrides(Dead, 3));
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::net::SocketAddr;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use super::messaging::RingMessage;
+use super::node::Node;
+
+// Safra's token, it sums up the message counters of the nodes it passes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TerminationToken {
+    pub initiator: SocketAddr,
+    pub round: u64,
+    pub count: i64,
+    pub black: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TerminationReport {
+    pub initiator: SocketAddr,
+    pub round: u64,
+    pub lamport: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TerminationMessage {
+    Token(TerminationToken),
+    // Goes once around the ring after termination was detected
+    Announce(TerminationReport),
+}
+
+#[derive(Debug, Default)]
+pub struct TerminationState {
+    // Basic messages sent minus received
+    pub counter: i64,
+    pub black: bool,
+    // Token waiting here until the node is passive
+    pub token: Option<TerminationToken>,
+    pub detected: Option<TerminationReport>,
+}
+
+impl Node {
+    // Basic computation messages are the permissions that wake up waiting nodes
+    pub fn basic_message_sent(&self) {
+        self.termination.write().unwrap().counter += 1;
+    }
+
+    pub fn basic_message_received(&self) {
+        let mut termination = self.termination.write().unwrap();
+        termination.counter -= 1;
+        termination.black = true;
+    }
+
+    pub fn start_termination_detection(&self) {
+        tracing::info!("T: {}. Node {} starting termination detection", self.log_time(), self.id.bold().green());
+        let mut termination = self.termination.write().unwrap();
+        termination.detected = None;
+        // Handled like a failed round coming back, so the first real round starts once we are passive
+        termination.token = Some(TerminationToken { initiator: self.addr, round: 0, count: 0, black: true });
+        drop(termination);
+        self.release_termination_token();
+    }
+
+    pub fn handle_termination(&self, msg: TerminationMessage) {
+        match msg {
+            TerminationMessage::Token(token) => {
+                tracing::debug!("Node {} got termination token {:?}", self.id, token);
+                self.termination.write().unwrap().token = Some(token);
+                self.release_termination_token();
+            }
+            TerminationMessage::Announce(report) => {
+                if report.initiator == self.addr {
+                    return;
+                }
+                tracing::info!("T: {}. Node {} learned about global termination from {}", self.log_time(), self.id.bold().green(), report.initiator);
+                self.local_event(format!("global termination detected by {}", report.initiator));
+                self.termination.write().unwrap().detected = Some(report.clone());
+                self.post(RingMessage::Termination(TerminationMessage::Announce(report)));
+            }
+        }
+    }
+
+    // Pass the token on, but only once this node is passive
+    pub fn release_termination_token(&self) {
+        if *self.is_active.read().unwrap() {
+            return;
+        }
+        let mut termination = self.termination.write().unwrap();
+        let Some(mut token) = termination.token.take() else {
+            return;
+        };
+
+        if token.initiator != self.addr {
+            token.count += termination.counter;
+            token.black |= termination.black;
+            termination.black = false;
+            self.post(RingMessage::Termination(TerminationMessage::Token(token)));
+            return;
+        }
+
+        if !token.black && !termination.black && token.count + termination.counter == 0 {
+            let report = TerminationReport { initiator: self.addr, round: token.round, lamport: *self.lamport_time.read().unwrap() };
+            tracing::info!("T: {}. Node {} detected global termination in round {}", self.log_time(), self.id.bold().green(), token.round);
+            self.local_event(format!("global termination detected in round {}", token.round));
+            termination.detected = Some(report.clone());
+            if self.neighbor_info.read().unwrap().next != self.addr {
+                self.post(RingMessage::Termination(TerminationMessage::Announce(report)));
+            }
+            return;
+        }
+
+        termination.black = false;
+        let round = TerminationToken { initiator: self.addr, round: token.round + 1, count: 0, black: false };
+        tracing::debug!("Node {} starting termination round {}", self.id, round.round);
+        if self.neighbor_info.read().unwrap().next == self.addr {
+            // Nobody else to ask, we only need our own counter
+            let settled = termination.counter == 0;
+            termination.token = Some(round);
+            drop(termination);
+            if settled {
+                self.release_termination_token();
+            }
+        } else {
+            self.post(RingMessage::Termination(TerminationMessage::Token(round)));
+        }
+    }
+}
@@ -0,0 +1,405 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use super::messaging::RingMessage;
+use super::node::Node;
+
+// How often the sequencer task runs, the token is held at most this long
+const SEQUENCER_TICK: Duration = Duration::from_millis(200);
+// How long a hole in the sequence is tolerated before asking for a retransmit
+const GAP_TIMEOUT: Duration = Duration::from_secs(2);
+// Delivered messages kept around to answer retransmit requests
+const HISTORY_SIZE: usize = 1024;
+
+// Update of the replicated key-value state, `None` deletes the key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotalOrderCommand {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+// Message with its global sequence number. A message that could not be
+// recovered by anyone is delivered as a gap without command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SequencedMessage {
+    pub seq: u64,
+    pub origin: SocketAddr,
+    pub command: Option<TotalOrderCommand>,
+}
+
+// Whoever holds the token assigns the next sequence numbers. A regenerated
+// token first makes a recovery round to learn the highest number in use.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SequencerToken {
+    pub epoch: u64,
+    pub next_seq: u64,
+    pub recovering: bool,
+    pub origin: SocketAddr,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TotalOrderMessage {
+    Token(SequencerToken),
+    Sequenced(SequencedMessage),
+    // Goes around the ring until someone has the missing messages
+    Retransmit(SocketAddr, Vec<u64>),
+    Resend(SocketAddr, Vec<SequencedMessage>),
+    // A node that just joined asks for the current state
+    SyncRequest(SocketAddr),
+    SyncReply(SocketAddr, u64, BTreeMap<String, String>),
+}
+
+#[derive(Debug)]
+pub struct TotalOrderState {
+    pub epoch: u64,
+    pub token: Option<SequencerToken>,
+    pub token_passed_to: Option<SocketAddr>,
+    pub synced: bool,
+    pub outgoing: VecDeque<TotalOrderCommand>,
+    pub delivered_upto: u64,
+    pub buffer: BTreeMap<u64, SequencedMessage>,
+    pub gap_since: Option<Instant>,
+    pub history: VecDeque<SequencedMessage>,
+    pub state: BTreeMap<String, String>,
+}
+
+impl TotalOrderState {
+    // Every node starts as a ring of its own, holding its own token
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            epoch: 0,
+            token: Some(SequencerToken { epoch: 0, next_seq: 1, recovering: false, origin: addr }),
+            token_passed_to: None,
+            synced: true,
+            outgoing: VecDeque::new(),
+            delivered_upto: 0,
+            buffer: BTreeMap::new(),
+            gap_since: None,
+            history: VecDeque::new(),
+            state: BTreeMap::new(),
+        }
+    }
+
+    fn highest_seen(&self) -> u64 {
+        self.buffer.keys().next_back().copied().unwrap_or(0).max(self.delivered_upto)
+    }
+
+    // Sequence numbers below `upto` we neither delivered nor buffered
+    fn missing_below(&self, upto: u64) -> Vec<u64> {
+        (self.delivered_upto + 1..upto).filter(|seq| !self.buffer.contains_key(seq)).collect()
+    }
+
+    fn find(&self, seq: u64) -> Option<SequencedMessage> {
+        self.buffer.get(&seq).cloned()
+            .or_else(|| self.history.iter().find(|m| m.seq == seq).cloned())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotalOrderSnapshot {
+    pub delivered_upto: u64,
+    pub synced: bool,
+    pub state: BTreeMap<String, String>,
+}
+
+impl Node {
+    // Queue a command, it gets its sequence number when the token comes by
+    pub fn total_order_broadcast(&self, command: TotalOrderCommand) {
+        tracing::info!("T: {}. Node {} queueing total order update {:?}", self.log_time(), self.id.bold().green(), command);
+        let mut tob = self.total_order.write().unwrap();
+        tob.outgoing.push_back(command);
+        self.sequence_outgoing(&mut tob);
+    }
+
+    pub fn total_order_snapshot(&self) -> TotalOrderSnapshot {
+        let tob = self.total_order.read().unwrap();
+        TotalOrderSnapshot { delivered_upto: tob.delivered_upto, synced: tob.synced, state: tob.state.clone() }
+    }
+
+    pub fn total_order_delivered(&self) -> Vec<SequencedMessage> {
+        self.total_order.read().unwrap().history.iter().cloned().collect()
+    }
+
+    // Assign numbers to queued commands if we hold the token
+    fn sequence_outgoing(&self, tob: &mut TotalOrderState) {
+        let Some(token) = tob.token.as_mut() else {
+            return;
+        };
+        if token.recovering {
+            return;
+        }
+        let mut sequenced = Vec::new();
+        while let Some(command) = tob.outgoing.pop_front() {
+            sequenced.push(SequencedMessage { seq: token.next_seq, origin: self.addr, command: Some(command) });
+            token.next_seq += 1;
+        }
+
+        let alone = self.neighbor_info.read().unwrap().next == self.addr;
+        for msg in sequenced {
+            tracing::info!("T: {}. Node {} sequenced update {:?} as {}", self.log_time(), self.id.bold().green(), msg.command, msg.seq);
+            if !alone {
+                self.post(RingMessage::TotalOrder(TotalOrderMessage::Sequenced(msg.clone())));
+            }
+            tob.buffer.insert(msg.seq, msg);
+        }
+        self.deliver_in_order(tob);
+    }
+
+    pub fn handle_total_order(&self, msg: TotalOrderMessage) {
+        let next = self.neighbor_info.read().unwrap().next;
+        let mut tob = self.total_order.write().unwrap();
+        match msg {
+            TotalOrderMessage::Token(token) => self.receive_token(&mut tob, token),
+            TotalOrderMessage::Sequenced(msg) => {
+                // Duplicates are not passed on, so a message never circles forever
+                if msg.seq <= tob.delivered_upto || tob.buffer.contains_key(&msg.seq) {
+                    return;
+                }
+                if next != msg.origin {
+                    self.post(RingMessage::TotalOrder(TotalOrderMessage::Sequenced(msg.clone())));
+                }
+                tob.buffer.insert(msg.seq, msg);
+                self.deliver_in_order(&mut tob);
+            }
+            TotalOrderMessage::Retransmit(requester, seqs) => {
+                if requester == self.addr {
+                    // Nobody has them, so every node is missing them: skip them everywhere
+                    let delivered_upto = tob.delivered_upto;
+                    for seq in seqs.into_iter().filter(|seq| *seq > delivered_upto) {
+                        tracing::warn!("Node {} could not recover update {}, skipping it", self.id.bold().red(), seq);
+                        tob.buffer.entry(seq).or_insert(SequencedMessage { seq, origin: self.addr, command: None });
+                    }
+                    self.deliver_in_order(&mut tob);
+                    return;
+                }
+                let (found, missing): (Vec<_>, Vec<_>) = seqs.into_iter().partition(|seq| tob.find(*seq).is_some());
+                if !found.is_empty() {
+                    let messages = found.iter().filter_map(|seq| tob.find(*seq)).collect();
+                    tracing::info!("Node {} resending updates {:?} to {}", self.id.bold().green(), found, requester);
+                    self.post(RingMessage::TotalOrder(TotalOrderMessage::Resend(requester, messages)));
+                }
+                if !missing.is_empty() {
+                    self.post(RingMessage::TotalOrder(TotalOrderMessage::Retransmit(requester, missing)));
+                }
+            }
+            TotalOrderMessage::Resend(to, messages) => {
+                if to != self.addr {
+                    self.post(RingMessage::TotalOrder(TotalOrderMessage::Resend(to, messages)));
+                    return;
+                }
+                for msg in messages {
+                    if msg.seq > tob.delivered_upto {
+                        tob.buffer.insert(msg.seq, msg);
+                    }
+                }
+                self.deliver_in_order(&mut tob);
+            }
+            TotalOrderMessage::SyncRequest(requester) => {
+                if requester == self.addr {
+                    tracing::info!("Node {} found nobody to sync with", self.id.bold().yellow());
+                    tob.synced = true;
+                    self.deliver_in_order(&mut tob);
+                } else if tob.synced {
+                    let reply = TotalOrderMessage::SyncReply(requester, tob.delivered_upto, tob.state.clone());
+                    self.post(RingMessage::TotalOrder(reply));
+                } else {
+                    self.post(RingMessage::TotalOrder(TotalOrderMessage::SyncRequest(requester)));
+                }
+            }
+            TotalOrderMessage::SyncReply(to, delivered_upto, state) => {
+                if to != self.addr {
+                    self.post(RingMessage::TotalOrder(TotalOrderMessage::SyncReply(to, delivered_upto, state)));
+                    return;
+                }
+                if tob.synced {
+                    return;
+                }
+                tracing::info!("Node {} synced total order state up to {}", self.id.bold().green(), delivered_upto);
+                tob.delivered_upto = delivered_upto;
+                tob.state = state;
+                tob.buffer.retain(|seq, _| *seq > delivered_upto);
+                tob.synced = true;
+                self.deliver_in_order(&mut tob);
+            }
+        }
+    }
+
+    fn receive_token(&self, tob: &mut TotalOrderState, mut token: SequencerToken) {
+        if token.epoch < tob.epoch {
+            tracing::info!("Node {} dropping stale sequencer token of epoch {}", self.id.bold().yellow(), token.epoch);
+            return;
+        }
+        if token.epoch > tob.epoch {
+            tob.epoch = token.epoch;
+            tob.token = None;
+        }
+        tob.token_passed_to = None;
+
+        // Anything numbered below the token's next number exists somewhere
+        let missing = tob.missing_below(token.next_seq);
+        if !missing.is_empty() {
+            self.request_retransmit(tob, missing);
+        }
+
+        if token.recovering {
+            token.next_seq = token.next_seq.max(tob.highest_seen() + 1);
+            if token.origin != self.addr {
+                self.post(RingMessage::TotalOrder(TotalOrderMessage::Token(token)));
+                return;
+            }
+            tracing::info!("Node {} sequencer token regenerated, epoch {}, next {}", self.id.bold().green(), token.epoch, token.next_seq);
+            token.recovering = false;
+        }
+        tob.token = Some(token);
+        self.sequence_outgoing(tob);
+    }
+
+    fn request_retransmit(&self, tob: &mut TotalOrderState, missing: Vec<u64>) {
+        tracing::info!("Node {} missing updates {:?}, asking for retransmit", self.id.bold().yellow(), missing);
+        tob.gap_since = Some(Instant::now());
+        if self.neighbor_info.read().unwrap().next == self.addr {
+            return;
+        }
+        self.post(RingMessage::TotalOrder(TotalOrderMessage::Retransmit(self.addr, missing)));
+    }
+
+    fn deliver_in_order(&self, tob: &mut TotalOrderState) {
+        if !tob.synced {
+            return;
+        }
+        let first = tob.delivered_upto;
+        while let Some(msg) = tob.buffer.remove(&(tob.delivered_upto + 1)) {
+            tob.delivered_upto = msg.seq;
+            match &msg.command {
+                Some(command) => {
+                    tracing::info!("T: {}. Node {} delivered update {}: {:?}", self.log_time(), self.id.bold().green(), msg.seq, command);
+                    match &command.value {
+                        Some(value) => tob.state.insert(command.key.clone(), value.clone()),
+                        None => tob.state.remove(&command.key),
+                    };
+                }
+                None => tracing::warn!("Node {} skipped lost update {}", self.id.bold().red(), msg.seq),
+            }
+            if tob.history.len() == HISTORY_SIZE {
+                tob.history.pop_front();
+            }
+            tob.history.push_back(msg);
+        }
+        if tob.delivered_upto > first {
+            self.local_event(format!("total order delivered up to {}", tob.delivered_upto));
+        }
+
+        if tob.buffer.is_empty() {
+            tob.gap_since = None;
+        } else if tob.gap_since.is_none() {
+            tob.gap_since = Some(Instant::now());
+        }
+    }
+
+    // Forget the old ring's order and take over the one of the ring we joined
+    pub fn reset_total_order(&self) {
+        let mut tob = self.total_order.write().unwrap();
+        let outgoing = std::mem::take(&mut tob.outgoing);
+        *tob = TotalOrderState::new(self.addr);
+        tob.token = None;
+        tob.synced = false;
+        tob.outgoing = outgoing;
+        self.post(RingMessage::TotalOrder(TotalOrderMessage::SyncRequest(self.addr)));
+    }
+
+    // Our ring was merged into one with its own order: drop ours, take no
+    // token from before the merge and sync with the other side
+    pub fn yield_total_order(&self, epoch: u64) {
+        self.reset_total_order();
+        let mut tob = self.total_order.write().unwrap();
+        tob.epoch = tob.epoch.max(epoch);
+    }
+
+    // Starts a new epoch after a merge, its recovery round replaces the
+    // tokens both rings had
+    pub fn restart_sequencer(&self, epoch: u64) {
+        let mut tob = self.total_order.write().unwrap();
+        tob.epoch = epoch;
+        tob.token_passed_to = None;
+        tob.token = None;
+        let token = SequencerToken { epoch, next_seq: tob.highest_seen() + 1, recovering: true, origin: self.addr };
+        tracing::info!("Node {} restarting the sequencer after a merge, epoch {}", self.id.bold().yellow(), epoch);
+        self.post(RingMessage::TotalOrder(TotalOrderMessage::Token(token)));
+    }
+
+    pub fn total_order_epoch(&self) -> u64 {
+        self.total_order.read().unwrap().epoch
+    }
+
+    // Called after repairing around a missing node that may have taken the token with it
+    pub fn regenerate_token(&self, missing_node: SocketAddr) {
+        let mut tob = self.total_order.write().unwrap();
+        if tob.token_passed_to != Some(missing_node) {
+            return;
+        }
+        tob.epoch += 1;
+        tob.token_passed_to = None;
+        tob.token = None;
+        let mut token = SequencerToken { epoch: tob.epoch, next_seq: tob.highest_seen() + 1, recovering: true, origin: self.addr };
+        tracing::info!("Node {} regenerating sequencer token lost with {}, epoch {}", self.id.bold().yellow(), missing_node, token.epoch);
+        if self.neighbor_info.read().unwrap().next == self.addr {
+            token.recovering = false;
+            tob.token = Some(token);
+        } else {
+            self.post(RingMessage::TotalOrder(TotalOrderMessage::Token(token)));
+        }
+    }
+
+    // Leaving nodes pass the token on instead of taking it with them
+    pub async fn hand_off_token(&self) {
+        let next = self.neighbor_info.read().unwrap().next;
+        let Some(token) = self.total_order.write().unwrap().token.take() else {
+            return;
+        };
+        if next != self.addr {
+            self.try_deliver(next, &RingMessage::TotalOrder(TotalOrderMessage::Token(token))).await;
+        }
+    }
+
+    fn pass_token(&self) {
+        let next = self.neighbor_info.read().unwrap().next;
+        let mut tob = self.total_order.write().unwrap();
+        self.sequence_outgoing(&mut tob);
+        if next == self.addr {
+            return;
+        }
+        if let Some(token) = tob.token.take() {
+            tob.token_passed_to = Some(next);
+            self.post(RingMessage::TotalOrder(TotalOrderMessage::Token(token)));
+        }
+    }
+
+    fn check_gaps(&self) {
+        let mut tob = self.total_order.write().unwrap();
+        let Some(since) = tob.gap_since else {
+            return;
+        };
+        if since.elapsed() < GAP_TIMEOUT || !tob.synced {
+            return;
+        }
+        let missing = tob.missing_below(tob.highest_seen());
+        if missing.is_empty() {
+            tob.gap_since = None;
+        } else {
+            self.request_retransmit(&mut tob, missing);
+        }
+    }
+}
+
+// Passes the sequencer token along and looks for holes in the delivered sequence
+pub async fn run_sequencer(node: Arc<Node>) {
+    loop {
+        tokio::time::sleep(SEQUENCER_TICK).await;
+        node.wait_until_up().await;
+        node.pass_token();
+        node.check_gaps();
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use super::node::Node;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EdgeKind {
+    // waitForMessage dependency
+    Message,
+    // Queued behind the current user of a resource (seen from the owner)
+    Queue(String),
+    // Waiting for the owner to grant a resource. The owner only keeps the
+    // queue, so this edge is shown but left out of cycle detection.
+    Request(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WaitForEdge {
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+    pub kind: EdgeKind,
+}
+
+// Wait-for graph assembled at the initiator by walking the ring
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WaitForGraph {
+    pub nodes: Vec<SocketAddr>,
+    pub edges: Vec<WaitForEdge>,
+    pub cycles: Vec<Vec<SocketAddr>>,
+}
+
+impl WaitForGraph {
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        self.nodes.contains(&addr)
+    }
+
+    // Every strongly connected component with a cycle in it, found with Tarjan's algorithm
+    pub fn find_cycles(&mut self) {
+        let mut vertices: BTreeSet<SocketAddr> = self.nodes.iter().cloned().collect();
+        let mut adjacency: HashMap<SocketAddr, BTreeSet<SocketAddr>> = HashMap::new();
+        for edge in self.edges.iter().filter(|e| !matches!(e.kind, EdgeKind::Request(_))) {
+            vertices.insert(edge.from);
+            vertices.insert(edge.to);
+            adjacency.entry(edge.from).or_default().insert(edge.to);
+        }
+
+        let mut tarjan = Tarjan {
+            adjacency: &adjacency,
+            index: 0,
+            indices: HashMap::new(),
+            low_link: HashMap::new(),
+            stack: Vec::new(),
+            on_stack: BTreeSet::new(),
+            components: Vec::new(),
+        };
+        for v in vertices {
+            if !tarjan.indices.contains_key(&v) {
+                tarjan.connect(v);
+            }
+        }
+
+        self.cycles = tarjan.components.into_iter()
+            .filter(|c| c.len() > 1 || adjacency.get(&c[0]).is_some_and(|n| n.contains(&c[0])))
+            .map(|mut c| { c.sort(); c })
+            .collect();
+    }
+
+    pub fn to_dot(&self) -> String {
+        let in_cycle: BTreeSet<SocketAddr> = self.cycles.iter().flatten().cloned().collect();
+        let mut dot = String::from("digraph waitfor {\n");
+        for node in &self.nodes {
+            let color = if in_cycle.contains(node) { "red" } else { "black" };
+            let _ = writeln!(dot, "    \"{}\" [color={}];", node, color);
+        }
+        for edge in &self.edges {
+            let (label, style) = match &edge.kind {
+                EdgeKind::Message => ("msg".to_string(), "solid"),
+                EdgeKind::Queue(r) => (format!("queue {}", r), "solid"),
+                EdgeKind::Request(r) => (format!("request {}", r), "dashed"),
+            };
+            let _ = writeln!(dot, "    \"{}\" -> \"{}\" [label=\"{}\", style={}];", edge.from, edge.to, label, style);
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+struct Tarjan<'a> {
+    adjacency: &'a HashMap<SocketAddr, BTreeSet<SocketAddr>>,
+    index: usize,
+    indices: HashMap<SocketAddr, usize>,
+    low_link: HashMap<SocketAddr, usize>,
+    stack: Vec<SocketAddr>,
+    on_stack: BTreeSet<SocketAddr>,
+    components: Vec<Vec<SocketAddr>>,
+}
+
+impl Tarjan<'_> {
+    fn connect(&mut self, v: SocketAddr) {
+        self.indices.insert(v, self.index);
+        self.low_link.insert(v, self.index);
+        self.index += 1;
+        self.stack.push(v);
+        self.on_stack.insert(v);
+
+        let adjacency = self.adjacency;
+        if let Some(neighbors) = adjacency.get(&v) {
+            for &w in neighbors {
+                if !self.indices.contains_key(&w) {
+                    self.connect(w);
+                    let low = std::cmp::min(self.low_link[&v], self.low_link[&w]);
+                    self.low_link.insert(v, low);
+                } else if self.on_stack.contains(&w) {
+                    let low = std::cmp::min(self.low_link[&v], self.indices[&w]);
+                    self.low_link.insert(v, low);
+                }
+            }
+        }
+
+        if self.low_link[&v] == self.indices[&v] {
+            let mut component = Vec::new();
+            while let Some(w) = self.stack.pop() {
+                self.on_stack.remove(&w);
+                component.push(w);
+                if w == v {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
+
+impl Node {
+    // Edges of the wait-for graph this node knows about
+    pub fn local_wait_for_edges(&self) -> Vec<WaitForEdge> {
+        let mut edges = Vec::new();
+        for from in self.waiting_messages_from.read().unwrap().iter() {
+            edges.push(WaitForEdge { from: self.addr, to: *from, kind: EdgeKind::Message });
+        }
+        for (resource, owner) in self.waiting_for.read().unwrap().iter() {
+            edges.push(WaitForEdge { from: self.addr, to: *owner, kind: EdgeKind::Request(resource.clone()) });
+        }
+        // A queued request waits for the current user, not for the owner that
+        // keeps the queue; the owner reports that edge
+        for (resource, state) in self.owned_resources.read().unwrap().iter() {
+            if let Some(user) = state.current_user {
+                for queued in &state.request_queue {
+                    edges.push(WaitForEdge { from: *queued, to: user, kind: EdgeKind::Queue(resource.clone()) });
+                }
+            }
+        }
+        edges
+    }
+
+    // Add our edges to the graph and pass it on until it is back at the initiator
+    pub async fn extend_wait_for_graph(&self, initiator: SocketAddr, mut graph: WaitForGraph) -> Result<WaitForGraph, Box<dyn Error>> {
+        if graph.contains(self.addr) {
+            return Ok(graph);
+        }
+        graph.nodes.push(self.addr);
+        graph.edges.extend(self.local_wait_for_edges());
+
+        let next = self.neighbor_info.read().unwrap().next;
+        if next == initiator || graph.contains(next) {
+            return Ok(graph);
+        }
+
+        let (client, ctx) = self.rpc.get_c(next).await;
+        let client = client.map_err(|_| format!("Cannot reach {}", next))?;
+        Ok(client.collect_wait_for(ctx, initiator, graph, self.stamp_send()).await?)
+    }
+
+    pub async fn collect_wait_for_graph(&self) -> Result<WaitForGraph, Box<dyn Error>> {
+        tracing::info!("Node {} collecting global wait-for graph", self.id.bold().green());
+        let mut graph = self.extend_wait_for_graph(self.addr, WaitForGraph::default()).await?;
+        graph.find_cycles();
+        tracing::info!("Node {} wait-for graph: {} nodes, {} edges, cycles: {:?}", self.id, graph.nodes.len(), graph.edges.len(), graph.cycles);
+        Ok(graph)
+    }
+}
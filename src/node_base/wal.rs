@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use super::node::{NeighborInfo, Node, ResourceState};
+
+const WAL_FILE: &str = "wal.jsonl";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+// How often the log is checked for compaction
+const COMPACT_CHECK: Duration = Duration::from_secs(1);
+// How far ahead of the Lamport clock the logged bound is moved, so not every tick is written
+const CLOCK_STEP: u64 = 100;
+// The log is compacted into a snapshot this often, or once it has this many entries
+const COMPACT_INTERVAL: Duration = Duration::from_secs(30);
+const COMPACT_ENTRIES: usize = 1000;
+
+// What a restarted node needs to pick up where it stopped, the snapshot
+// holds it and the log entries after it are replayed on top
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PersistedState {
+    pub lamport: u64,
+    pub incarnation: u64,
+    // The last ring we were on, kept after leaving so a restart can rejoin
+    pub ring: Option<NeighborInfo>,
+    pub owned: HashMap<String, ResourceState>,
+    pub used: HashMap<String, SocketAddr>,
+    pub waiting: HashMap<String, SocketAddr>,
+}
+
+// One line of the log, stamped with the clock and incarnation when written
+#[derive(Debug, Serialize, Deserialize)]
+struct WalEntry {
+    lamport: u64,
+    incarnation: u64,
+    change: Change,
+}
+
+// Every change carries the resulting state rather than a delta, so
+// replaying an entry the snapshot already contains does no harm
+#[derive(Debug, Serialize, Deserialize)]
+enum Change {
+    // A resource we own after an assignment, grant, release or queue change,
+    // None once it was handed off
+    Owned(String, Option<ResourceState>),
+    // Who we hold a resource from and who we wait for it from
+    Used(String, Option<SocketAddr>, Option<SocketAddr>),
+    // All resource tables at once, after a rejoin rebuilt them
+    Resources {
+        owned: HashMap<String, ResourceState>,
+        used: HashMap<String, SocketAddr>,
+        waiting: HashMap<String, SocketAddr>,
+    },
+    // New ring neighbors, or None if only the clock bound or incarnation moved
+    Ring(Option<NeighborInfo>),
+}
+
+impl PersistedState {
+    fn apply(&mut self, entry: WalEntry) {
+        self.lamport = self.lamport.max(entry.lamport);
+        self.incarnation = self.incarnation.max(entry.incarnation);
+        match entry.change {
+            Change::Owned(resource, Some(state)) => {
+                self.owned.insert(resource, state);
+            }
+            Change::Owned(resource, None) => {
+                self.owned.remove(&resource);
+            }
+            Change::Used(resource, held, waiting) => {
+                set_or_remove(&mut self.used, resource.clone(), held);
+                set_or_remove(&mut self.waiting, resource, waiting);
+            }
+            Change::Resources { owned, used, waiting } => {
+                self.owned = owned;
+                self.used = used;
+                self.waiting = waiting;
+            }
+            Change::Ring(ring) => {
+                if ring.is_some() {
+                    self.ring = ring;
+                }
+            }
+        }
+    }
+}
+
+fn set_or_remove(map: &mut HashMap<String, SocketAddr>, resource: String, addr: Option<SocketAddr>) {
+    match addr {
+        Some(addr) => map.insert(resource, addr),
+        None => map.remove(&resource),
+    };
+}
+
+#[derive(Debug)]
+pub struct Wal {
+    dir: PathBuf,
+    log: File,
+    entries: usize,
+    // Last ring and incarnation written, only changes are logged
+    ring: Option<NeighborInfo>,
+    incarnation: u64,
+    // The Lamport clock has not passed this value, it is what a restart resumes from
+    clock_bound: u64,
+}
+
+impl Wal {
+    fn append(&mut self, entry: &WalEntry) -> Result<(), Box<dyn Error>> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.log.write_all(line.as_bytes())?;
+        self.entries += 1;
+        self.clock_bound = self.clock_bound.max(entry.lamport);
+        self.incarnation = entry.incarnation;
+        Ok(())
+    }
+
+    // The snapshot is replaced atomically, the log is only emptied after that
+    fn compact(&mut self, state: &PersistedState) -> Result<(), Box<dyn Error>> {
+        let tmp = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(serde_json::to_string(state)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT_FILE))?;
+        self.log.set_len(0)?;
+        self.log.sync_all()?;
+        self.entries = 0;
+        self.clock_bound = state.lamport;
+        self.incarnation = state.incarnation;
+        Ok(())
+    }
+}
+
+// Snapshot plus log, None if the directory holds neither
+fn load(dir: &Path) -> Result<Option<PersistedState>, Box<dyn Error>> {
+    let snapshot = dir.join(SNAPSHOT_FILE);
+    let mut found = snapshot.exists();
+    let mut state: PersistedState = if found {
+        serde_json::from_str(&fs::read_to_string(&snapshot)?)?
+    } else {
+        PersistedState::default()
+    };
+    let log = dir.join(WAL_FILE);
+    if log.exists() {
+        for (number, line) in BufReader::new(File::open(&log)?).lines().enumerate() {
+            match serde_json::from_str::<WalEntry>(&line?) {
+                Ok(entry) => {
+                    state.apply(entry);
+                    found = true;
+                }
+                Err(e) => {
+                    // A crash in the middle of a write leaves a torn last line
+                    tracing::error!("Ignoring write-ahead log from line {} on: {}", number + 1, e);
+                    break;
+                }
+            }
+        }
+    }
+    Ok(found.then_some(state))
+}
+
+impl Node {
+    // Replays the state found in dir and logs every later change there.
+    // Returns whether there was any state to replay.
+    pub fn open_data_dir(&self, dir: &Path) -> Result<bool, Box<dyn Error>> {
+        fs::create_dir_all(dir)?;
+        let restored = load(dir)?;
+        let ring = restored.as_ref().and_then(|state| state.ring.clone());
+        if let Some(state) = restored.clone() {
+            self.install(state);
+        }
+        let log = OpenOptions::new().create(true).append(true).open(dir.join(WAL_FILE))?;
+        *self.wal.lock().unwrap() = Some(Wal { dir: dir.to_path_buf(), log, entries: 0, ring, incarnation: 0, clock_bound: 0 });
+        self.compact_wal();
+        Ok(restored.is_some())
+    }
+
+    fn install(&self, state: PersistedState) {
+        tracing::info!("Node {} restored {} owned, {} used and {} awaited resources, Lamport {}, incarnation {}",
+            self.id.bold().green(), state.owned.len(), state.used.len(), state.waiting.len(), state.lamport, state.incarnation);
+        {
+            let mut clock = self.lamport_time.write().unwrap();
+            *clock = (*clock).max(state.lamport);
+        }
+        self.swim.write().unwrap().resume(state.incarnation);
+        *self.blocked_processes.write().unwrap() = state.waiting.values().copied().collect();
+        *self.owned_resources.write().unwrap() = state.owned;
+        *self.used_resources.write().unwrap() = state.used;
+        *self.waiting_for.write().unwrap() = state.waiting;
+    }
+
+    // The change is read under the log lock, so entries follow the order of
+    // the state they describe even when compaction runs in between
+    fn persist(&self, change: impl FnOnce() -> Change) {
+        let mut wal = self.wal.lock().unwrap();
+        let Some(wal) = wal.as_mut() else {
+            return;
+        };
+        let entry = WalEntry {
+            lamport: *self.lamport_time.read().unwrap(),
+            incarnation: self.swim.read().unwrap().incarnation(),
+            change: change(),
+        };
+        if let Err(e) = wal.append(&entry) {
+            tracing::error!("Node {} could not write to its write-ahead log: {}", self.id.bold().red(), e);
+        }
+    }
+
+    pub fn log_owned(&self, resource: &str) {
+        self.persist(|| Change::Owned(resource.to_string(), self.owned_resources.read().unwrap().get(resource).cloned()));
+    }
+
+    pub fn log_used(&self, resource: &str) {
+        self.persist(|| Change::Used(
+            resource.to_string(),
+            self.used_resources.read().unwrap().get(resource).copied(),
+            self.waiting_for.read().unwrap().get(resource).copied(),
+        ));
+    }
+
+    pub fn log_resources(&self) {
+        self.persist(|| Change::Resources {
+            owned: self.owned_resources.read().unwrap().clone(),
+            used: self.used_resources.read().unwrap().clone(),
+            waiting: self.waiting_for.read().unwrap().clone(),
+        });
+    }
+
+    // Called wherever the neighbors change. Alone on the ring we keep the
+    // last ring we were on, a restart rejoins through it.
+    pub fn log_ring(&self) {
+        let mut wal = self.wal.lock().unwrap();
+        let Some(wal) = wal.as_mut() else {
+            return;
+        };
+        let neighbor_info = self.neighbor_info.read().unwrap().clone();
+        if neighbor_info.next == self.addr || wal.ring.as_ref() == Some(&neighbor_info) {
+            return;
+        }
+        wal.ring = Some(neighbor_info.clone());
+        let entry = WalEntry {
+            lamport: *self.lamport_time.read().unwrap(),
+            incarnation: self.swim.read().unwrap().incarnation(),
+            change: Change::Ring(Some(neighbor_info)),
+        };
+        if let Err(e) = wal.append(&entry) {
+            tracing::error!("Node {} could not write to its write-ahead log: {}", self.id.bold().red(), e);
+        }
+    }
+
+    // Called wherever the Lamport clock or our incarnation change. The clock
+    // is logged as a bound CLOCK_STEP ahead once it reaches the last one.
+    pub fn log_clock(&self) {
+        let mut wal = self.wal.lock().unwrap();
+        let Some(wal) = wal.as_mut() else {
+            return;
+        };
+        let lamport = *self.lamport_time.read().unwrap();
+        let incarnation = self.swim.read().unwrap().incarnation();
+        if lamport < wal.clock_bound && incarnation == wal.incarnation {
+            return;
+        }
+        let bound = if lamport < wal.clock_bound { wal.clock_bound } else { lamport + CLOCK_STEP };
+        if let Err(e) = wal.append(&WalEntry { lamport: bound, incarnation, change: Change::Ring(None) }) {
+            tracing::error!("Node {} could not write to its write-ahead log: {}", self.id.bold().red(), e);
+        }
+    }
+
+    pub fn compact_wal(&self) {
+        let mut wal = self.wal.lock().unwrap();
+        let Some(wal) = wal.as_mut() else {
+            return;
+        };
+        let state = PersistedState {
+            lamport: (*self.lamport_time.read().unwrap()).max(wal.clock_bound),
+            incarnation: self.swim.read().unwrap().incarnation(),
+            ring: wal.ring.clone(),
+            owned: self.owned_resources.read().unwrap().clone(),
+            used: self.used_resources.read().unwrap().clone(),
+            waiting: self.waiting_for.read().unwrap().clone(),
+        };
+        match wal.compact(&state) {
+            Ok(()) => tracing::debug!("Node {} compacted its write-ahead log", self.id),
+            Err(e) => tracing::error!("Node {} could not compact its write-ahead log: {}", self.id.bold().red(), e),
+        }
+    }
+
+    // Rejoins through the first reachable node of the ring we were on before
+    // the restart, then reconciles resources as a revived node does
+    pub async fn rejoin_after_restart(&self) {
+        let Some(ring) = self.wal.lock().unwrap().as_ref().and_then(|wal| wal.ring.clone()) else {
+            return;
+        };
+        let mut candidates = Vec::new();
+        for addr in [ring.prev, ring.next].into_iter().chain(ring.successors) {
+            if addr != self.addr && !candidates.contains(&addr) {
+                candidates.push(addr);
+            }
+        }
+        for candidate in candidates {
+            if !self.ping(candidate).await {
+                continue;
+            }
+            tracing::info!("T: {}. Node {} rejoining through {} after restart", self.log_time(), self.id.bold().green(), candidate);
+            if self.try_join_other(candidate).await.is_err() {
+                continue;
+            }
+            if let Err(e) = self.reconcile_after_rejoin().await {
+                tracing::error!("Node {} could not reconcile after rejoin: {}", self.id.bold().red(), e);
+            }
+            return;
+        }
+        tracing::error!("Node {} reached nobody of its old ring, staying alone", self.id.bold().red());
+    }
+}
+
+pub async fn run_wal(node: Arc<Node>) {
+    let mut since_compaction = Duration::ZERO;
+    loop {
+        tokio::time::sleep(COMPACT_CHECK).await;
+        node.wait_until_up().await;
+        let Some(entries) = node.wal.lock().unwrap().as_ref().map(|wal| wal.entries) else {
+            return;
+        };
+        since_compaction += COMPACT_CHECK;
+        if since_compaction >= COMPACT_INTERVAL || entries >= COMPACT_ENTRIES {
+            node.compact_wal();
+            since_compaction = Duration::ZERO;
+        }
+    }
+}
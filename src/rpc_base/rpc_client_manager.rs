@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::sync::{Arc};
 use tarpc::{client, context};
@@ -11,12 +11,18 @@ use super::{service};
 #[derive(Clone, Debug)]
 pub struct RpcClientManager {
     clients: Arc<RwLock<HashMap<SocketAddr, service::NodeRpcClient>>>,
+    // Peers we pretend not to reach, used to simulate a network partition
+    blocked: Arc<RwLock<HashSet<SocketAddr>>>,
+    // Set while the node is killed, no RPC leaves it
+    down: Arc<RwLock<bool>>,
 }
 
 impl RpcClientManager {
     pub fn new() -> Self {
         Self {
             clients: Arc::new(RwLock::new(HashMap::new())),
+            blocked: Arc::new(RwLock::new(HashSet::new())),
+            down: Arc::new(RwLock::new(false)),
         }
     }
 
@@ -36,7 +42,29 @@ impl RpcClientManager {
         self.clients.write().await.remove(&addr);
     }
 
+    pub async fn set_blocked(&self, peers: HashSet<SocketAddr>) {
+        let mut clients = self.clients.write().await;
+        for peer in &peers {
+            clients.remove(peer);
+        }
+        *self.blocked.write().await = peers;
+    }
+
+    // Going down drops every cached client, so open connections close too
+    pub async fn set_down(&self, down: bool) {
+        if down {
+            self.clients.write().await.clear();
+        }
+        *self.down.write().await = down;
+    }
+
     pub async fn get_client(&self, addr: SocketAddr) -> Result<service::NodeRpcClient, Box<dyn std::error::Error>> {
+        if *self.down.read().await {
+            return Err("Node is down".into());
+        }
+        if self.blocked.read().await.contains(&addr) {
+            return Err(format!("Partitioned from {}", addr).into());
+        }
         let mut clients = self.clients.write().await;
         if let std::collections::hash_map::Entry::Vacant(e) = clients.entry(addr) {
             match self.create_client(addr).await {
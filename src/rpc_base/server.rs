@@ -1,14 +1,27 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use colored::Colorize;
 use futures::{future, StreamExt};
 use tarpc::{context};
 use tarpc::context::Context;
 use tarpc::server::{BaseChannel, Channel};
 use tarpc::tokio_serde::formats::Json;
+use crate::node_base::barrier::BarrierGoal;
+use crate::node_base::broadcast::CausalMessage;
+use crate::node_base::clocks::RpcHeader;
+use crate::node_base::kv::{KvEntry, KvOp};
 use crate::node_base::cmh_funcs::CmhMessageType;
-use crate::node_base::node::{NeighborInfo, Node};
+use crate::node_base::messaging::RingMessage;
+use crate::node_base::node::{MemberRound, NeighborInfo, Node, ResourceState};
+use crate::node_base::placement::PlacementMode;
+use crate::node_base::rejoin::{RejoinReply, RejoinReport};
+use crate::node_base::repair::{RepairId, RepairStatus};
 use crate::node_base::resources::ResourceMessageType;
+use crate::node_base::snapshot::{ChannelMessage, LocalSnapshot, SnapshotMarker};
+use crate::node_base::termination::TerminationMessage;
+use crate::node_base::total_order::TotalOrderMessage;
+use crate::node_base::wait_for_graph::WaitForGraph;
 use super::service::NodeRpc;
 
 #[derive(Clone)]
@@ -24,27 +37,88 @@ impl NodeRpcServer {
 
 impl NodeRpc for NodeRpcServer {
     
-    async fn heartbeat(self, _context: Context) -> bool {
+    async fn heartbeat(self, _context: Context, header: RpcHeader) -> bool {
+        self.node.observe(&header);
         tracing::debug!("Node {} received heartbeat", self.node.id.bold().green());
         true
     }
-    async fn handle_resource_msg(self, _: context::Context, message: ResourceMessageType, from: SocketAddr) -> ResourceMessageType {
-        tracing::debug!("Node {} received Resource message", self.node.id.bold().green());
-        self.node.handle_message(message, from).await.unwrap()
+    async fn handle_resource_msg(self, _: context::Context, message: ResourceMessageType, from: SocketAddr, request_id: Option<u64>, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        tracing::debug!("Node {} received Resource message, VC: {}", self.node.id.bold().green(), header.vclock);
+        self.node.log_event(format!("receive: {:?}", message));
+        self.node.receive(header.sender, RingMessage::Resource(message, from, request_id))
     }
 
-    async fn handle_cmh_msg(self, _: context::Context, message: CmhMessageType, from: SocketAddr, lamport: u64) -> CmhMessageType {
-        self.node.update_clock(lamport);
-        tracing::debug!("Node {} received CMH message", self.node.id.bold().green());
-        self.node.handle_cmh_message(message, from).await.unwrap()
+    async fn handle_cmh_msg(self, _: context::Context, message: CmhMessageType, from: SocketAddr, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        tracing::debug!("Node {} received CMH message, VC: {}", self.node.id.bold().green(), header.vclock);
+        self.node.log_event(format!("receive: {:?}", message));
+        self.node.receive(header.sender, RingMessage::Cmh(message, from))
     }
 
-    async fn other_joining(self, _context: Context, addr: SocketAddr) -> NeighborInfo {
+    async fn handle_broadcast_msg(self, _: context::Context, message: CausalMessage, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        tracing::debug!("Node {} received broadcast from {}, VC: {}", self.node.id.bold().green(), message.origin, header.vclock);
+        self.node.log_event(format!("receive: {:?}", message));
+        self.node.receive(header.sender, RingMessage::Broadcast(message))
+    }
+
+    async fn handle_total_order_msg(self, _: context::Context, message: TotalOrderMessage, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        tracing::debug!("Node {} received total order message, VC: {}", self.node.id.bold().green(), header.vclock);
+        self.node.log_event(format!("receive: {:?}", message));
+        self.node.receive(header.sender, RingMessage::TotalOrder(message))
+    }
+
+    async fn handle_snapshot_marker(self, _: context::Context, marker: SnapshotMarker, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        tracing::debug!("Node {} received snapshot marker {} of {}", self.node.id.bold().green(), marker.id, marker.initiator);
+        self.node.log_event(format!("receive: snapshot marker {} of {}", marker.id, marker.initiator));
+        self.node.receive(header.sender, RingMessage::Marker(marker))
+    }
+
+    async fn snapshot_report(self, _: context::Context, id: u64, local: LocalSnapshot, in_flight: Vec<ChannelMessage>, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        tracing::debug!("Node {} got snapshot {} report of {}", self.node.id.bold().green(), id, local.addr);
+        self.node.add_snapshot_report(id, local, in_flight)
+    }
+
+    async fn handle_termination_msg(self, _: context::Context, message: TerminationMessage, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        tracing::debug!("Node {} received termination message, VC: {}", self.node.id.bold().green(), header.vclock);
+        self.node.log_event(format!("receive: {:?}", message));
+        self.node.receive(header.sender, RingMessage::Termination(message))
+    }
+
+    async fn ring_changed(self, _: context::Context, origin: SocketAddr, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        tracing::debug!("Node {} told about ring change noticed by {}", self.node.id.bold().green(), origin);
+        self.node.receive(header.sender, RingMessage::RingChanged(origin))
+    }
+
+    async fn handle_member_round(self, _: context::Context, round: MemberRound, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        self.node.receive(header.sender, RingMessage::Members(round))
+    }
+
+    async fn handle_counterclockwise_msg(self, _: context::Context, destination: SocketAddr, message: RingMessage, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        tracing::debug!("Node {} received message for {} from next, VC: {}", self.node.id.bold().green(), destination, header.vclock);
+        self.node.log_event(format!("receive counter-clockwise: {:?}", message));
+        self.node.receive(header.sender, RingMessage::Counterclockwise(destination, Box::new(message)))
+    }
+
+    async fn other_joining(self, _context: Context, addr: SocketAddr, header: RpcHeader) -> NeighborInfo {
+        self.node.observe(&header);
         tracing::debug!("Node {} received other_joining from {}", self.node.id.bold().green(), addr.to_string().bold().green());
+        // Joins at the same node are handled one after another
+        let _membership = self.node.membership_lock.lock().await;
+        // The joining node may be a restarted process, a cached connection
+        // to it would fail once it is our next
+        self.node.rpc.delete_client(addr).await;
         
         if self.node.neighbor_info.read().unwrap().next == addr {
             tracing::info!("Node {} is already my next, so revive", addr.to_string().bold().green());
-            self.node.rpc.delete_client(addr).await; // we create a new one
             // Basically reset the node, so it doesnt mess with anything
             self.node.repair_topology(addr).await;
         }
@@ -56,26 +130,34 @@ impl NodeRpc for NodeRpcServer {
                 next: neighbor_info.next,
                 nnext: neighbor_info.nnext,
                 prev: self.node.addr,
+                successors: self.node.successor_list(neighbor_info.next, &neighbor_info.successors),
             }, neighbor_info.next, neighbor_info.prev)
         };
 
         // update next node to change prev to new
-        self.node.rpc.get_client(my_next).await.unwrap().change_prev(_context, addr).await.unwrap();
+        self.node.rpc.get_client(my_next).await.unwrap().change_prev(_context, addr, self.node.stamp_send()).await.unwrap();
         // update prev node to change next next to new
-        self.node.rpc.get_client(my_prev).await.unwrap().change_nnext(_context, addr).await.unwrap();
+        self.node.rpc.get_client(my_prev).await.unwrap().change_nnext(_context, addr, self.node.stamp_send()).await.unwrap();
         
         tmp_neighbor.nnext = self.node.neighbor_info.read().unwrap().nnext;
         
         // change my neighbors
-        self.node.neighbor_info.write().unwrap().nnext = my_next;
-        self.node.neighbor_info.write().unwrap().next = addr;
+        {
+            let mut neighbor_info = self.node.neighbor_info.write().unwrap();
+            neighbor_info.nnext = my_next;
+            neighbor_info.next = addr;
+            neighbor_info.successors = self.node.successor_list(addr, &tmp_neighbor.successors);
+        }
+        self.node.log_ring();
         
         self.node.print_status();
         tmp_neighbor
     }
     
-    async fn leave_topology(self, _context: Context) -> bool {
+    async fn leave_topology(self, _context: Context, header: RpcHeader) -> bool {
+        self.node.observe(&header);
         tracing::debug!("Node {} leaving the topology", self.node.id.bold().green());
+        let _membership = self.node.membership_lock.lock().await;
         let (prev, next, nnext) = {
             let neighbor_info= self.node.neighbor_info.read().unwrap();
             let prev = neighbor_info.prev;
@@ -88,37 +170,40 @@ impl NodeRpc for NodeRpcServer {
             // Only one other node exists
             // Update the remaining node to point to itself
             let remaining_client = self.node.rpc.get_client(next).await.unwrap();
-            remaining_client.change_next(_context, next).await.unwrap();
-            remaining_client.change_prev(_context, next).await.unwrap();
+            remaining_client.change_next(_context, next, self.node.stamp_send()).await.unwrap();
+            remaining_client.change_prev(_context, next, self.node.stamp_send()).await.unwrap();
         } else if prev == nnext {
             // 3-node topology
             // Update the next node to point to itself
             let next_client = self.node.rpc.get_client(next).await.unwrap();
-            next_client.change_prev(_context, prev).await.unwrap();
-            next_client.change_nnext(_context, next).await.unwrap();
+            next_client.change_prev(_context, prev, self.node.stamp_send()).await.unwrap();
+            next_client.change_nnext(_context, next, self.node.stamp_send()).await.unwrap();
             let prev_client = self.node.rpc.get_client(prev).await.unwrap();
-            prev_client.change_next(_context, next).await.unwrap();
-            prev_client.change_nnext(_context, nnext).await.unwrap();
+            prev_client.change_next(_context, next, self.node.stamp_send()).await.unwrap();
+            prev_client.change_nnext(_context, nnext, self.node.stamp_send()).await.unwrap();
         } else {
             // normal case with 3+ nodes
             // update previous node's next pointer
             let prev_client = self.node.rpc.get_client(prev).await.unwrap();
-            prev_client.change_next(_context, next).await.unwrap();
-            prev_client.change_nnext(_context, nnext).await.unwrap();
-            prev_client.change_nnext_of_prev(_context, next).await.unwrap();
+            prev_client.change_next(_context, next, self.node.stamp_send()).await.unwrap();
+            prev_client.change_nnext(_context, nnext, self.node.stamp_send()).await.unwrap();
+            prev_client.change_nnext_of_prev(_context, next, self.node.stamp_send()).await.unwrap();
 
             // update next node's prev pointer
             let next_client = self.node.rpc.get_client(next).await.unwrap();
-            next_client.change_prev(_context, prev).await.unwrap();
+            next_client.change_prev(_context, prev, self.node.stamp_send()).await.unwrap();
         }
         
         true
     }
-    async fn change_next(self, _context: Context, next: SocketAddr) -> bool {
+    async fn change_next(self, _context: Context, next: SocketAddr, header: RpcHeader) -> bool {
+        self.node.observe(&header);
         tracing::debug!("Node {} changing Next to {}", self.node.id.bold().green(), next.to_string().bold().yellow());
         match self.node.neighbor_info.write() {
             Ok(mut neighbor_info) => {
                 neighbor_info.next = next;
+                drop(neighbor_info);
+                self.node.log_ring();
                 true
             },
             Err(e) => {
@@ -128,11 +213,14 @@ impl NodeRpc for NodeRpcServer {
         }
     }
 
-    async fn change_nnext(self, _context: Context, nnext: SocketAddr) -> bool {
+    async fn change_nnext(self, _context: Context, nnext: SocketAddr, header: RpcHeader) -> bool {
+        self.node.observe(&header);
         tracing::debug!("Node {} changing NNext to {}", self.node.id.bold().green(), nnext.to_string().bold().yellow());
         match self.node.neighbor_info.write() {
             Ok(mut neighbor_info) => {
                 neighbor_info.nnext = nnext;
+                drop(neighbor_info);
+                self.node.log_ring();
                 true
             },
             Err(e) => {
@@ -142,12 +230,16 @@ impl NodeRpc for NodeRpcServer {
         }
     }
 
-    async fn change_prev(self, _context: Context, prev: SocketAddr) -> SocketAddr {
+    async fn change_prev(self, _context: Context, prev: SocketAddr, header: RpcHeader) -> SocketAddr {
+        self.node.observe(&header);
         tracing::debug!("Node {} changing Prev to {}", self.node.id.bold().green(), prev.to_string().bold().yellow());
         match self.node.neighbor_info.write() {
             Ok(mut neighbor_info) => {
                 neighbor_info.prev = prev;
-                neighbor_info.next
+                let next = neighbor_info.next;
+                drop(neighbor_info);
+                self.node.log_ring();
+                next
             },
             Err(e) => {
                 tracing::error!("Error changing Prev: {} in Node {}", e, self.node.id.bold().green());
@@ -156,61 +248,159 @@ impl NodeRpc for NodeRpcServer {
         }
     }
 
-    async fn change_nnext_of_prev(self, _context: Context, next: SocketAddr) -> bool {
+    async fn change_nnext_of_prev(self, _context: Context, next: SocketAddr, header: RpcHeader) -> bool {
+        self.node.observe(&header);
         tracing::debug!("Node {} changing nnext of Prev to {}", self.node.id.bold().green(), next.to_string().bold().yellow());
         let prev = {
             let neighbor_info = self.node.neighbor_info.read().unwrap();
             neighbor_info.prev
         };
         let prev_client = self.node.rpc.get_client(prev).await.unwrap();
-        prev_client.change_nnext(_context, next).await.unwrap()
+        prev_client.change_nnext(_context, next, self.node.stamp_send()).await.unwrap()
     }
 
-    async fn missing_node(self, context: Context, from: SocketAddr, missing_node: SocketAddr) -> bool {
-        let next = self.node.neighbor_info.read().unwrap().next;
-        
-        if self.node.addr == from && next != missing_node {
-            // instantly ends the cycle
-            tracing::info!("Finished sending missing node. Circle complete");
-            return true;
-        } else {
-            // handle the missing node
-            tracing::debug!("Node {} received missing node from {} will remove deps", self.node.id.bold().green(), from.to_string().bold().red());
-            self.node.delete_dependencies(missing_node).await;
+    async fn get_neighbors(self, _: context::Context, header: RpcHeader) -> NeighborInfo {
+        self.node.observe(&header);
+        self.node.neighbor_info.read().unwrap().clone()
+    }
+
+    async fn notify(self, _: context::Context, candidate: SocketAddr, candidate_prev: SocketAddr, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        tracing::debug!("Node {} notified by {} that it is our prev", self.node.id.bold().green(), candidate.to_string().bold().green());
+        self.node.handle_notify(candidate, candidate_prev).await
+    }
+
+    async fn missing_node(self, _context: Context, id: RepairId, skipped: Vec<SocketAddr>, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        self.node.handle_repair_message(id, skipped).await
+    }
+    async fn repair_merged(self, _context: Context, id: RepairId, into: RepairId, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        self.node.finish_repair(id, RepairStatus::Merged, Some(into), Vec::new());
+        true
+    }
+
+    async fn collect_wait_for(self, _context: Context, initiator: SocketAddr, graph: WaitForGraph, header: RpcHeader) -> WaitForGraph {
+        self.node.observe(&header);
+        tracing::debug!("Node {} adding wait-for edges for {}", self.node.id.bold().green(), initiator.to_string().bold().green());
+        match self.node.extend_wait_for_graph(initiator, graph.clone()).await {
+            Ok(graph) => graph,
+            Err(e) => {
+                tracing::error!("Error collecting wait-for graph in Node {}: {}", self.node.id.bold().red(), e);
+                graph
+            }
         }
-        
-        tracing::debug!("Node {} fixing topology with missing node: {}", self.node.id.bold().green(), missing_node.to_string().bold().red());
-        let nnext = self.node.neighbor_info.read().unwrap().nnext;
-        let prev = self.node.neighbor_info.read().unwrap().prev;
-        
-        if missing_node == self.node.neighbor_info.read().unwrap().next {
-            
-            self.node.rpc.delete_client(missing_node).await;            
-            // its for me
-            // to my nnext send msg ChPrev with myaddr -> my nnext = next
-            self.node.neighbor_info.write().unwrap().next = nnext;
-            self.node.neighbor_info.write().unwrap().nnext = self.node.rpc.get_client(nnext).await.unwrap().change_prev(context, self.node.addr).await.unwrap();
-            // to my prev send msg ChNNext to my.next
-            if prev == next {
-                // only 2 nodes
-                self.node.neighbor_info.write().unwrap().prev = nnext;
-            } else {
-                // 3+ nodes
-                self.node.rpc.get_client(prev).await.unwrap().change_nnext(context, nnext).await.unwrap();
+    }
+
+    async fn get_finger(self, _context: Context, index: usize, header: RpcHeader) -> SocketAddr {
+        self.node.observe(&header);
+        self.node.finger(index)
+    }
+
+    // Runs a member round on our ring, a node on another ring asks this after a partition
+    async fn collect_members(self, _context: Context, header: RpcHeader) -> Vec<SocketAddr> {
+        self.node.observe(&header);
+        match self.node.ring_members().await {
+            Ok(members) => members,
+            Err(e) => {
+                tracing::error!("Error collecting ring members in Node {}: {}", self.node.id.bold().red(), e);
+                self.node.members.read().unwrap().clone()
             }
-            
-            // tell other nodes about the missing node, so that they can remove from dependency
-            self.node.rpc.get_client(nnext).await.unwrap().missing_node(context, from, missing_node).await.unwrap()
-        } else {
-            // send to next node
-            self.node.rpc.get_client(next).await.unwrap().missing_node(context, from, missing_node).await.unwrap()
         }
     }
+
+    async fn barrier_arrive(self, _context: Context, name: String, participant: SocketAddr, goal: BarrierGoal, header: RpcHeader) -> Option<SocketAddr> {
+        self.node.observe(&header);
+        self.node.handle_barrier_arrive(name, participant, goal).await
+    }
+
+    async fn barrier_pin(self, _context: Context, names: Vec<String>, coordinator: SocketAddr, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        self.node.handle_barrier_pin(names, coordinator);
+        true
+    }
+
+    async fn barrier_release(self, _context: Context, name: String, participants: Vec<SocketAddr>, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        tracing::debug!("Node {} released from barrier {}", self.node.id.bold().green(), name);
+        self.node.handle_barrier_release(name, participants)
+    }
+
+    async fn kv_request(self, _context: Context, op: KvOp, header: RpcHeader) -> Option<KvEntry> {
+        self.node.observe(&header);
+        tracing::debug!("Node {} handling kv request {:?}", self.node.id.bold().green(), op);
+        self.node.kv_apply(op).await
+    }
+
+    async fn kv_store(self, _context: Context, entries: Vec<KvEntry>, primary: bool, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        self.node.kv_merge(entries, primary).await;
+        true
+    }
+
+    async fn set_placement(self, _context: Context, mode: PlacementMode, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        self.node.set_placement(mode).await;
+        true
+    }
+
+    async fn placement(self, _context: Context, header: RpcHeader) -> PlacementMode {
+        self.node.observe(&header);
+        *self.node.placement.read().unwrap()
+    }
+
+    async fn take_over_resources(self, _context: Context, resources: Vec<(String, ResourceState)>, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        self.node.take_over_resources(resources).await;
+        true
+    }
+
+    async fn revoke_resource(self, _context: Context, resource: String, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        self.node.revoke_resource(resource, header.sender);
+        true
+    }
+
+    async fn owned_resource_names(self, _context: Context, header: RpcHeader) -> Vec<String> {
+        self.node.observe(&header);
+        self.node.owned_resources.read().unwrap().keys().cloned().collect()
+    }
+
+    async fn total_order_epoch(self, _context: Context, header: RpcHeader) -> u64 {
+        self.node.observe(&header);
+        self.node.total_order_epoch()
+    }
+
+    async fn yield_total_order(self, _context: Context, epoch: u64, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        self.node.yield_total_order(epoch);
+        true
+    }
+
+    async fn give_up_resources(self, _context: Context, names: Vec<String>, header: RpcHeader) -> Vec<(String, ResourceState)> {
+        self.node.observe(&header);
+        tracing::info!("Node {} giving up resources {:?}", self.node.id.bold().yellow(), names);
+        self.node.give_up_resources(names)
+    }
+    // Indirect probe on behalf of the caller
+    async fn ping_req(self, _context: Context, target: SocketAddr, header: RpcHeader) -> bool {
+        self.node.observe(&header);
+        self.node.ping(target).await
+    }
+    async fn rejoin(self, _context: Context, report: RejoinReport, header: RpcHeader) -> Option<RejoinReply> {
+        self.node.observe(&header);
+        self.node.handle_rejoin(report)
+    }
 }
 
-pub async fn spawn(fut: impl std::future::Future<Output = ()> + Send + 'static) {
+// Handlers are counted so that shutdown can wait for them
+pub async fn spawn(node: Arc<Node>, fut: impl std::future::Future<Output = ()> + Send + 'static) {
     tracing::debug!("Spawning a new task");
-    tokio::spawn(fut);
+    node.rpcs_in_flight.fetch_add(1, Ordering::SeqCst);
+    tokio::spawn(async move {
+        fut.await;
+        node.rpcs_in_flight.fetch_sub(1, Ordering::SeqCst);
+    });
 }
 
 pub async fn serve_rpc(node: Arc<Node>) -> Result<(), Box<dyn std::error::Error>> {
@@ -228,8 +418,9 @@ pub async fn serve_rpc(node: Arc<Node>) -> Result<(), Box<dyn std::error::Error>
                 .filter_map(|r| future::ready(r.ok()))
                 .map(BaseChannel::with_defaults)
                 .map(|channel| {
+                    let node = node.clone();
                     channel.execute(server.clone().serve())
-                        .for_each(spawn)
+                        .for_each(move |fut| spawn(node.clone(), fut))
                 })
                 .buffer_unordered(10)
                 .for_each(|_| async {})
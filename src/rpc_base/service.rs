@@ -1,18 +1,60 @@
 use std::net::SocketAddr;
-use crate::node_base::node::NeighborInfo;
+use crate::node_base::barrier::BarrierGoal;
+use crate::node_base::broadcast::CausalMessage;
+use crate::node_base::clocks::RpcHeader;
+use crate::node_base::node::ResourceState;
+use crate::node_base::placement::PlacementMode;
+use crate::node_base::kv::{KvEntry, KvOp};
+use crate::node_base::messaging::RingMessage;
+use crate::node_base::node::{MemberRound, NeighborInfo};
+use crate::node_base::rejoin::{RejoinReply, RejoinReport};
+use crate::node_base::repair::RepairId;
 use crate::node_base::resources::{ResourceMessageType};
 use crate::node_base::cmh_funcs::{CmhMessageType};
+use crate::node_base::snapshot::{ChannelMessage, LocalSnapshot, SnapshotMarker};
+use crate::node_base::termination::TerminationMessage;
+use crate::node_base::total_order::TotalOrderMessage;
+use crate::node_base::wait_for_graph::WaitForGraph;
 
 #[tarpc::service]
 pub trait NodeRpc {
-    async fn heartbeat() -> bool;
-    async fn handle_resource_msg(message: ResourceMessageType, from: SocketAddr) -> ResourceMessageType;
-    async fn handle_cmh_msg(message: CmhMessageType, from: SocketAddr, lamport: u64) -> CmhMessageType;
-    async fn other_joining(addr: SocketAddr) -> NeighborInfo;
-    async fn leave_topology() -> bool;
-    async fn change_next(next: SocketAddr) -> bool;
-    async fn change_nnext(nnext: SocketAddr) -> bool;
-    async fn change_prev(prev: SocketAddr) -> SocketAddr;
-    async fn change_nnext_of_prev(next: SocketAddr) -> bool;
-    async fn missing_node(from:SocketAddr, addr: SocketAddr) -> bool;
+    async fn heartbeat(header: RpcHeader) -> bool;
+    async fn handle_resource_msg(message: ResourceMessageType, from: SocketAddr, request_id: Option<u64>, header: RpcHeader) -> bool;
+    async fn handle_cmh_msg(message: CmhMessageType, from: SocketAddr, header: RpcHeader) -> bool;
+    async fn handle_broadcast_msg(message: CausalMessage, header: RpcHeader) -> bool;
+    async fn handle_total_order_msg(message: TotalOrderMessage, header: RpcHeader) -> bool;
+    async fn handle_snapshot_marker(marker: SnapshotMarker, header: RpcHeader) -> bool;
+    async fn snapshot_report(id: u64, local: LocalSnapshot, in_flight: Vec<ChannelMessage>, header: RpcHeader) -> bool;
+    async fn handle_termination_msg(message: TerminationMessage, header: RpcHeader) -> bool;
+    async fn ring_changed(origin: SocketAddr, header: RpcHeader) -> bool;
+    async fn handle_member_round(round: MemberRound, header: RpcHeader) -> bool;
+    async fn handle_counterclockwise_msg(destination: SocketAddr, message: RingMessage, header: RpcHeader) -> bool;
+    async fn other_joining(addr: SocketAddr, header: RpcHeader) -> NeighborInfo;
+    async fn leave_topology(header: RpcHeader) -> bool;
+    async fn change_next(next: SocketAddr, header: RpcHeader) -> bool;
+    async fn change_nnext(nnext: SocketAddr, header: RpcHeader) -> bool;
+    async fn change_prev(prev: SocketAddr, header: RpcHeader) -> SocketAddr;
+    async fn change_nnext_of_prev(next: SocketAddr, header: RpcHeader) -> bool;
+    async fn get_neighbors(header: RpcHeader) -> NeighborInfo;
+    async fn notify(candidate: SocketAddr, candidate_prev: SocketAddr, header: RpcHeader) -> bool;
+    async fn missing_node(id: RepairId, skipped: Vec<SocketAddr>, header: RpcHeader) -> bool;
+    async fn repair_merged(id: RepairId, into: RepairId, header: RpcHeader) -> bool;
+    async fn collect_wait_for(initiator: SocketAddr, graph: WaitForGraph, header: RpcHeader) -> WaitForGraph;
+    async fn get_finger(index: usize, header: RpcHeader) -> SocketAddr;
+    async fn collect_members(header: RpcHeader) -> Vec<SocketAddr>;
+    async fn barrier_arrive(name: String, participant: SocketAddr, goal: BarrierGoal, header: RpcHeader) -> Option<SocketAddr>;
+    async fn barrier_pin(names: Vec<String>, coordinator: SocketAddr, header: RpcHeader) -> bool;
+    async fn barrier_release(name: String, participants: Vec<SocketAddr>, header: RpcHeader) -> bool;
+    async fn kv_request(op: KvOp, header: RpcHeader) -> Option<KvEntry>;
+    async fn kv_store(entries: Vec<KvEntry>, primary: bool, header: RpcHeader) -> bool;
+    async fn set_placement(mode: PlacementMode, header: RpcHeader) -> bool;
+    async fn placement(header: RpcHeader) -> PlacementMode;
+    async fn take_over_resources(resources: Vec<(String, ResourceState)>, header: RpcHeader) -> bool;
+    async fn revoke_resource(resource: String, header: RpcHeader) -> bool;
+    async fn owned_resource_names(header: RpcHeader) -> Vec<String>;
+    async fn total_order_epoch(header: RpcHeader) -> u64;
+    async fn yield_total_order(epoch: u64, header: RpcHeader) -> bool;
+    async fn give_up_resources(names: Vec<String>, header: RpcHeader) -> Vec<(String, ResourceState)>;
+    async fn ping_req(target: SocketAddr, header: RpcHeader) -> bool;
+    async fn rejoin(report: RejoinReport, header: RpcHeader) -> Option<RejoinReply>;
 }
\ No newline at end of file